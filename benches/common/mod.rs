@@ -0,0 +1,39 @@
+//! Transaction files shared by the benchmarks, generated up front so that generation isn't
+//! measured.
+
+use txn_assignment::generate::{GenerateArgs, generate};
+
+/// Number of rows in each generated file.
+pub const SIZES: [usize; 3] = [10_000, 100_000, 1_000_000];
+
+/// Transaction type mixes, from plain payments to dispute-heavy files where most clients end up
+/// locked by chargebacks.
+pub const MIXES: [(&str, &str); 3] = [
+    ("payments", "deposit=1,withdrawal=1"),
+    (
+        "default",
+        "deposit=60,withdrawal=30,dispute=6,resolve=3,chargeback=1",
+    ),
+    (
+        "disputes",
+        "deposit=30,withdrawal=20,dispute=30,resolve=10,chargeback=10",
+    ),
+];
+
+/// Generates a transaction CSV with the given number of rows and transaction type mix.
+pub fn file(rows: usize, mix: &str) -> Vec<u8> {
+    let args = GenerateArgs {
+        clients: 10_000,
+        rows,
+        mix: mix.parse().expect("Valid mix"),
+        duplicate_rate: 0.01,
+        malformed_rate: 0.01,
+        seed: 0,
+        output: None,
+        expected: None,
+    };
+
+    let mut out = Vec::new();
+    generate(&args, &mut out).expect("Failed to generate file");
+    out
+}
@@ -0,0 +1,97 @@
+//! Peak memory report for end-to-end processing of generated transaction files. Each file is
+//! processed in a fresh child process, so peaks don't carry over between files. Linux only, as the
+//! resident set size is read from `/proc/self/status`.
+//!
+//! Run with `cargo bench --bench memory`. Without `--bench`, e.g. under `cargo test --benches`,
+//! only the smallest files are processed as a smoke test.
+
+use std::{env, process::Command, time::Instant};
+
+use futures_util::StreamExt;
+use txn_assignment::{csv::parse_csv, model::State};
+
+mod common;
+
+/// Reads a `kB` field, e.g. `VmHWM` for the peak resident set size, from `/proc/self/status`.
+fn status_kb(field: &str) -> Option<u64> {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Processes a single generated file and prints `rows/sec,stored transactions,baseline,peak`.
+fn scenario(mix: &str, rows: usize) {
+    let data = common::file(rows, mix);
+    let baseline = status_kb("VmRSS").unwrap_or_default();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("Failed to build runtime");
+    let start = Instant::now();
+    let state = runtime.block_on(async {
+        let stream = parse_csv(data.as_slice()).await;
+        futures_util::pin_mut!(stream);
+        let mut state = State::default();
+        while let Some(tx) = stream.next().await {
+            let _ = state.apply(tx.expect("Failed to parse"));
+        }
+        state
+    });
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:.0},{},{},{}",
+        rows as f64 / elapsed.as_secs_f64(),
+        state.transactions.len(),
+        baseline,
+        status_kb("VmHWM").unwrap_or_default()
+    );
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if let [_, flag, mix, rows] = args.as_slice()
+        && flag == "--scenario"
+    {
+        scenario(mix, rows.parse().expect("Row count"));
+        return;
+    }
+
+    let sizes = if args.iter().any(|arg| arg == "--bench") {
+        &common::SIZES[..]
+    } else {
+        &common::SIZES[..1]
+    };
+    let exe = env::current_exe().expect("Benchmark executable");
+
+    println!(
+        "{:<10} {:>10} {:>12} {:>12} {:>14} {:>12} {:>12}",
+        "mix", "rows", "rows/sec", "stored txs", "baseline KiB", "peak KiB", "growth KiB"
+    );
+    for (name, mix) in common::MIXES {
+        for rows in sizes {
+            let output = Command::new(&exe)
+                .args(["--scenario", mix, &rows.to_string()])
+                .output()
+                .expect("Failed to run scenario");
+            assert!(output.status.success(), "Scenario {name} {rows} failed");
+
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let fields = stdout.trim().split(',').collect::<Vec<_>>();
+            let [throughput, stored, baseline, peak] = fields.as_slice() else {
+                panic!("Unexpected scenario output: {stdout}");
+            };
+            let kib = |field: &str| field.parse::<u64>().expect("Memory in KiB");
+            let growth = kib(peak).saturating_sub(kib(baseline));
+            println!(
+                "{name:<10} {rows:>10} {throughput:>12} {stored:>12} {baseline:>14} {peak:>12} {growth:>12}"
+            );
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Throughput benchmarks for parsing, handling and end-to-end processing of generated transaction
+//! files, serially and sharded across worker tasks, reported in rows per second. Criterion
+//! compares every run against the previous one, so use `cargo bench --bench throughput --
+//! --save-baseline <name>` and `--baseline <name>` to compare branches.
+
+use std::hint::black_box;
+use std::num::NonZeroUsize;
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures_util::{StreamExt, TryStreamExt};
+use tokio::runtime::{Builder, Runtime};
+use txn_assignment::{
+    csv::{parse_csv, parse_csv_lines},
+    model::State,
+    policy::Policy,
+    shard,
+};
+
+mod common;
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread()
+        .build()
+        .expect("Failed to build runtime")
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("parse");
+    group.sample_size(10);
+
+    for (mix, weights) in common::MIXES {
+        for rows in common::SIZES {
+            let data = common::file(rows, weights);
+            group.throughput(Throughput::Elements(rows as u64));
+            group.bench_with_input(BenchmarkId::new(mix, rows), &data, |b, data| {
+                b.iter(|| {
+                    rt.block_on(async {
+                        let stream = parse_csv(data.as_slice()).await;
+                        futures_util::pin_mut!(stream);
+                        while let Some(tx) = stream.next().await {
+                            black_box(tx.expect("Failed to parse"));
+                        }
+                    })
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn bench_handle(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("handle");
+    group.sample_size(10);
+
+    for (mix, weights) in common::MIXES {
+        for rows in common::SIZES {
+            let data = common::file(rows, weights);
+            let txs = rt.block_on(async {
+                parse_csv(data.as_slice())
+                    .await
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .expect("Failed to parse")
+            });
+
+            group.throughput(Throughput::Elements(rows as u64));
+            group.bench_with_input(BenchmarkId::new(mix, rows), &txs, |b, txs| {
+                b.iter_batched(
+                    || txs.clone(),
+                    |txs| {
+                        let mut state = State::default();
+                        for tx in txs {
+                            let _ = black_box(state.apply(tx));
+                        }
+                        // Returned so that dropping the State isn't measured
+                        state
+                    },
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn bench_end_to_end(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("end_to_end");
+    group.sample_size(10);
+
+    for (mix, weights) in common::MIXES {
+        for rows in common::SIZES {
+            let data = common::file(rows, weights);
+            group.throughput(Throughput::Elements(rows as u64));
+            group.bench_with_input(BenchmarkId::new(mix, rows), &data, |b, data| {
+                b.iter_with_large_drop(|| {
+                    rt.block_on(async {
+                        let stream = parse_csv(data.as_slice()).await;
+                        futures_util::pin_mut!(stream);
+                        let mut state = State::default();
+                        while let Some(tx) = stream.next().await {
+                            let _ = black_box(state.apply(tx.expect("Failed to parse")));
+                        }
+                        state
+                    })
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn bench_end_to_end_sharded(c: &mut Criterion) {
+    let rt = Builder::new_multi_thread()
+        .build()
+        .expect("Failed to build runtime");
+    let shards = std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+    let mut group = c.benchmark_group("end_to_end_sharded");
+    group.sample_size(10);
+
+    for (mix, weights) in common::MIXES {
+        for rows in common::SIZES {
+            let data = common::file(rows, weights);
+            group.throughput(Throughput::Elements(rows as u64));
+            group.bench_with_input(BenchmarkId::new(mix, rows), &data, |b, data| {
+                b.iter_with_large_drop(|| {
+                    rt.block_on(async {
+                        let stream = parse_csv_lines(data.as_slice()).await;
+                        shard::process(stream, shards, &Policy::default(), |e| {
+                            black_box(e);
+                        })
+                        .await
+                        .expect("Failed to parse")
+                    })
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_parse,
+    bench_handle,
+    bench_end_to_end,
+    bench_end_to_end_sharded
+);
+criterion_main!(benches);
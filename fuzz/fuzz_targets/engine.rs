@@ -0,0 +1,88 @@
+#![no_main]
+
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use futures_util::StreamExt;
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::{Builder, Runtime};
+use txn_assignment::{
+    csv::parse_csv,
+    model::{AccountId, State, TxType},
+};
+
+static RUNTIME: LazyLock<Runtime> =
+    LazyLock::new(|| Builder::new_current_thread().build().unwrap());
+
+/// Bitwise copy of every account, so that `NaN` balances still compare equal to themselves.
+fn snapshot(state: &State) -> BTreeMap<AccountId, (u32, u32, u32, bool)> {
+    state
+        .accounts
+        .iter()
+        .map(|(id, account)| {
+            (
+                *id,
+                (
+                    account.available.to_bits(),
+                    account.held.to_bits(),
+                    account.total.to_bits(),
+                    account.locked,
+                ),
+            )
+        })
+        .collect()
+}
+
+// Feeds arbitrary bytes through the CSV parser and applies every parsed Transaction to the
+// handlers, checking the account invariants after each row:
+//
+//  - A rejected Transaction leaves every account untouched.
+//  - An accepted Transaction only touches the account of its own Client in its currency, and a
+//    transfer that of its destination too.
+//  - A locked account is never modified or unlocked.
+//  - The total of an account stays the sum of its available and held funds.
+fuzz_target!(|data: &[u8]| {
+    RUNTIME.block_on(async {
+        let stream = parse_csv(data).await;
+        futures_util::pin_mut!(stream);
+
+        let mut state = State::default();
+        while let Some(Ok(tx)) = stream.next().await {
+            let (client_id, id) = (tx.client_id, (tx.client_id, tx.currency));
+            let destination = tx
+                .destination
+                .filter(|_| tx.tx_type == TxType::Transfer)
+                .map(|destination| (destination, tx.currency));
+            let before = snapshot(&state);
+
+            let res = state.apply(tx);
+
+            let mut after = snapshot(&state);
+            if res.is_ok() {
+                let mut untouched = before.clone();
+                for id in std::iter::once(id).chain(destination) {
+                    let touched = after.remove(&id);
+                    if let Some((_, _, _, true)) = before.get(&id) {
+                        assert_eq!(before.get(&id), touched.as_ref(), "Locked account changed");
+                    }
+                    untouched.remove(&id);
+                }
+                assert_eq!(untouched, after, "Transaction touched another Client's account");
+            } else {
+                assert_eq!(before, after, "Rejected transaction changed an account: {res:?}");
+            }
+
+            let Some(account) = state.accounts.get(&id) else {
+                continue;
+            };
+            let (available, held, total) = (account.available, account.held, account.total);
+            if [available, held, total].iter().all(|v| v.is_finite()) {
+                let tolerance = 1e-3 * available.abs().max(held.abs()).max(total.abs()).max(1.);
+                assert!(
+                    (total - (available + held)).abs() <= tolerance,
+                    "Client {client_id} total {total} diverged from available {available} and held {held}"
+                );
+            }
+        }
+    });
+});
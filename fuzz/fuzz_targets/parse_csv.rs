@@ -0,0 +1,30 @@
+#![no_main]
+
+use std::sync::LazyLock;
+
+use futures_util::StreamExt;
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::{Builder, Runtime};
+use txn_assignment::csv::parse_csv;
+
+static RUNTIME: LazyLock<Runtime> =
+    LazyLock::new(|| Builder::new_current_thread().build().unwrap());
+
+// Feeds arbitrary bytes through the CSV parser, which must never panic or yield more
+// Transactions than there are lines in the input.
+fuzz_target!(|data: &[u8]| {
+    RUNTIME.block_on(async {
+        let stream = parse_csv(data).await;
+        futures_util::pin_mut!(stream);
+
+        let lines = data.split(|b| *b == b'\n' || *b == b'\r').count();
+        let mut rows = 0;
+        while let Some(row) = stream.next().await {
+            rows += 1;
+            assert!(rows <= lines, "{rows} rows yielded from {lines} lines");
+            if row.is_err() {
+                break;
+            }
+        }
+    });
+});
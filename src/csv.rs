@@ -1,29 +1,123 @@
 use async_stream::try_stream;
-use csv_async::{AsyncReaderBuilder, ByteRecord};
+use csv_async::{AsyncReaderBuilder, AsyncWriterBuilder, ByteRecord};
 use futures_util::{Stream, StreamExt};
-use tokio::io::AsyncRead;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::{error::ParsingError, model::Transaction};
+use crate::{
+    error::ParsingError,
+    model::{ClientAccount, ClientId, Transaction},
+    rules::Flag,
+    shard::Rejection,
+};
 
 /// Parse and deserialize a CSV. Errors will occur if the CSV is empty, I/O errors or on faulty
 /// deserialization. Receives an `AsyncRead`, so can be swapped into a async TCP server receiving
 /// TCP packets, returns a stream of deserialized Transactions.
-pub(crate) async fn parse_csv(
+pub async fn parse_csv(
     read: impl AsyncRead + Unpin + Send,
 ) -> impl Stream<Item = Result<Transaction, ParsingError>> {
+    parse_csv_lines(read).await.map(|row| row.map(|(_, tx)| tx))
+}
+
+/// Parse and deserialize a CSV like [`parse_csv`], along with the line each Transaction starts on
+/// in the source, counting from 1 for the header.
+pub async fn parse_csv_lines(
+    read: impl AsyncRead + Unpin + Send,
+) -> impl Stream<Item = Result<(u64, Transaction), ParsingError>> {
+    let rows = parse_csv_rows(read).await;
+    try_stream! {
+        for await row in rows {
+            let (line, tx) = row?;
+            match tx {
+                Ok(tx) => yield (line, tx),
+                // Columns are read by position, so any header is accepted
+                Err(ParsingError::Header { .. }) => continue,
+                Err(e) => Err(e)?,
+            }
+        }
+    }
+}
+
+/// Columns of the CSV, in the order they are read.
+pub const HEADER: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Optional column of the CSV after [`HEADER`], holding the currency of each row.
+pub const CURRENCY: &str = "currency";
+
+/// Optional last column of the CSV after [`CURRENCY`], holding the Client a transfer credits.
+pub const DESTINATION: &str = "destination";
+
+/// Whether a header row names the [`HEADER`] columns, optionally followed by [`CURRENCY`] and
+/// [`DESTINATION`].
+fn is_header(record: &ByteRecord) -> bool {
+    let columns = record
+        .iter()
+        .map(|field| field.trim_ascii().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let optional = [CURRENCY, DESTINATION];
+    (0..=optional.len()).any(|count| {
+        let expected = HEADER.iter().chain(&optional[..count]);
+        columns.iter().eq(expected.map(|column| column.as_bytes()))
+    })
+}
+
+/// Parse and deserialize every row of a CSV along with its line, carrying on past rows that can't
+/// be read or deserialized. A header other than [`HEADER`], with or without the [`CURRENCY`] and
+/// [`DESTINATION`] columns, is reported as a `Header` error on line 1. Rows may leave out the
+/// currency and destination, whichever header the file has. The stream only fails, and ends, if
+/// the CSV is empty or can't be read.
+pub async fn parse_csv_rows(
+    read: impl AsyncRead + Unpin + Send,
+) -> impl Stream<Item = Result<(u64, Result<Transaction, ParsingError>), ParsingError>> {
     let mut rdr = AsyncReaderBuilder::new()
         .trim(csv_async::Trim::All)
         // This parameter seems to be a bug in the csv_async implementation
         .has_headers(false)
+        // Rows only carry a currency where they need one
+        .flexible(true)
         .end_on_io_error(true)
         .create_deserializer(read);
 
     let mut record = ByteRecord::new();
     try_stream! {
       if rdr.read_byte_record(&mut record).await.map_err(|e| ParsingError::ReadRecord{ record: ByteRecord::clone(&record), source: e })? {
-          let mut row = rdr.deserialize();
-          while let Some(col) = row.next().await {
-            yield col.map_err(|e| ParsingError::Deserialize{ record: ByteRecord::clone(&record), source: e })?;
+          // The reader counts the line feeds it consumed. A record ends on the line before its line
+          // feed, whereas a CRLF terminated record ends on the carriage return, leaving its line
+          // feed to the next read. The header shows which line endings the file uses
+          let crlf = rdr.position().line() == 1;
+          let header = ByteRecord::clone(&record);
+          if !is_header(&header) {
+              yield (1, Err(ParsingError::Header { record: ByteRecord::clone(&header) }));
+          }
+          loop {
+            let start = rdr.position().line();
+            let read = match rdr.read_byte_record(&mut record).await {
+                Ok(read) => read,
+                Err(e) => {
+                    let fatal = e.is_io_error();
+                    let end = rdr.position().line();
+                    let line = if crlf || end == start { end } else { end - 1 };
+                    yield (line, Err(ParsingError::ReadRecord{ record: ByteRecord::clone(&record), source: e }));
+                    if fatal {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            if !read {
+                break;
+            }
+            let end = rdr.position().line();
+            let line = if crlf || end == start { end } else { end - 1 };
+            let tx = record.deserialize::<Transaction>(None).map_err(|e| {
+                tracing::debug!(line, error = %e, "couldn't deserialize row");
+                ParsingError::Deserialize{ record: ByteRecord::clone(&header), source: e }
+            });
+            if let Ok(tx) = &tx {
+                tracing::trace!(line, tx_type = tx.tx_type.as_ref(), tx = tx.tx_id, client = tx.client_id, amount = tx.amount, "parsed row");
+            }
+            yield (line, tx);
           }
         } else {
             Err(ParsingError::NoRecords{ record })?
@@ -31,19 +125,237 @@ pub(crate) async fn parse_csv(
     }
 }
 
+/// Parse and deserialize a single CSV row without a header, such as a line received by the TCP
+/// server. Errors will occur if the row is empty or on faulty deserialization.
+pub async fn parse_row(row: &[u8]) -> Result<Transaction, ParsingError> {
+    let mut rdr = AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .has_headers(false)
+        .create_deserializer(row);
+
+    match rdr.deserialize().next().await {
+        Some(tx) => tx.map_err(|e| ParsingError::Deserialize {
+            record: ByteRecord::from(vec![row]),
+            source: e,
+        }),
+        None => Err(ParsingError::NoRecords {
+            record: ByteRecord::new(),
+        }),
+    }
+}
+
+/// Parse Client accounts in the application's output format,
+/// `client,available,held,total,locked[,currency]`, with or without a header row.
+pub async fn parse_accounts(
+    read: impl AsyncRead + Unpin + Send,
+) -> Result<Vec<ClientAccount>, ParsingError> {
+    parse_rows(read, b"client").await
+}
+
+/// Parse the tier assigned to each Client, as `client,tier` rows with or without a header row.
+pub async fn parse_client_tiers(
+    read: impl AsyncRead + Unpin + Send,
+) -> Result<Vec<(ClientId, String)>, ParsingError> {
+    parse_rows(read, b"client").await
+}
+
+/// Parse FX rates, as `date,pair,rate` rows with or without a header row.
+pub async fn parse_rates(
+    read: impl AsyncRead + Unpin + Send,
+) -> Result<Vec<(String, String, f64)>, ParsingError> {
+    parse_rows(read, b"date").await
+}
+
+/// Parse rows, skipping a header row starting with the `first` column, such as `client`.
+async fn parse_rows<T: DeserializeOwned>(
+    read: impl AsyncRead + Unpin + Send,
+    first: &[u8],
+) -> Result<Vec<T>, ParsingError> {
+    let mut rdr = AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .has_headers(false)
+        .flexible(true)
+        .create_deserializer(read);
+
+    let mut rows = Vec::new();
+    let mut record = ByteRecord::new();
+    while rdr
+        .read_byte_record(&mut record)
+        .await
+        .map_err(|e| ParsingError::ReadRecord {
+            record: ByteRecord::clone(&record),
+            source: e,
+        })?
+    {
+        if rows.is_empty() && record.get(0).map(<[u8]>::trim_ascii) == Some(first) {
+            continue;
+        }
+        let row = record
+            .deserialize(None)
+            .map_err(|e| ParsingError::Deserialize {
+                record: ByteRecord::clone(&record),
+                source: e,
+            })?;
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Writes rejected transactions as CSV rows with a header: the original
+/// `type,client,tx,amount,currency,destination` fields, followed by the error `code`, its `message`
+/// and the source `line`.
+pub async fn write_rejections(
+    write: impl AsyncWrite + Unpin,
+    rejections: &[Rejection],
+) -> Result<(), csv_async::Error> {
+    let mut wtr = AsyncWriterBuilder::new().create_writer(write);
+    wtr.write_record([
+        "type",
+        "client",
+        "tx",
+        "amount",
+        "currency",
+        "destination",
+        "code",
+        "message",
+        "line",
+    ])
+    .await?;
+    for rejection in rejections {
+        let tx = &rejection.tx;
+        wtr.write_record([
+            tx.tx_type.as_ref().to_lowercase(),
+            tx.client_id.to_string(),
+            tx.tx_id.to_string(),
+            tx.amount
+                .map(|amount| amount.to_string())
+                .unwrap_or_default(),
+            tx.currency
+                .map(|currency| currency.to_string())
+                .unwrap_or_default(),
+            tx.destination
+                .map(|destination| destination.to_string())
+                .unwrap_or_default(),
+            rejection.error.code().to_owned(),
+            rejection.error.to_string(),
+            rejection.line.to_string(),
+        ])
+        .await?;
+    }
+    wtr.flush().await?;
+    Ok(())
+}
+
+/// Writes transactions flagged for review as CSV rows with a header: the original
+/// `type,client,tx,amount,currency,destination` fields, followed by the `rule` that flagged it and
+/// its `reason`.
+pub async fn write_flags(
+    write: impl AsyncWrite + Unpin,
+    flags: &[Flag],
+) -> Result<(), csv_async::Error> {
+    let mut wtr = AsyncWriterBuilder::new().create_writer(write);
+    wtr.write_record([
+        "type",
+        "client",
+        "tx",
+        "amount",
+        "currency",
+        "destination",
+        "rule",
+        "reason",
+    ])
+    .await?;
+    for flag in flags {
+        let tx = &flag.tx;
+        wtr.write_record([
+            tx.tx_type.as_ref().to_lowercase(),
+            tx.client_id.to_string(),
+            tx.tx_id.to_string(),
+            tx.amount
+                .map(|amount| amount.to_string())
+                .unwrap_or_default(),
+            tx.currency
+                .map(|currency| currency.to_string())
+                .unwrap_or_default(),
+            tx.destination
+                .map(|destination| destination.to_string())
+                .unwrap_or_default(),
+            flag.rule.to_owned(),
+            flag.reason.clone(),
+        ])
+        .await?;
+    }
+    wtr.flush().await?;
+    Ok(())
+}
+
+/// Formats a Client account as an output row: `client,available,held,total,locked`, followed by
+/// the `currency` of accounts that have one.
+pub fn format_account(account: &ClientAccount) -> String {
+    let row = format!(
+        "{},{},{},{},{}",
+        account.client_id,
+        fmt_decimals(account.available),
+        fmt_decimals(account.held),
+        fmt_decimals(account.total),
+        account.locked
+    );
+    match account.currency {
+        Some(currency) => format!("{row},{currency}"),
+        None => row,
+    }
+}
+
+/// Formats a Transaction as an input row: `type,client,tx,amount`, followed by the `currency` of
+/// transactions that have one, and the `destination` of transfers.
+pub fn format_transaction(tx: &Transaction) -> String {
+    let row = format!(
+        "{},{},{},{}",
+        tx.tx_type.as_ref().to_lowercase(),
+        tx.client_id,
+        tx.tx_id,
+        tx.amount.map(fmt_decimals).unwrap_or_default()
+    );
+    match (tx.currency, tx.destination) {
+        (currency, Some(destination)) => {
+            let currency = currency.map(|c| c.to_string()).unwrap_or_default();
+            format!("{row},{currency},{destination}")
+        }
+        (Some(currency), None) => format!("{row},{currency}"),
+        (None, None) => row,
+    }
+}
+
+/// Formats an amount to 4 decimal places, without trailing zeros. `f32` amounts are widened to
+/// `f64` exactly, so formatting either keeps the precision of the value given.
+pub(crate) fn fmt_decimals(value: impl Into<f64>) -> String {
+    let formatted = format!("{:.4}", value.into());
+
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
 #[cfg(test)]
 mod tests {
-    use futures_util::TryStreamExt;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_util::{StreamExt, TryStreamExt};
     use rstest::rstest;
+    use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 
-    use super::parse_csv;
+    use super::{parse_csv, parse_csv_lines, parse_row, write_flags, write_rejections};
     use crate::{
-        error::ParsingError,
+        error::{ParsingError, TransactionError},
         model::{Transaction, TxType},
+        rules::Flag,
+        shard::Rejection,
     };
 
     #[rstest]
-    #[tokio::test]
     #[case::happy(indoc::indoc!{
         b"\
         type,client,tx,amount
@@ -52,14 +364,43 @@ mod tests {
         "
     }.as_slice(),
         vec![
-            Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 1, amount: Some(100.)},
-            Transaction{tx_type: TxType::Withdrawal, client_id: 1, tx_id: 2, amount: Some(250.)}
+            Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 1, amount: Some(100.), currency: None, destination: None},
+            Transaction{tx_type: TxType::Withdrawal, client_id: 1, tx_id: 2, amount: Some(250.), currency: None, destination: None}
+        ]
+    )]
+    #[case::currency(indoc::indoc!{
+        b"\
+        type,client,tx,amount,currency
+        deposit,1,1,100.0,usd
+        dispute,1,1,,USD
+        withdrawal,1,2,250.0
+        "
+    }.as_slice(),
+        vec![
+            Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 1, amount: Some(100.), currency: "USD".parse().ok(), destination: None},
+            Transaction{tx_type: TxType::Dispute, client_id: 1, tx_id: 1, amount: None, currency: "USD".parse().ok(), destination: None},
+            Transaction{tx_type: TxType::Withdrawal, client_id: 1, tx_id: 2, amount: Some(250.), currency: None, destination: None}
+        ]
+    )]
+    #[case::transfer(indoc::indoc!{
+        b"\
+        type,client,tx,amount,currency,destination
+        transfer,1,1,20.0,,2
+        transfer,1,2,5.0,EUR,3
+        deposit,1,3,1.0
+        "
+    }.as_slice(),
+        vec![
+            Transaction{tx_type: TxType::Transfer, client_id: 1, tx_id: 1, amount: Some(20.), currency: None, destination: Some(2)},
+            Transaction{tx_type: TxType::Transfer, client_id: 1, tx_id: 2, amount: Some(5.), currency: "EUR".parse().ok(), destination: Some(3)},
+            Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 3, amount: Some(1.), currency: None, destination: None}
         ]
     )]
     #[case::empty(indoc::indoc!{
         b"\
         type,client,tx,amount"
     }.as_slice(), vec![])]
+    #[tokio::test]
     async fn test_parse_csv(#[case] input: &[u8], #[case] expected: Vec<Transaction>) {
         let result = parse_csv(input).await;
 
@@ -71,7 +412,6 @@ mod tests {
     }
 
     #[rstest]
-    #[tokio::test]
     #[case::additional_whitespace(indoc::indoc!{
         b"\
         type,client,tx,amount
@@ -81,12 +421,13 @@ mod tests {
         withdrawal,1,4,150.0
         "
     }.as_slice(), vec![
-            Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 1, amount: Some(100.)},
-            Transaction{tx_type: TxType::Deposit, client_id: 2, tx_id: 2, amount: Some(200.)},
-            Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 3, amount: Some(200.23447)},
-            Transaction{tx_type: TxType::Withdrawal, client_id: 1, tx_id: 4, amount: Some(150.)}
+            Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 1, amount: Some(100.), currency: None, destination: None},
+            Transaction{tx_type: TxType::Deposit, client_id: 2, tx_id: 2, amount: Some(200.), currency: None, destination: None},
+            Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 3, amount: Some(200.23447), currency: None, destination: None},
+            Transaction{tx_type: TxType::Withdrawal, client_id: 1, tx_id: 4, amount: Some(150.), currency: None, destination: None}
             
         ])]
+    #[tokio::test]
     async fn test_parse_csv_whitespace(#[case] input: &[u8], #[case] expected: Vec<Transaction>) {
         let result = parse_csv(input).await;
 
@@ -98,10 +439,10 @@ mod tests {
     }
 
     #[rstest]
-    #[tokio::test]
     #[case::no_records(indoc::indoc!{
         b""
     }.as_slice())]
+    #[tokio::test]
     async fn test_parse_csv_no_records(#[case] input: &[u8]) {
         let result = parse_csv(input).await;
 
@@ -110,13 +451,13 @@ mod tests {
     }
 
     #[rstest]
-    #[tokio::test]
     #[case::deserialize_missing(indoc::indoc!{
         b"\
         type,client,tx,amount
         deposit,1
         "
     }.as_slice())]
+    #[tokio::test]
     async fn test_parse_csv_deserialize(#[case] input: &[u8]) {
         let result = parse_csv(input).await;
 
@@ -125,13 +466,13 @@ mod tests {
     }
 
     #[rstest]
-    #[tokio::test]
     #[case::invalid_transaction_type(indoc::indoc!{
         b"\
         type,client,tx,amount
         invalid_type,1,1,100.0
         "
     }.as_slice())]
+    #[tokio::test]
     async fn test_parse_csv_invalid_transaction_type(#[case] input: &[u8]) {
         let result = parse_csv(input).await;
 
@@ -140,13 +481,13 @@ mod tests {
     }
 
     #[rstest]
-    #[tokio::test]
     #[case::invalid_client_id(indoc::indoc!{
         b"\
         type,client,tx,amount
         deposit,not_a_number,1,100.0
         "
     }.as_slice())]
+    #[tokio::test]
     async fn test_parse_csv_invalid_client_id(#[case] input: &[u8]) {
         let result = parse_csv(input).await;
 
@@ -155,13 +496,13 @@ mod tests {
     }
 
     #[rstest]
-    #[tokio::test]
     #[case::invalid_tx_id(indoc::indoc!{
         b"\
         type,client,tx,amount
         deposit,1,not_a_number,100.0
         "
     }.as_slice())]
+    #[tokio::test]
     async fn test_parse_csv_invalid_tx_id(#[case] input: &[u8]) {
         let result = parse_csv(input).await;
 
@@ -170,13 +511,13 @@ mod tests {
     }
 
     #[rstest]
-    #[tokio::test]
     #[case::invalid_amount(indoc::indoc!{
         b"\
         type,client,tx,amount
         deposit,1,1,not_a_number
         "
     }.as_slice())]
+    #[tokio::test]
     async fn test_parse_csv_invalid_amount(#[case] input: &[u8]) {
         let result = parse_csv(input).await;
 
@@ -185,7 +526,21 @@ mod tests {
     }
 
     #[rstest]
+    #[case::invalid_currency(indoc::indoc!{
+        b"\
+        type,client,tx,amount,currency
+        deposit,1,1,1.0,EURO
+        "
+    }.as_slice())]
     #[tokio::test]
+    async fn test_parse_csv_invalid_currency(#[case] input: &[u8]) {
+        let result = parse_csv(input).await;
+
+        let actual = result.try_collect::<Vec<_>>().await;
+        assert!(matches!(actual, Err(ParsingError::Deserialize { .. })));
+    }
+
+    #[rstest]
     #[case::dispute_resolve_chargeback_transactions(indoc::indoc!{
         b"\
         type,client,tx,amount
@@ -194,10 +549,11 @@ mod tests {
         chargeback,1,3,
         "
     }.as_slice(), vec![
-            Transaction{tx_type: TxType::Dispute, client_id: 1, tx_id: 1, amount: None},
-            Transaction{tx_type: TxType::Resolve, client_id: 1, tx_id: 2, amount: None},
-            Transaction{tx_type: TxType::Chargeback, client_id: 1, tx_id: 3, amount: None}
+            Transaction{tx_type: TxType::Dispute, client_id: 1, tx_id: 1, amount: None, currency: None, destination: None},
+            Transaction{tx_type: TxType::Resolve, client_id: 1, tx_id: 2, amount: None, currency: None, destination: None},
+            Transaction{tx_type: TxType::Chargeback, client_id: 1, tx_id: 3, amount: None, currency: None, destination: None}
         ])]
+    #[tokio::test]
     async fn test_parse_csv_dispute_resolve_chargeback(#[case] input: &[u8], #[case] expected: Vec<Transaction>) {
         let result = parse_csv(input).await;
 
@@ -209,7 +565,6 @@ mod tests {
     }
 
     #[rstest]
-    #[tokio::test]
     #[case::very_precise_amounts(indoc::indoc!{
         b"\
         type,client,tx,amount
@@ -218,10 +573,11 @@ mod tests {
         withdrawal,1,3,999999.9999
         "
     }.as_slice(), vec![
-            Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 1, amount: Some(123.4567)},
-            Transaction{tx_type: TxType::Deposit, client_id: 2, tx_id: 2, amount: Some(0.0001)},
-            Transaction{tx_type: TxType::Withdrawal, client_id: 1, tx_id: 3, amount: Some(999999.9999)}
+            Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 1, amount: Some(123.4567), currency: None, destination: None},
+            Transaction{tx_type: TxType::Deposit, client_id: 2, tx_id: 2, amount: Some(0.0001), currency: None, destination: None},
+            Transaction{tx_type: TxType::Withdrawal, client_id: 1, tx_id: 3, amount: Some(999999.9999), currency: None, destination: None}
         ])]
+    #[tokio::test]
     async fn test_parse_csv_precise_amounts(#[case] input: &[u8], #[case] expected: Vec<Transaction>) {
         let result = parse_csv(input).await;
 
@@ -233,15 +589,42 @@ mod tests {
     }
 
     #[rstest]
+    #[case::deposit(b"deposit,1,1,100.0", Transaction{tx_type: TxType::Deposit, client_id: 1, tx_id: 1, amount: Some(100.), currency: None, destination: None})]
+    #[case::whitespace(b" withdrawal, 2 ,3,  1.5 ", Transaction{tx_type: TxType::Withdrawal, client_id: 2, tx_id: 3, amount: Some(1.5), currency: None, destination: None})]
+    #[case::no_amount(b"dispute,1,1,", Transaction{tx_type: TxType::Dispute, client_id: 1, tx_id: 1, amount: None, currency: None, destination: None})]
+    #[tokio::test]
+    async fn test_parse_row(#[case] input: &[u8], #[case] expected: Transaction) {
+        let actual = parse_row(input).await.expect("Failed to parse");
+        assert_eq!(actual, expected);
+    }
+
+    #[rstest]
+    #[case::empty(b"")]
     #[tokio::test]
+    async fn test_parse_row_no_records(#[case] input: &[u8]) {
+        let actual = parse_row(input).await;
+        assert!(matches!(actual, Err(ParsingError::NoRecords { .. })));
+    }
+
+    #[rstest]
+    #[case::missing_fields(b"deposit,1")]
+    #[case::header(b"type,client,tx,amount")]
+    #[tokio::test]
+    async fn test_parse_row_deserialize(#[case] input: &[u8]) {
+        let actual = parse_row(input).await;
+        assert!(matches!(actual, Err(ParsingError::Deserialize { .. })));
+    }
+
+    #[rstest]
     #[case::max_client_and_tx_ids(indoc::indoc!{
         b"\
         type,client,tx,amount
         deposit,65535,4294967295,100.0
         "
     }.as_slice(), vec![
-            Transaction{tx_type: TxType::Deposit, client_id: 65535, tx_id: 4294967295, amount: Some(100.0)}
+            Transaction{tx_type: TxType::Deposit, client_id: 65535, tx_id: 4294967295, amount: Some(100.0), currency: None, destination: None}
         ])]
+    #[tokio::test]
     async fn test_parse_csv_max_ids(#[case] input: &[u8], #[case] expected: Vec<Transaction>) {
         let result = parse_csv(input).await;
 
@@ -251,4 +634,171 @@ mod tests {
             .expect("Failed to parse");
         assert_eq!(actual, expected);
     }
+
+    #[rstest]
+    #[case::lines(indoc::indoc!{
+        b"\
+        type,client,tx,amount
+        deposit,1,1,100.0
+
+        withdrawal,1,2,250.0
+        dispute,1,1,
+        "
+    }.as_slice(), vec![2, 4, 5])]
+    #[case::crlf(b"type,client,tx,amount\r\ndeposit,1,1,1\r\n\r\ndeposit,1,2,1\r\n".as_slice(), vec![2, 4])]
+    #[case::no_trailing_newline(b"type,client,tx,amount\ndeposit,1,1,1\ndispute,1,1,".as_slice(), vec![2, 3])]
+    #[tokio::test]
+    async fn test_parse_csv_lines(#[case] input: &[u8], #[case] expected: Vec<u64>) {
+        let result = parse_csv_lines(input).await;
+
+        let actual = result
+            .map_ok(|(line, _)| line)
+            .try_collect::<Vec<_>>()
+            .await
+            .expect("Failed to parse");
+        assert_eq!(actual, expected);
+    }
+
+    /// Reader failing on every read, such as a dropped connection.
+    struct FailingRead;
+
+    impl AsyncRead for FailingRead {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::Error::other("connection reset")))
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_parse_csv_read_error() {
+        let input = b"type,client,tx,amount\ndeposit,1,1,1\n"
+            .as_slice()
+            .chain(FailingRead);
+
+        let actual = parse_csv(input).await.collect::<Vec<_>>().await;
+
+        assert!(
+            matches!(
+                actual.as_slice(),
+                [Ok(_), Err(ParsingError::ReadRecord { .. })]
+            ),
+            "{actual:?}"
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_rejections() {
+        let rejections = [
+            Rejection {
+                line: 3,
+                tx: Transaction {
+                    tx_type: TxType::Withdrawal,
+                    client_id: 1,
+                    tx_id: 2,
+                    amount: Some(250.5),
+                    currency: "EUR".parse().ok(),
+                    destination: None,
+                },
+                error: TransactionError::BalanceInsufficient {
+                    available: 100.,
+                    tx_type: TxType::Withdrawal,
+                    id: 2,
+                    amount: 250.5,
+                },
+            },
+            Rejection {
+                line: 5,
+                tx: Transaction {
+                    tx_type: TxType::Transfer,
+                    client_id: 1,
+                    tx_id: 4,
+                    amount: Some(20.),
+                    currency: None,
+                    destination: Some(3),
+                },
+                error: TransactionError::AccountNotFound { id: 3 },
+            },
+            Rejection {
+                line: 7,
+                tx: Transaction {
+                    tx_type: TxType::Dispute,
+                    client_id: 2,
+                    tx_id: 9,
+                    amount: None,
+                    currency: None,
+                    destination: None,
+                },
+                error: TransactionError::NotFound {
+                    tx_type: TxType::Dispute,
+                    id: 9,
+                },
+            },
+        ];
+
+        let mut out = Vec::new();
+        write_rejections(&mut out, &rejections)
+            .await
+            .expect("Failed to write");
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {"
+                type,client,tx,amount,currency,destination,code,message,line
+                withdrawal,1,2,250.5,EUR,,E_INSUFFICIENT_FUNDS,\"Balance insufficient: available '100', Withdrawal amount '250.5', Transaction Id '2'\",3
+                transfer,1,4,20,,3,E_ACCOUNT_NOT_FOUND,Account not found processing transaction: Client Id '3',5
+                dispute,2,9,,,,E_TX_NOT_FOUND,Transaction not found or is invalid for type Dispute: Transaction Id '9',7
+            "}
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_flags() {
+        let flags = [
+            Flag {
+                tx: Transaction {
+                    tx_type: TxType::Withdrawal,
+                    client_id: 1,
+                    tx_id: 2,
+                    amount: Some(100.),
+                    currency: None,
+                    destination: None,
+                },
+                rule: "quick_withdrawal",
+                reason: "withdrawal of 100 follows a deposit of 100 within 3 transactions"
+                    .to_owned(),
+            },
+            Flag {
+                tx: Transaction {
+                    tx_type: TxType::Transfer,
+                    client_id: 1,
+                    tx_id: 4,
+                    amount: Some(50.),
+                    currency: "EUR".parse().ok(),
+                    destination: Some(2),
+                },
+                rule: "quick_withdrawal",
+                reason: "transfer of 50 follows a deposit of 100 within 3 transactions".to_owned(),
+            },
+        ];
+
+        let mut out = Vec::new();
+        write_flags(&mut out, &flags)
+            .await
+            .expect("Failed to write");
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {"
+                type,client,tx,amount,currency,destination,rule,reason
+                withdrawal,1,2,100,,,quick_withdrawal,withdrawal of 100 follows a deposit of 100 within 3 transactions
+                transfer,1,4,50,EUR,2,quick_withdrawal,transfer of 50 follows a deposit of 100 within 3 transactions
+            "}
+        );
+    }
 }
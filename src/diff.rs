@@ -0,0 +1,462 @@
+//! Comparison of two balance outputs, or of the balances of one transaction file replayed under two
+//! policy configs, reporting per-Client differences so that the effect of a change can be reviewed
+//! before it is deployed.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use csv_async::AsyncWriterBuilder;
+use serde::Serialize;
+use strum::AsRefStr;
+use tokio::io::AsyncWrite;
+
+use crate::{
+    csv::{CURRENCY, fmt_decimals, parse_accounts, parse_csv_lines},
+    error::{Error, RunError},
+    model::{AccountId, ClientAccount, Currency},
+    policy::Policy,
+    shard,
+};
+
+/// Compare two balance outputs, or replay a file under two policy configs, reporting per-Client
+/// differences
+#[derive(Debug, clap::Args)]
+pub struct DiffArgs {
+    /// Balances to compare from, in the application's output format
+    #[arg(required_unless_present = "input")]
+    pub left: Option<PathBuf>,
+    /// Balances to compare to
+    #[arg(required_unless_present = "input")]
+    pub right: Option<PathBuf>,
+    /// CSV file of transactions to replay under both policy configs, instead of comparing two
+    /// balance files
+    #[arg(long, conflicts_with_all = ["left", "right"])]
+    pub input: Option<PathBuf>,
+    /// Policy config to replay the input under on the left. Defaults to `--config`, or the
+    /// built-in policies
+    #[arg(long, conflicts_with_all = ["left", "right"])]
+    pub left_config: Option<PathBuf>,
+    /// Policy config to replay the input under on the right. Defaults to `--config`, or the
+    /// built-in policies
+    #[arg(long, conflicts_with_all = ["left", "right"])]
+    pub right_config: Option<PathBuf>,
+    /// Largest difference between two balances that is still considered equal
+    #[arg(long, default_value_t = 0., value_parser = parse_tolerance)]
+    pub tolerance: f32,
+}
+
+pub(crate) fn parse_tolerance(tolerance: &str) -> Result<f32, String> {
+    match tolerance.parse::<f32>() {
+        Ok(tolerance) if tolerance >= 0. && tolerance.is_finite() => Ok(tolerance),
+        Ok(_) => Err("must be a non-negative number".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Field of a Client account that differs between the two sides.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, AsRefStr, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Field {
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+/// How a Client differs between the two sides.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    OnlyLeft(ClientAccount),
+    OnlyRight(ClientAccount),
+    Changed {
+        left: ClientAccount,
+        right: ClientAccount,
+        fields: Vec<Field>,
+    },
+}
+
+/// Differences between two sets of balances, by Client Id and currency.
+#[derive(Debug, Default)]
+pub struct Comparison {
+    pub clients: usize,
+    pub identical: usize,
+    pub changes: BTreeMap<AccountId, Change>,
+    /// Largest difference between two balances of a Client present on both sides.
+    pub max_difference: f64,
+}
+
+impl Comparison {
+    fn count(&self, kind: fn(&Change) -> bool) -> usize {
+        self.changes.values().filter(|change| kind(change)).count()
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Compared {} clients: {} identical, {} changed, {} only in left, {} only in right, largest difference {}",
+            self.clients,
+            self.identical,
+            self.count(|c| matches!(c, Change::Changed { .. })),
+            self.count(|c| matches!(c, Change::OnlyLeft(_))),
+            self.count(|c| matches!(c, Change::OnlyRight(_))),
+            self.max_difference
+        )
+    }
+}
+
+/// Difference between two balances, computed in `f64` so that it isn't rounded again.
+fn difference(left: f32, right: f32) -> f64 {
+    f64::from(right) - f64::from(left)
+}
+
+/// Compares two sets of balances. Balances that differ by at most `tolerance` are equal, whereas
+/// a different lock always counts as a change.
+pub fn compare(left: &[ClientAccount], right: &[ClientAccount], tolerance: f32) -> Comparison {
+    let left = left.iter().map(|a| (a.id(), a)).collect::<BTreeMap<_, _>>();
+    let right = right
+        .iter()
+        .map(|a| (a.id(), a))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut comparison = Comparison::default();
+    for (id, account) in &left {
+        let Some(other) = right.get(id) else {
+            comparison
+                .changes
+                .insert(*id, Change::OnlyLeft((*account).clone()));
+            continue;
+        };
+
+        let mut fields = Vec::new();
+        for (field, l, r) in [
+            (Field::Available, account.available, other.available),
+            (Field::Held, account.held, other.held),
+            (Field::Total, account.total, other.total),
+        ] {
+            let difference = difference(l, r).abs();
+            comparison.max_difference = comparison.max_difference.max(difference);
+            if difference > f64::from(tolerance) {
+                fields.push(field);
+            }
+        }
+        if account.locked != other.locked {
+            fields.push(Field::Locked);
+        }
+
+        if fields.is_empty() {
+            comparison.identical += 1;
+        } else {
+            comparison.changes.insert(
+                *id,
+                Change::Changed {
+                    left: (*account).clone(),
+                    right: (*other).clone(),
+                    fields,
+                },
+            );
+        }
+    }
+    for (id, account) in &right {
+        if !left.contains_key(id) {
+            comparison
+                .changes
+                .insert(*id, Change::OnlyRight((*account).clone()));
+        }
+    }
+    comparison.clients = comparison.identical + comparison.changes.len();
+
+    comparison
+}
+
+/// Appends the `currency` column to a report row, when the report covers accounts with currencies.
+pub(crate) fn with_currency<T>(row: impl IntoIterator<Item = T>, currency: Option<T>) -> Vec<T> {
+    row.into_iter().chain(currency).collect()
+}
+
+/// Writes the changes as CSV rows with a header, `client,field,left,right,difference`: one row per
+/// changed field, or an `account` row for a Client missing from one side. A `currency` column is
+/// added when any changed account has a currency.
+pub async fn write_changes(
+    write: impl AsyncWrite + Unpin,
+    comparison: &Comparison,
+) -> Result<(), csv_async::Error> {
+    let currencies = comparison.changes.keys().any(|(_, c)| c.is_some());
+    let mut wtr = AsyncWriterBuilder::new().create_writer(write);
+    wtr.write_record(with_currency(
+        ["client", "field", "left", "right", "difference"],
+        currencies.then_some(CURRENCY),
+    ))
+    .await?;
+    for ((client_id, currency), change) in &comparison.changes {
+        let client_id = client_id.to_string();
+        let currency = currencies.then(|| currency.as_ref().map_or("", Currency::as_str));
+        match change {
+            Change::OnlyLeft(_) => {
+                wtr.write_record(with_currency(
+                    [client_id.as_str(), "account", "present", "missing", ""],
+                    currency,
+                ))
+                .await?
+            }
+            Change::OnlyRight(_) => {
+                wtr.write_record(with_currency(
+                    [client_id.as_str(), "account", "missing", "present", ""],
+                    currency,
+                ))
+                .await?
+            }
+            Change::Changed {
+                left,
+                right,
+                fields,
+            } => {
+                for field in fields {
+                    let (l, r) = match field {
+                        Field::Available => (left.available, right.available),
+                        Field::Held => (left.held, right.held),
+                        Field::Total => (left.total, right.total),
+                        Field::Locked => {
+                            wtr.write_record(with_currency(
+                                [
+                                    client_id.as_str(),
+                                    field.as_ref(),
+                                    &left.locked.to_string(),
+                                    &right.locked.to_string(),
+                                    "",
+                                ],
+                                currency,
+                            ))
+                            .await?;
+                            continue;
+                        }
+                    };
+                    wtr.write_record(with_currency(
+                        [
+                            client_id.as_str(),
+                            field.as_ref(),
+                            &fmt_decimals(l),
+                            &fmt_decimals(r),
+                            &fmt_decimals(difference(l, r)),
+                        ],
+                        currency,
+                    ))
+                    .await?;
+                }
+            }
+        }
+    }
+    wtr.flush().await?;
+    Ok(())
+}
+
+pub(crate) async fn read_accounts(
+    path: &Path,
+) -> Result<Vec<ClientAccount>, Box<dyn std::error::Error>> {
+    let fp = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| Error::IOError {
+            filename: path.to_path_buf(),
+            source: e,
+        })?;
+    Ok(parse_accounts(fp).await?)
+}
+
+/// Processes a transaction file across `shards` under `policy`, skipping rejected transactions as
+/// a run does, and returns the resulting balances.
+pub(crate) async fn replay(
+    path: &Path,
+    shards: NonZeroUsize,
+    policy: &Policy,
+) -> Result<Vec<ClientAccount>, Box<dyn std::error::Error>> {
+    let fp = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| Error::IOError {
+            filename: path.to_path_buf(),
+            source: e,
+        })?;
+    let state = shard::process(parse_csv_lines(fp).await, shards, policy, |_| {}).await?;
+    Ok(state.accounts.into_values().collect())
+}
+
+async fn load_or(path: &Option<PathBuf>, policy: &Policy) -> Result<Policy, Error> {
+    match path {
+        Some(path) => Policy::load(path).await,
+        None => Ok(policy.clone()),
+    }
+}
+
+/// Compares two balance files, or the balances of the input replayed across `shards` under the
+/// left and right policy configs, falling back to `policy`. Writes the changes as CSV rows to
+/// `stdout` and a summary to `stderr`. Fails when there are changes, so that the exit status tells
+/// whether they match.
+pub async fn run(
+    args: &DiffArgs,
+    shards: NonZeroUsize,
+    policy: &Policy,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (left, right) = match (&args.input, &args.left, &args.right) {
+        (Some(input), _, _) => {
+            let left_policy = load_or(&args.left_config, policy).await?;
+            let right_policy = load_or(&args.right_config, policy).await?;
+            (
+                replay(input, shards, &left_policy).await?,
+                replay(input, shards, &right_policy).await?,
+            )
+        }
+        (None, Some(left), Some(right)) => {
+            (read_accounts(left).await?, read_accounts(right).await?)
+        }
+        _ => unreachable!("Balance files are required without an input"),
+    };
+
+    let comparison = compare(&left, &right, args.tolerance);
+    write_changes(tokio::io::stdout(), &comparison).await?;
+    eprintln!("{comparison}");
+
+    match comparison.changes.len() {
+        0 => Ok(()),
+        changes => Err(RunError::Differences { changes }.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::{Change, Field, compare, write_changes};
+    use crate::{csv::parse_accounts, model::ClientAccount};
+
+    async fn accounts(input: &str) -> Vec<ClientAccount> {
+        parse_accounts(input.as_bytes()).await.unwrap()
+    }
+
+    #[rstest]
+    #[case::identical("2,5,0,5,true\n1,10,0,10,false\n", 0., 0, "largest difference 0")]
+    #[case::within_tolerance(
+        "1,10.5,0,10.5,false\n2,5,0,5,true\n",
+        0.5,
+        0,
+        "largest difference 0.5"
+    )]
+    #[case::beyond_tolerance(
+        "1,10.5,0,10.5,false\n2,5,0,5,true\n",
+        0.25,
+        1,
+        "largest difference 0.5"
+    )]
+    #[tokio::test]
+    async fn test_compare_tolerance(
+        #[case] right: &str,
+        #[case] tolerance: f32,
+        #[case] changed: usize,
+        #[case] largest: &str,
+    ) {
+        let left =
+            accounts("client,available,held,total,locked\n1,10,0,10,false\n2,5,0,5,true\n").await;
+
+        let comparison = compare(&left, &accounts(right).await, tolerance);
+
+        assert_eq!(comparison.changes.len(), changed);
+        assert_eq!(
+            comparison.to_string(),
+            format!(
+                "Compared 2 clients: {} identical, {changed} changed, 0 only in left, 0 only in right, {largest}",
+                2 - changed
+            )
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_compare_changes() {
+        let left = accounts("1,10,0,10,false\n2,5,0,5,false\n3,1,0,1,false\n").await;
+        let right = accounts("1,10,0,10,false\n2,0,5,5,true\n4,1,0,1,false\n").await;
+
+        let comparison = compare(&left, &right, 0.);
+
+        assert_eq!(comparison.identical, 1);
+        assert!(matches!(
+            &comparison.changes[&(2, None)],
+            Change::Changed { fields, .. } if *fields == [Field::Available, Field::Held, Field::Locked]
+        ));
+        assert!(matches!(
+            comparison.changes[&(3, None)],
+            Change::OnlyLeft(_)
+        ));
+        assert!(matches!(
+            comparison.changes[&(4, None)],
+            Change::OnlyRight(_)
+        ));
+
+        let mut out = Vec::new();
+        write_changes(&mut out, &comparison).await.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {"
+                client,field,left,right,difference
+                2,available,5,0,-5
+                2,held,0,5,5
+                2,locked,false,true,
+                3,account,present,missing,
+                4,account,missing,present,
+            "}
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_changes_precision() {
+        let left = accounts("1,1000000,0,1000000,false\n").await;
+        let right = accounts("1,0.0001,0,1000000,false\n").await;
+
+        let comparison = compare(&left, &right, 0.);
+
+        let mut out = Vec::new();
+        write_changes(&mut out, &comparison).await.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {"
+                client,field,left,right,difference
+                1,available,1000000,0.0001,-999999.9999
+            "}
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_compare_currencies() {
+        let left = accounts(
+            "1,10,0,10,false
+1,5,0,5,false,EUR
+",
+        )
+        .await;
+        let right = accounts(
+            "1,10,0,10,false
+1,4,0,4,false,EUR
+1,2,0,2,false,USD
+",
+        )
+        .await;
+
+        let comparison = compare(&left, &right, 0.);
+
+        assert_eq!(comparison.identical, 1);
+        let mut out = Vec::new();
+        write_changes(&mut out, &comparison).await.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {"
+                client,field,left,right,difference,currency
+                1,available,5,4,-1,EUR
+                1,total,5,4,-1,EUR
+                1,account,missing,present,,USD
+            "}
+        );
+    }
+}
@@ -1,8 +1,13 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use csv_async::ByteRecord;
+use serde::Serialize;
 
-use crate::model::{Amount, ClientId, TxId, TxStatus, TxType};
+use crate::{
+    fx::{Date, Pair},
+    model::{Amount, ClientId, Currency, TxId, TxStatus, TxType},
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -12,6 +17,77 @@ pub enum Error {
         #[source]
         source: std::io::Error,
     },
+    #[error("Couldn't write to file: {filename:?}")]
+    WriteError {
+        filename: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Couldn't listen on address: {address}")]
+    BindError {
+        address: SocketAddr,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Couldn't listen on socket: {path:?}")]
+    SocketBindError {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Couldn't listen for Ctrl-C")]
+    SignalError {
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Invalid policy config {filename:?}: {source}")]
+    ConfigError {
+        filename: PathBuf,
+        #[source]
+        source: PolicyError,
+    },
+    #[error("Invalid FX rates {filename:?}: {source}")]
+    RatesError {
+        filename: PathBuf,
+        #[source]
+        source: RatesError,
+    },
+}
+
+/// Errors in a policy config, found when it is loaded.
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error(transparent)]
+    Parse(#[from] toml::de::Error),
+    #[error("`disputes.types` includes {tx_type:?}, only deposits and withdrawals can be disputed")]
+    NotDisputable { tx_type: TxType },
+    #[error("`{key}` includes {tx_type:?} more than once")]
+    Duplicate { key: &'static str, tx_type: TxType },
+    #[error("`{key}` must be a positive amount, got {value}")]
+    InvalidLimit { key: String, value: Amount },
+    #[error("`{key}` limits withdrawals over a window, but doesn't set its `window`")]
+    MissingWindow { key: String },
+    #[error(transparent)]
+    Tiers(#[from] ParsingError),
+    #[error("Client Id '{client}' is assigned the unknown tier `{tier}`")]
+    UnknownTier { client: ClientId, tier: String },
+    #[error("Client Id '{client}' is assigned more than one tier")]
+    DuplicateClient { client: ClientId },
+}
+
+/// Errors in an FX rates file, found when it is loaded or when balances are converted with it.
+#[derive(Debug, thiserror::Error)]
+pub enum RatesError {
+    #[error(transparent)]
+    Parse(#[from] ParsingError),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Rate of {pair} on {date} must be a positive number, got {rate}")]
+    InvalidRate { pair: Pair, date: Date, rate: f64 },
+    #[error("{pair} has more than one rate on {date}")]
+    DuplicateRate { pair: Pair, date: Date },
+    #[error("No {from}/{to} or {to}/{from} rate is effective on the conversion date")]
+    MissingRate { from: Currency, to: Currency },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -24,6 +100,8 @@ pub enum ParsingError {
     },
     #[error("Couldn't find data in the CSV: {record:?}")]
     NoRecords { record: ByteRecord },
+    #[error("Unexpected header in CSV, expected `type,client,tx,amount`: {record:?}")]
+    Header { record: ByteRecord },
     #[error("Couldn't deserialize row in CSV: {record:?}")]
     Deserialize {
         record: ByteRecord,
@@ -32,9 +110,14 @@ pub enum ParsingError {
     },
 }
 
-#[derive(Debug, thiserror::Error)]
+/// Errors rejecting a single transaction. Every variant has a stable code, returned by
+/// [`TransactionError::code`] and serialized as the `code` field alongside the variant's fields.
+/// Codes are never changed or reused, so that rejections can be aggregated across versions.
+#[derive(Debug, thiserror::Error, Serialize)]
+#[serde(tag = "code")]
 pub enum TransactionError {
     #[error("Transaction must be positive:  Transaction Id '{id}', {tx_type:?} amount {amount}")]
+    #[serde(rename = "E_INVALID_AMOUNT")]
     MustBePositive {
         tx_type: TxType,
         id: TxId,
@@ -43,6 +126,7 @@ pub enum TransactionError {
     #[error(
         "Balance insufficient: available '{available}', {tx_type:?} amount '{amount}', Transaction Id '{id}'"
     )]
+    #[serde(rename = "E_INSUFFICIENT_FUNDS")]
     BalanceInsufficient {
         available: Amount,
         tx_type: TxType,
@@ -50,26 +134,272 @@ pub enum TransactionError {
         amount: Amount,
     },
     #[error("Locked Account: Client Id '{id}")]
+    #[serde(rename = "E_ACCOUNT_LOCKED")]
     AccountLocked { id: ClientId },
     #[error("Transaction not found or is invalid for type {tx_type:?}: Transaction Id '{id}'")]
+    #[serde(rename = "E_TX_NOT_FOUND")]
     NotFound { tx_type: TxType, id: TxId },
     #[error("Account not found processing transaction: Client Id '{id}'")]
+    #[serde(rename = "E_ACCOUNT_NOT_FOUND")]
     AccountNotFound { id: ClientId },
     #[error("Client ID mismatch processing transaction: expected '{expected}', got '{actual}'")]
+    #[serde(rename = "E_CLIENT_MISMATCH")]
     ClientIdMismatch {
         expected: ClientId,
         actual: ClientId,
     },
+    #[error(
+        "Currency mismatch processing transaction: expected '{}', got '{}'",
+        .expected.as_ref().map_or("none", Currency::as_str),
+        .actual.as_ref().map_or("none", Currency::as_str)
+    )]
+    #[serde(rename = "E_CURRENCY_MISMATCH")]
+    CurrencyMismatch {
+        expected: Option<Currency>,
+        actual: Option<Currency>,
+    },
+    #[error(
+        "Transfer needs a destination other than its own Client: destination '{}', Transaction Id '{id}'",
+        .destination.map_or_else(|| "none".to_owned(), |d| d.to_string())
+    )]
+    #[serde(rename = "E_INVALID_DESTINATION")]
+    InvalidDestination {
+        id: TxId,
+        destination: Option<ClientId>,
+    },
     #[error("Duplicate transaction: Transaction id '{id}'")]
+    #[serde(rename = "E_DUPLICATE_TX")]
     DuplicateTransaction { id: TxId },
     #[error("Missing amount for transaction type {tx_type:?}: Transaction id '{id}'")]
+    #[serde(rename = "E_MISSING_AMOUNT")]
     MissingAmount { tx_type: TxType, id: TxId },
     #[error(
         "Transaction on incorrect state '{state:?}' for transaction type {tx_type:?}: Transaction id '{id}'"
     )]
+    #[serde(rename = "E_INVALID_TX_STATE")]
     IncorrectState {
         tx_type: TxType,
         state: TxStatus,
         id: TxId,
     },
+    #[error("Withdrawal limit exceeded: amount '{amount}', limit '{limit}', Transaction Id '{id}'")]
+    #[serde(rename = "E_WITHDRAWAL_LIMIT")]
+    WithdrawalLimit {
+        id: TxId,
+        amount: Amount,
+        limit: Amount,
+    },
+    #[error(
+        "Withdrawal total limit exceeded over the last {window} transactions: total '{total}', limit '{limit}', Transaction Id '{id}'"
+    )]
+    #[serde(rename = "E_VELOCITY_TOTAL")]
+    VelocityTotal {
+        id: TxId,
+        total: f64,
+        limit: Amount,
+        window: u32,
+    },
+    #[error(
+        "Withdrawal count limit exceeded over the last {window} transactions: count '{count}', limit '{limit}', Transaction Id '{id}'"
+    )]
+    #[serde(rename = "E_VELOCITY_COUNT")]
+    VelocityCount {
+        id: TxId,
+        count: u32,
+        limit: u32,
+        window: u32,
+    },
+    #[error(
+        "Overdraft limit exceeded: available '{available}', {tx_type:?} amount '{amount}', overdraft '{overdraft}', Transaction Id '{id}'"
+    )]
+    #[serde(rename = "E_OVERDRAFT_LIMIT")]
+    OverdraftLimit {
+        available: Amount,
+        tx_type: TxType,
+        id: TxId,
+        amount: Amount,
+        overdraft: Amount,
+    },
+    #[error("Rejected by rule `{rule}`: {reason}, Transaction Id '{id}'")]
+    #[serde(rename = "E_RULE_REJECTED")]
+    RuleRejected {
+        id: TxId,
+        rule: &'static str,
+        reason: String,
+    },
+}
+
+impl TransactionError {
+    /// The stable, machine-readable code of the error, e.g. `E_INSUFFICIENT_FUNDS`.
+    pub fn code(&self) -> &'static str {
+        // The same codes as the `serde(rename)`s, which the payload tests check for every variant
+        match self {
+            Self::MustBePositive { .. } => "E_INVALID_AMOUNT",
+            Self::BalanceInsufficient { .. } => "E_INSUFFICIENT_FUNDS",
+            Self::AccountLocked { .. } => "E_ACCOUNT_LOCKED",
+            Self::NotFound { .. } => "E_TX_NOT_FOUND",
+            Self::AccountNotFound { .. } => "E_ACCOUNT_NOT_FOUND",
+            Self::ClientIdMismatch { .. } => "E_CLIENT_MISMATCH",
+            Self::CurrencyMismatch { .. } => "E_CURRENCY_MISMATCH",
+            Self::InvalidDestination { .. } => "E_INVALID_DESTINATION",
+            Self::DuplicateTransaction { .. } => "E_DUPLICATE_TX",
+            Self::MissingAmount { .. } => "E_MISSING_AMOUNT",
+            Self::IncorrectState { .. } => "E_INVALID_TX_STATE",
+            Self::WithdrawalLimit { .. } => "E_WITHDRAWAL_LIMIT",
+            Self::VelocityTotal { .. } => "E_VELOCITY_TOTAL",
+            Self::VelocityCount { .. } => "E_VELOCITY_COUNT",
+            Self::OverdraftLimit { .. } => "E_OVERDRAFT_LIMIT",
+            Self::RuleRejected { .. } => "E_RULE_REJECTED",
+        }
+    }
+}
+
+/// Inconsistencies in the State that no sequence of transactions should cause, found by
+/// [`State::check_invariants`](crate::model::State::check_invariants).
+#[derive(Debug, thiserror::Error)]
+pub enum InvariantError {
+    #[error("Balance isn't a finite number: Client Id '{id}'")]
+    NonFinite { id: ClientId },
+    #[error(
+        "Total doesn't match available and held funds: Client Id '{id}', available '{available}', held '{held}', total '{total}'"
+    )]
+    Unbalanced {
+        id: ClientId,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+    },
+    #[error("Held funds are negative: Client Id '{id}', held '{held}'")]
+    NegativeHeld { id: ClientId, held: Amount },
+    #[error("Stored transaction has no account: Transaction Id '{tx_id}', Client Id '{id}'")]
+    Orphaned { tx_id: TxId, id: ClientId },
+}
+
+/// Errors failing a file run once its transactions were processed.
+#[derive(Debug, thiserror::Error)]
+pub enum RunError {
+    #[error("Rejected transaction on line {line}: {source}")]
+    Rejected {
+        line: u64,
+        #[source]
+        source: TransactionError,
+    },
+    #[error("Invariant violated: {0}")]
+    Invariant(#[from] InvariantError),
+    #[error("File is invalid: {schema} schema problems, {rejected} rejected transactions")]
+    Invalid { schema: usize, rejected: usize },
+    #[error("Balances differ: {changes} clients changed")]
+    Differences { changes: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use serde_json::json;
+
+    use super::TransactionError;
+    use crate::model::{TxStatus, TxType};
+
+    #[rstest]
+    #[case::must_be_positive(
+        TransactionError::MustBePositive { tx_type: TxType::Deposit, id: 1, amount: -1. },
+        json!({"code": "E_INVALID_AMOUNT", "tx_type": "deposit", "id": 1, "amount": -1.0})
+    )]
+    #[case::balance_insufficient(
+        TransactionError::BalanceInsufficient {
+            available: 1.5,
+            tx_type: TxType::Withdrawal,
+            id: 2,
+            amount: 2.,
+        },
+        json!({
+            "code": "E_INSUFFICIENT_FUNDS",
+            "available": 1.5,
+            "tx_type": "withdrawal",
+            "id": 2,
+            "amount": 2.0,
+        })
+    )]
+    #[case::account_locked(
+        TransactionError::AccountLocked { id: 3 },
+        json!({"code": "E_ACCOUNT_LOCKED", "id": 3})
+    )]
+    #[case::not_found(
+        TransactionError::NotFound { tx_type: TxType::Dispute, id: 4 },
+        json!({"code": "E_TX_NOT_FOUND", "tx_type": "dispute", "id": 4})
+    )]
+    #[case::account_not_found(
+        TransactionError::AccountNotFound { id: 5 },
+        json!({"code": "E_ACCOUNT_NOT_FOUND", "id": 5})
+    )]
+    #[case::client_id_mismatch(
+        TransactionError::ClientIdMismatch { expected: 1, actual: 2 },
+        json!({"code": "E_CLIENT_MISMATCH", "expected": 1, "actual": 2})
+    )]
+    #[case::duplicate_transaction(
+        TransactionError::DuplicateTransaction { id: 6 },
+        json!({"code": "E_DUPLICATE_TX", "id": 6})
+    )]
+    #[case::missing_amount(
+        TransactionError::MissingAmount { tx_type: TxType::Deposit, id: 7 },
+        json!({"code": "E_MISSING_AMOUNT", "tx_type": "deposit", "id": 7})
+    )]
+    #[case::incorrect_state(
+        TransactionError::IncorrectState {
+            tx_type: TxType::Resolve,
+            state: TxStatus::Valid,
+            id: 8,
+        },
+        json!({"code": "E_INVALID_TX_STATE", "tx_type": "resolve", "state": "valid", "id": 8})
+    )]
+    #[case::withdrawal_limit(
+        TransactionError::WithdrawalLimit { id: 9, amount: 600., limit: 500. },
+        json!({"code": "E_WITHDRAWAL_LIMIT", "id": 9, "amount": 600.0, "limit": 500.0})
+    )]
+    #[case::velocity_total(
+        TransactionError::VelocityTotal { id: 10, total: 1200., limit: 1000., window: 5 },
+        json!({"code": "E_VELOCITY_TOTAL", "id": 10, "total": 1200.0, "limit": 1000.0, "window": 5})
+    )]
+    #[case::velocity_count(
+        TransactionError::VelocityCount { id: 11, count: 4, limit: 3, window: 5 },
+        json!({"code": "E_VELOCITY_COUNT", "id": 11, "count": 4, "limit": 3, "window": 5})
+    )]
+    #[case::overdraft_limit(
+        TransactionError::OverdraftLimit {
+            available: -20.,
+            tx_type: TxType::Withdrawal,
+            id: 13,
+            amount: 100.,
+            overdraft: 50.,
+        },
+        json!({
+            "code": "E_OVERDRAFT_LIMIT",
+            "available": -20.0,
+            "tx_type": "withdrawal",
+            "id": 13,
+            "amount": 100.0,
+            "overdraft": 50.0
+        })
+    )]
+    #[case::currency_mismatch(
+        TransactionError::CurrencyMismatch { expected: "eur".parse().ok(), actual: None },
+        json!({"code": "E_CURRENCY_MISMATCH", "expected": "EUR", "actual": null})
+    )]
+    #[case::invalid_destination(
+        TransactionError::InvalidDestination { id: 14, destination: Some(1) },
+        json!({"code": "E_INVALID_DESTINATION", "id": 14, "destination": 1})
+    )]
+    #[case::rule_rejected(
+        TransactionError::RuleRejected { id: 12, rule: "disputes", reason: "too many".to_owned() },
+        json!({"code": "E_RULE_REJECTED", "id": 12, "rule": "disputes", "reason": "too many"})
+    )]
+    fn test_transaction_error_payload(
+        #[case] error: TransactionError,
+        #[case] expected: serde_json::Value,
+    ) {
+        let payload = serde_json::to_value(&error).unwrap();
+
+        assert_eq!(payload, expected);
+        assert_eq!(payload["code"], error.code());
+    }
 }
@@ -0,0 +1,335 @@
+//! Replays a transaction file to explain how every row carrying one transaction id was handled:
+//! the stored transaction it references, the account before and after, and the check that
+//! rejected it.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use futures_util::{Stream, StreamExt};
+
+use crate::{
+    csv::{format_account, format_transaction, parse_csv_lines},
+    error::{Error, ParsingError, TransactionError},
+    model::{ClientAccount, State, Transaction, TxId, TxStatus, TxType},
+    policy::Policy,
+};
+
+/// Explain how every row with a transaction id was handled, replaying the file
+#[derive(Debug, clap::Args)]
+pub struct ExplainArgs {
+    /// Transaction id to explain
+    #[arg(long)]
+    pub tx: TxId,
+    /// CSV file to replay
+    pub filename: PathBuf,
+}
+
+/// How a single row carrying the explained transaction id was handled.
+#[derive(Debug)]
+pub struct Step {
+    pub line: u64,
+    pub tx: Transaction,
+    /// The deposit or withdrawal stored with the id before the row, along with its status.
+    pub referenced: Option<(Transaction, TxStatus)>,
+    /// Status of the stored deposit or withdrawal after the row.
+    pub status: Option<TxStatus>,
+    /// Account of the Client owning the stored transaction, or of the row's Client without one, in
+    /// the transaction's currency.
+    pub before: Option<ClientAccount>,
+    pub after: Option<ClientAccount>,
+    pub result: Result<(), TransactionError>,
+}
+
+impl Step {
+    /// Names the check in the handler that rejected the row.
+    pub fn failed_check(&self) -> Option<&'static str> {
+        let error = self.result.as_ref().err()?;
+        Some(match error {
+            TransactionError::MustBePositive { .. } => "check_positive",
+            TransactionError::AccountLocked { .. } => "check_locked",
+            TransactionError::ClientIdMismatch { .. } => "check_client_id_mismatch",
+            TransactionError::CurrencyMismatch { .. } => "check_currency_mismatch",
+            TransactionError::DuplicateTransaction { .. } => "check_duplicate",
+            TransactionError::MissingAmount { .. } => "amount required",
+            TransactionError::NotFound { .. } => "referenced deposit lookup",
+            TransactionError::AccountNotFound { .. } => "account lookup",
+            TransactionError::InvalidDestination { .. } => "destination lookup",
+            TransactionError::IncorrectState { .. } => "transaction status",
+            TransactionError::WithdrawalLimit { .. } => "check_limits (single withdrawal)",
+            TransactionError::VelocityTotal { .. } => "check_limits (window total)",
+            TransactionError::VelocityCount { .. } => "check_limits (window count)",
+            TransactionError::RuleRejected { rule, .. } => rule,
+            TransactionError::OverdraftLimit { .. } => match self.tx.tx_type {
+                TxType::Chargeback => "available funds beyond the overdraft",
+                _ => "check_funds (overdraft)",
+            },
+            TransactionError::BalanceInsufficient { .. } => match self.tx.tx_type {
+                TxType::Withdrawal | TxType::Dispute | TxType::Transfer => {
+                    "check_sufficient_balance (available funds)"
+                }
+                // Disputes that left the account in arrears fail the chargeback before its held
+                // funds are checked
+                TxType::Chargeback if self.before.as_ref().is_some_and(|a| a.available < 0.) => {
+                    "available funds in arrears"
+                }
+                _ => "check_sufficient_balance (held funds)",
+            },
+        })
+    }
+}
+
+/// Every row carrying a transaction id, in input order.
+#[derive(Debug)]
+pub struct Explanation {
+    pub tx_id: TxId,
+    pub steps: Vec<Step>,
+}
+
+fn stored(state: &State, tx_id: TxId) -> Option<(Transaction, TxStatus)> {
+    state.transactions.get(&tx_id).map(|tx| {
+        (
+            Transaction {
+                tx_type: tx.tx_type(),
+                client_id: tx.client_id(),
+                tx_id: tx.tx_id(),
+                amount: tx.amount(),
+                currency: tx.currency(),
+                destination: tx.destination(),
+            },
+            tx.status(),
+        )
+    })
+}
+
+/// Replays transactions into a fresh State under `policy`, recording a [`Step`] for every row with
+/// `tx_id`. Processing stops on a CSV parsing error, as a run would.
+pub async fn explain(
+    stream: impl Stream<Item = Result<(u64, Transaction), ParsingError>>,
+    tx_id: TxId,
+    policy: &Policy,
+) -> Result<Explanation, ParsingError> {
+    let mut state = State::new(policy.clone());
+    let mut steps = Vec::new();
+
+    futures_util::pin_mut!(stream);
+    while let Some(row) = stream.next().await {
+        let (line, tx) = row?;
+        if tx.tx_id != tx_id {
+            let _ = state.apply(tx);
+            continue;
+        }
+
+        let referenced = stored(&state, tx_id);
+        let account_id = referenced
+            .as_ref()
+            .map_or((tx.client_id, tx.currency), |(stored, _)| {
+                (stored.client_id, stored.currency)
+            });
+        let before = state.accounts.get(&account_id).cloned();
+        let result = state.apply(tx.clone());
+        steps.push(Step {
+            line,
+            tx,
+            referenced,
+            status: stored(&state, tx_id).map(|(_, status)| status),
+            before,
+            after: state.accounts.get(&account_id).cloned(),
+            result,
+        });
+    }
+
+    Ok(Explanation { tx_id, steps })
+}
+
+fn fmt_status(status: Option<TxStatus>) -> String {
+    status.map_or_else(|| "none".to_owned(), |s| s.as_ref().to_lowercase())
+}
+
+fn fmt_account(account: &Option<ClientAccount>) -> String {
+    account
+        .as_ref()
+        .map_or_else(|| "none".to_owned(), format_account)
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.steps.is_empty() {
+            return write!(f, "Transaction {} doesn't appear in the file", self.tx_id);
+        }
+
+        for step in &self.steps {
+            writeln!(f, "Line {}: {}", step.line, format_transaction(&step.tx))?;
+            match &step.referenced {
+                Some((tx, status)) => writeln!(
+                    f,
+                    "  Referenced:     {} ({})",
+                    format_transaction(tx),
+                    fmt_status(Some(*status))
+                )?,
+                None => writeln!(f, "  Referenced:     none")?,
+            }
+            writeln!(
+                f,
+                "  Status:         {} -> {}",
+                fmt_status(step.referenced.as_ref().map(|(_, status)| *status)),
+                fmt_status(step.status)
+            )?;
+            writeln!(f, "  Account before: {}", fmt_account(&step.before))?;
+            writeln!(f, "  Account after:  {}", fmt_account(&step.after))?;
+            match (&step.result, step.failed_check()) {
+                (Err(e), Some(check)) => writeln!(f, "  Rejected by {check}: {} {e}", e.code())?,
+                _ => writeln!(f, "  Accepted")?,
+            }
+            writeln!(f)?;
+        }
+
+        // Statuses the stored transaction went through, starting from the row that stored it
+        let mut history = Vec::new();
+        for step in &self.steps {
+            let changed = history.last().map(|(_, status)| *status) != step.status;
+            if let (true, Some(status)) = (changed, step.status) {
+                history.push((step.line, status));
+            }
+        }
+        write!(f, "Status history: ")?;
+        if history.is_empty() {
+            write!(f, "never stored")
+        } else {
+            let history = history
+                .iter()
+                .map(|(line, status)| format!("{} (line {line})", fmt_status(Some(*status))))
+                .collect::<Vec<_>>();
+            write!(f, "{}", history.join(" -> "))
+        }
+    }
+}
+
+/// Replays a file and prints the explanation of a transaction id to `stdout`.
+pub async fn run(args: &ExplainArgs, policy: &Policy) -> Result<(), Box<dyn std::error::Error>> {
+    let fp = tokio::fs::File::open(&args.filename)
+        .await
+        .map_err(|e| Error::IOError {
+            filename: args.filename.clone(),
+            source: e,
+        })?;
+
+    let explanation = explain(parse_csv_lines(fp).await, args.tx, policy).await?;
+    println!("{explanation}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::explain;
+    use crate::{csv::parse_csv_lines, model::TxStatus, policy::Policy};
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_explain() {
+        let input = indoc::indoc! {b"
+            type,client,tx,amount
+            deposit,1,1,100.0
+            deposit,1,2,20.0
+            dispute,1,2,
+            withdrawal,1,3,90.0
+            dispute,1,1,
+            chargeback,1,2,
+            chargeback,1,1,
+        "};
+
+        let explanation = explain(
+            parse_csv_lines(input.as_slice()).await,
+            2,
+            &Policy::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            explanation
+                .steps
+                .iter()
+                .map(|step| (step.line, step.status, step.failed_check()))
+                .collect::<Vec<_>>(),
+            vec![
+                (3, Some(TxStatus::Valid), None),
+                (4, Some(TxStatus::Disputed), None),
+                // The withdrawal and the dispute of tx 1 left the account in arrears
+                (
+                    7,
+                    Some(TxStatus::Chargeback),
+                    Some("available funds in arrears")
+                ),
+            ]
+        );
+        assert_eq!(
+            explanation.to_string(),
+            indoc::indoc! {"
+                Line 3: deposit,1,2,20
+                  Referenced:     none
+                  Status:         none -> valid
+                  Account before: 1,100,0,100,false
+                  Account after:  1,120,0,120,false
+                  Accepted
+
+                Line 4: dispute,1,2,
+                  Referenced:     deposit,1,2,20 (valid)
+                  Status:         valid -> disputed
+                  Account before: 1,120,0,120,false
+                  Account after:  1,100,20,120,false
+                  Accepted
+
+                Line 7: chargeback,1,2,
+                  Referenced:     deposit,1,2,20 (disputed)
+                  Status:         disputed -> chargeback
+                  Account before: 1,-90,120,30,false
+                  Account after:  1,-90,120,30,false
+                  Rejected by available funds in arrears: E_INSUFFICIENT_FUNDS Balance insufficient: available '-70', Chargeback amount '20', Transaction Id '2'
+
+                Status history: valid (line 3) -> disputed (line 4) -> chargeback (line 7)"}
+        );
+    }
+
+    #[rstest]
+    #[case::withdrawal(b"type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2,10\n".as_slice(), 2, Some("check_sufficient_balance (available funds)"))]
+    #[case::transfer(b"type,client,tx,amount,currency,destination\ndeposit,1,1,5\ndeposit,2,2,5\ntransfer,1,3,10,,2\n".as_slice(), 3, Some("check_sufficient_balance (available funds)"))]
+    #[case::duplicate(b"type,client,tx,amount\ndeposit,1,1,5\ndeposit,2,1,5\n".as_slice(), 1, Some("check_duplicate"))]
+    #[case::mismatch(b"type,client,tx,amount\ndeposit,1,1,5\ndispute,2,1,\n".as_slice(), 1, Some("check_client_id_mismatch"))]
+    #[case::resolve(b"type,client,tx,amount\ndeposit,1,1,5\nresolve,1,1,\n".as_slice(), 1, Some("transaction status"))]
+    #[case::unknown(b"type,client,tx,amount\ndispute,1,1,\n".as_slice(), 1, Some("referenced deposit lookup"))]
+    #[tokio::test]
+    async fn test_failed_check(
+        #[case] input: &[u8],
+        #[case] tx_id: u32,
+        #[case] expected: Option<&str>,
+    ) {
+        let explanation = explain(parse_csv_lines(input).await, tx_id, &Policy::default())
+            .await
+            .unwrap();
+
+        assert_eq!(explanation.steps.last().unwrap().failed_check(), expected);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_explain_missing() {
+        let input = b"type,client,tx,amount\ndeposit,1,1,5\n";
+
+        let explanation = explain(
+            parse_csv_lines(input.as_slice()).await,
+            7,
+            &Policy::default(),
+        )
+        .await
+        .unwrap();
+
+        assert!(explanation.steps.is_empty());
+        assert_eq!(
+            explanation.to_string(),
+            "Transaction 7 doesn't appear in the file"
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! Conversion of Client balances to a reporting currency with an offline table of FX rates, for
+//! consolidated reports alongside the native balances.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use csv_async::AsyncWriterBuilder;
+use tokio::io::AsyncWrite;
+
+use crate::{
+    csv::{fmt_decimals, parse_rates},
+    error::{Error, RatesError},
+    model::{ClientAccount, ClientId, Currency},
+};
+
+/// Decimal places converted balances are rounded to, as balances are printed.
+const DECIMALS: i32 = 4;
+
+/// Convert balances to a reporting currency, reported per Client
+#[derive(Debug, Default, clap::Args)]
+pub struct FxArgs {
+    /// File to write every Client's balances converted to the reporting currency to as CSV
+    #[arg(long, requires_all = ["rates", "report_currency"])]
+    pub fx_report: Option<PathBuf>,
+    /// CSV file of `date,pair,rate` FX rates, e.g. `2024-01-31,EUR/USD,1.0842`
+    #[arg(long, requires = "fx_report")]
+    pub rates: Option<PathBuf>,
+    /// Currency to convert balances to. Accounts without a currency are taken to be in it
+    #[arg(long, requires = "fx_report")]
+    pub report_currency: Option<Currency>,
+    /// Date to convert at, as `YYYY-MM-DD`, using the latest rate effective on or before it.
+    /// Defaults to the latest rate of every pair
+    #[arg(long, requires = "fx_report")]
+    pub as_of: Option<Date>,
+    /// Rounding of converted balances to 4 decimal places
+    #[arg(long, value_enum, default_value_t)]
+    pub rounding: Rounding,
+}
+
+/// Calendar date a rate becomes effective on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    year: u16,
+    month: u8,
+    day: u8,
+}
+
+impl FromStr for Date {
+    type Err = String;
+
+    fn from_str(date: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid date '{date}', expected `YYYY-MM-DD`");
+        let mut parts = date.split('-');
+        let (Some(year), Some(month), Some(day), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+            return Err(invalid());
+        }
+        let (Ok(year), Ok(month), Ok(day)) = (year.parse(), month.parse(), day.parse()) else {
+            return Err(invalid());
+        };
+
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let days = match month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            1..=12 => 31,
+            _ => return Err(invalid()),
+        };
+        if !(1..=days).contains(&day) {
+            return Err(invalid());
+        }
+        Ok(Self { year, month, day })
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Currency pair of a rate, `BASE/QUOTE`: one unit of the base currency is worth `rate` units of
+/// the quote currency.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pair {
+    pub base: Currency,
+    pub quote: Currency,
+}
+
+impl FromStr for Pair {
+    type Err = String;
+
+    fn from_str(pair: &str) -> Result<Self, Self::Err> {
+        let (base, quote) = pair
+            .split_once('/')
+            .ok_or_else(|| format!("Invalid currency pair '{pair}', expected `BASE/QUOTE`"))?;
+        Ok(Self {
+            base: base.trim().parse()?,
+            quote: quote.trim().parse()?,
+        })
+    }
+}
+
+impl fmt::Display for Pair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.base, self.quote)
+    }
+}
+
+/// How converted balances are rounded to 4 decimal places.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Rounding {
+    // Ties to the even digit, so that rounding errors don't accumulate over many accounts
+    #[default]
+    HalfEven,
+    // Ties away from zero
+    HalfUp,
+    // Towards zero, never overstating a balance
+    Down,
+}
+
+impl Rounding {
+    pub fn round(self, value: f64) -> f64 {
+        let scale = 10f64.powi(DECIMALS);
+        // Drops representation errors well below the rounded digit, so that ties are rounded as
+        // written
+        let scaled = (value * scale * 1e6).round() / 1e6;
+        let rounded = match self {
+            Rounding::HalfEven => scaled.round_ties_even(),
+            Rounding::HalfUp => scaled.round(),
+            Rounding::Down => scaled.trunc(),
+        };
+        rounded / scale
+    }
+}
+
+/// Offline table of FX rates by pair and effective date.
+#[derive(Debug, Default)]
+pub struct Rates(BTreeMap<Pair, BTreeMap<Date, f64>>);
+
+impl Rates {
+    /// Loads rates from a CSV file of `date,pair,rate` rows, with or without a header row.
+    pub async fn load(path: &Path) -> Result<Self, Error> {
+        let fp = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| Error::IOError {
+                filename: path.to_path_buf(),
+                source: e,
+            })?;
+        let rates = async { Rates::from_rows(parse_rates(fp).await?) };
+        rates.await.map_err(|e| Error::RatesError {
+            filename: path.to_path_buf(),
+            source: e,
+        })
+    }
+
+    /// Builds the table from `date,pair,rate` rows, which must be positive and unique by pair and
+    /// date.
+    pub fn from_rows(
+        rows: impl IntoIterator<Item = (String, String, f64)>,
+    ) -> Result<Self, RatesError> {
+        let mut rates = Rates::default();
+        for (date, pair, rate) in rows {
+            let date = date.parse::<Date>().map_err(RatesError::Invalid)?;
+            let pair = pair.parse::<Pair>().map_err(RatesError::Invalid)?;
+            if !rate.is_finite() || rate <= 0. {
+                return Err(RatesError::InvalidRate { pair, date, rate });
+            }
+            if rates
+                .0
+                .entry(pair)
+                .or_default()
+                .insert(date, rate)
+                .is_some()
+            {
+                return Err(RatesError::DuplicateRate { pair, date });
+            }
+        }
+        Ok(rates)
+    }
+
+    /// Rate converting `from` to `to`, effective on `as_of` or the latest one without it. A pair
+    /// quoted the other way round is inverted, and rates aren't chained across pairs.
+    pub fn rate(&self, from: Currency, to: Currency, as_of: Option<Date>) -> Option<f64> {
+        let effective = |pair: Pair| {
+            let rates = self.0.get(&pair)?;
+            match as_of {
+                Some(date) => rates.range(..=date).next_back(),
+                None => rates.last_key_value(),
+            }
+            .map(|(_, rate)| *rate)
+        };
+
+        if from == to {
+            return Some(1.);
+        }
+        effective(Pair {
+            base: from,
+            quote: to,
+        })
+        .or_else(|| {
+            effective(Pair {
+                base: to,
+                quote: from,
+            })
+            .map(|rate| 1. / rate)
+        })
+    }
+}
+
+/// A Client account's total, converted to the reporting currency.
+#[derive(Debug, PartialEq)]
+pub struct Conversion {
+    pub currency: Currency,
+    /// Total in the account's currency, as printed.
+    pub total: f64,
+    pub rate: f64,
+    pub converted: f64,
+}
+
+/// A Client's accounts converted to the reporting currency, and their consolidated total.
+#[derive(Debug, PartialEq)]
+pub struct Consolidation {
+    pub client_id: ClientId,
+    pub accounts: Vec<Conversion>,
+    /// Sum of the rounded conversions, so that it adds up in the report.
+    pub total: f64,
+}
+
+/// Converts the total of every account to `to` at the rates effective `as_of`, rounding each
+/// conversion, and consolidates them per Client, ordered by Client Id and then currency. Accounts
+/// without a currency are taken to be in `to`. Fails if a currency has no rate to `to`.
+pub fn consolidate<'a>(
+    accounts: impl IntoIterator<Item = &'a ClientAccount>,
+    rates: &Rates,
+    to: Currency,
+    as_of: Option<Date>,
+    rounding: Rounding,
+) -> Result<Vec<Consolidation>, RatesError> {
+    let mut clients = BTreeMap::<ClientId, Vec<Conversion>>::new();
+    for account in accounts {
+        let currency = account.currency.unwrap_or(to);
+        let rate = rates
+            .rate(currency, to, as_of)
+            .ok_or(RatesError::MissingRate { from: currency, to })?;
+        // Converted from the printed total, rather than its binary representation
+        let total = fmt_decimals(account.total)
+            .parse::<f64>()
+            .unwrap_or(f64::from(account.total));
+        clients
+            .entry(account.client_id)
+            .or_default()
+            .push(Conversion {
+                currency,
+                total,
+                rate,
+                converted: rounding.round(total * rate),
+            });
+    }
+
+    Ok(clients
+        .into_iter()
+        .map(|(client_id, mut accounts)| {
+            accounts.sort_by_key(|conversion| conversion.currency);
+            let total = rounding.round(accounts.iter().map(|c| c.converted).sum());
+            Consolidation {
+                client_id,
+                accounts,
+                total,
+            }
+        })
+        .collect())
+}
+
+/// Writes the consolidations as CSV rows with a header, `client,currency,total,rate,converted`:
+/// one row per account, then a row in the reporting currency `to` with the Client's
+/// consolidated total in `converted`, without a native total or rate.
+pub async fn write_csv(
+    write: impl AsyncWrite + Unpin,
+    consolidations: &[Consolidation],
+    to: Currency,
+) -> Result<(), csv_async::Error> {
+    let mut wtr = AsyncWriterBuilder::new().create_writer(write);
+    wtr.write_record(["client", "currency", "total", "rate", "converted"])
+        .await?;
+    for consolidation in consolidations {
+        let client_id = consolidation.client_id.to_string();
+        for conversion in &consolidation.accounts {
+            wtr.write_record([
+                client_id.as_str(),
+                conversion.currency.as_str(),
+                &fmt_decimals(conversion.total),
+                &conversion.rate.to_string(),
+                &fmt_decimals(conversion.converted),
+            ])
+            .await?;
+        }
+        wtr.write_record([
+            client_id.as_str(),
+            to.as_str(),
+            "",
+            "",
+            &fmt_decimals(consolidation.total),
+        ])
+        .await?;
+    }
+    wtr.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::{Date, Rates, Rounding, consolidate, write_csv};
+    use crate::{csv::parse_accounts, error::RatesError};
+
+    fn rates(rows: &[(&str, &str, f64)]) -> Result<Rates, RatesError> {
+        Rates::from_rows(
+            rows.iter()
+                .map(|(date, pair, rate)| (date.to_string(), pair.to_string(), *rate)),
+        )
+    }
+
+    #[rstest]
+    #[case::valid("2024-02-29", true)]
+    #[case::not_leap("2023-02-29", false)]
+    #[case::month("2024-13-01", false)]
+    #[case::short("2024-1-01", false)]
+    #[case::time("2024-01-01T00:00", false)]
+    fn test_parse_date(#[case] date: &str, #[case] valid: bool) {
+        assert_eq!(date.parse::<Date>().is_ok(), valid, "{date}");
+    }
+
+    #[rstest]
+    #[case::latest(None, Some(1.1))]
+    #[case::as_of(Some("2024-01-15"), Some(1.05))]
+    #[case::before_first(Some("2023-12-31"), None)]
+    fn test_rate(#[case] as_of: Option<&str>, #[case] expected: Option<f64>) {
+        let rates = rates(&[
+            ("2024-01-01", "EUR/USD", 1.05),
+            ("2024-02-01", "EUR/USD", 1.1),
+        ])
+        .unwrap();
+        let as_of = as_of.map(|date| date.parse().unwrap());
+
+        let eur = "EUR".parse().unwrap();
+        let usd = "USD".parse().unwrap();
+        assert_eq!(rates.rate(eur, usd, as_of), expected);
+        assert_eq!(rates.rate(usd, eur, as_of), expected.map(|rate| 1. / rate));
+        assert_eq!(rates.rate(usd, usd, as_of), Some(1.));
+    }
+
+    #[rstest]
+    #[case::date(("2024-01-32", "EUR/USD", 1.), "Invalid date '2024-01-32'")]
+    #[case::pair(("2024-01-01", "EURUSD", 1.), "Invalid currency pair 'EURUSD'")]
+    #[case::rate(("2024-01-01", "EUR/USD", 0.), "must be a positive number")]
+    #[case::duplicate(("2024-01-01", "EUR/GBP", 1.), "more than one rate")]
+    fn test_rates_invalid(#[case] row: (&str, &str, f64), #[case] expected: &str) {
+        let err = rates(&[("2024-01-01", "EUR/GBP", 0.85), row]).unwrap_err();
+
+        assert!(err.to_string().contains(expected), "{err}");
+    }
+
+    #[rstest]
+    #[case::half_even(Rounding::HalfEven, 1.0002)]
+    #[case::half_up(Rounding::HalfUp, 1.0003)]
+    #[case::down(Rounding::Down, 1.0002)]
+    fn test_round(#[case] rounding: Rounding, #[case] expected: f64) {
+        assert_eq!(rounding.round(1.00025), expected);
+        assert_eq!(rounding.round(-1.00025), -expected);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_consolidate() {
+        let accounts = parse_accounts(
+            b"2,10,0,10,false,GBP\n1,100,0,100,false,EUR\n1,50.5,0,50.5,false\n2,1,0,1,true\n"
+                .as_slice(),
+        )
+        .await
+        .unwrap();
+        let rates = rates(&[
+            ("2024-01-01", "EUR/USD", 1.0842),
+            ("2024-01-01", "USD/GBP", 0.7861),
+        ])
+        .unwrap();
+        let usd = "USD".parse().unwrap();
+
+        let consolidations = consolidate(&accounts, &rates, usd, None, Rounding::HalfEven).unwrap();
+        let mut out = Vec::new();
+        write_csv(&mut out, &consolidations, usd).await.unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {"
+                client,currency,total,rate,converted
+                1,EUR,100,1.0842,108.42
+                1,USD,50.5,1,50.5
+                1,USD,,,158.92
+                2,GBP,10,1.272102785905101,12.721
+                2,USD,1,1,1
+                2,USD,,,13.721
+            "}
+        );
+
+        let jpy = "JPY".parse().unwrap();
+        assert!(matches!(
+            consolidate(&accounts, &rates, jpy, None, Rounding::HalfEven),
+            Err(RatesError::MissingRate { .. })
+        ));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_consolidate_beyond_f32_precision(
+        #[values(Rounding::HalfEven, Rounding::Down)] rounding: Rounding,
+    ) {
+        let accounts = parse_accounts(b"1,100,0,100,false,EUR\n1,2.5,0,2.5,false,USD\n".as_slice())
+            .await
+            .unwrap();
+        let rates = rates(&[
+            ("2024-01-01", "EUR/JPY", 98765.4321),
+            ("2024-01-01", "USD/JPY", 740740.734),
+        ])
+        .unwrap();
+        let jpy = "JPY".parse().unwrap();
+
+        let consolidations = consolidate(&accounts, &rates, jpy, None, rounding).unwrap();
+        let mut out = Vec::new();
+        write_csv(&mut out, &consolidations, jpy).await.unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {"
+                client,currency,total,rate,converted
+                1,EUR,100,98765.4321,9876543.21
+                1,USD,2.5,740740.734,1851851.835
+                1,JPY,,,11728395.045
+            "}
+        );
+    }
+}
@@ -0,0 +1,609 @@
+//! Synthetic transaction file generator. Produces reproducible transaction streams for load
+//! testing, alongside the final balances the engine is expected to produce, so a generated file
+//! doubles as a test oracle.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    csv::{CURRENCY, DESTINATION, HEADER, format_account},
+    error::Error,
+    model::{Amount, ClientAccount, ClientId, TxId, TxType},
+};
+
+/// Generate a synthetic transaction file
+#[derive(Debug, clap::Args)]
+pub struct GenerateArgs {
+    /// Number of distinct clients
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u16).range(1..))]
+    pub clients: ClientId,
+    /// Number of rows to generate
+    #[arg(long, default_value_t = 10_000)]
+    pub rows: usize,
+    /// Relative weights of each transaction type
+    #[arg(
+        long,
+        default_value = "deposit=60,withdrawal=30,dispute=6,resolve=3,chargeback=1"
+    )]
+    pub mix: Mix,
+    /// Fraction of rows re-using the id of an earlier deposit or withdrawal
+    #[arg(long, default_value_t = 0.01, value_parser = parse_rate)]
+    pub duplicate_rate: f64,
+    /// Fraction of rows the engine must reject: missing or negative amounts, references to unknown
+    /// transactions or withdrawals, and client mismatches
+    #[arg(long, default_value_t = 0.01, value_parser = parse_rate)]
+    pub malformed_rate: f64,
+    /// Seed for the random number generator
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+    /// File to write the transactions to, defaults to stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// File to write the expected balances to
+    #[arg(long)]
+    pub expected: Option<PathBuf>,
+}
+
+/// Relative weights of each transaction type, parsed from e.g. `deposit=60,withdrawal=30`. Types
+/// that are left out are never generated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mix(Vec<(TxType, u32)>);
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(|pair| {
+                let (tx_type, weight) = pair
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected `type=weight`, got '{pair}'"))?;
+                let tx_type = TxType::from_str(tx_type.trim())
+                    .map_err(|_| format!("Unknown transaction type '{tx_type}'"))?;
+                let weight = weight
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid weight '{weight}' for {tx_type:?}"))?;
+                Ok((tx_type, weight))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        if weights.iter().all(|(_, weight)| *weight == 0) {
+            Err(String::from("At least one transaction type needs a weight"))
+        } else {
+            Ok(Self(weights))
+        }
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("Expected a rate between 0 and 1, got '{s}'")),
+    }
+}
+
+/// Writes the generated transactions and expected balances to the files given in the arguments.
+pub fn run(args: &GenerateArgs) -> Result<(), Error> {
+    let write_error = |filename: &Path| {
+        let filename = filename.to_path_buf();
+        move |source| Error::WriteError { filename, source }
+    };
+
+    let expected = match &args.output {
+        Some(path) => {
+            let file = File::create(path).map_err(write_error(path))?;
+            let mut writer = BufWriter::new(file);
+            let expected = generate(args, &mut writer).map_err(write_error(path))?;
+            writer.flush().map_err(write_error(path))?;
+            expected
+        }
+        None => {
+            let mut writer = BufWriter::new(std::io::stdout().lock());
+            generate(args, &mut writer).map_err(write_error(Path::new("<stdout>")))?
+        }
+    };
+
+    if let Some(path) = &args.expected {
+        let file = File::create(path).map_err(write_error(path))?;
+        let mut writer = BufWriter::new(file);
+        for account in &expected {
+            writeln!(writer, "{}", format_account(account)).map_err(write_error(path))?;
+        }
+        writer.flush().map_err(write_error(path))?;
+    }
+
+    Ok(())
+}
+
+/// Writes a CSV of generated transactions and returns the balances, ordered by Client Id, that
+/// processing the CSV should produce.
+pub fn generate(args: &GenerateArgs, out: &mut impl Write) -> std::io::Result<Vec<ClientAccount>> {
+    let mut generator = Generator::new(args);
+
+    // Only files with transfers need the optional columns, which other rows leave out
+    let Mix(weights) = &args.mix;
+    if weights
+        .iter()
+        .any(|(tx_type, weight)| *tx_type == TxType::Transfer && *weight > 0)
+    {
+        writeln!(out, "{},{CURRENCY},{DESTINATION}", HEADER.join(","))?;
+    } else {
+        writeln!(out, "{}", HEADER.join(","))?;
+    }
+    for _ in 0..args.rows {
+        let row = generator.row();
+        match (row.amount, row.destination) {
+            (Some(amount), Some(destination)) => writeln!(
+                out,
+                "{},{},{},{},,{}",
+                row.tx_type.as_ref().to_lowercase(),
+                row.client_id,
+                row.tx_id,
+                amount,
+                destination
+            )?,
+            (Some(amount), None) => writeln!(
+                out,
+                "{},{},{},{}",
+                row.tx_type.as_ref().to_lowercase(),
+                row.client_id,
+                row.tx_id,
+                amount
+            )?,
+            (None, _) => writeln!(
+                out,
+                "{},{},{},",
+                row.tx_type.as_ref().to_lowercase(),
+                row.client_id,
+                row.tx_id
+            )?,
+        }
+    }
+
+    let mut expected = generator.accounts.into_values().collect::<Vec<_>>();
+    expected.sort_by_key(|account| account.client_id);
+
+    Ok(expected)
+}
+
+struct Row {
+    tx_type: TxType,
+    client_id: ClientId,
+    tx_id: TxId,
+    amount: Option<Amount>,
+    destination: Option<ClientId>,
+}
+
+/// Tracks a ledger of the generated transactions, mirroring the engine's rules, to pick realistic
+/// targets for disputes, resolves and chargebacks and to compute the expected balances.
+struct Generator<'a> {
+    args: &'a GenerateArgs,
+    rng: ChaCha8Rng,
+    accounts: HashMap<ClientId, ClientAccount>,
+    deposits: HashMap<TxId, (ClientId, Amount)>,
+    valid: Vec<TxId>,
+    disputed: Vec<TxId>,
+    withdrawals: Vec<TxId>,
+    stored: Vec<TxId>,
+    next_id: TxId,
+}
+
+impl<'a> Generator<'a> {
+    fn new(args: &'a GenerateArgs) -> Self {
+        Self {
+            args,
+            rng: ChaCha8Rng::seed_from_u64(args.seed),
+            accounts: HashMap::new(),
+            deposits: HashMap::new(),
+            valid: Vec::new(),
+            disputed: Vec::new(),
+            withdrawals: Vec::new(),
+            stored: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    fn row(&mut self) -> Row {
+        let roll = self.rng.random::<f64>();
+        if roll < self.args.duplicate_rate && !self.stored.is_empty() {
+            self.duplicate()
+        } else if roll < self.args.duplicate_rate + self.args.malformed_rate {
+            self.malformed()
+        } else {
+            match self.tx_type() {
+                TxType::Deposit => self.deposit(),
+                TxType::Withdrawal => self.withdrawal(),
+                TxType::Transfer => self.transfer(),
+                TxType::Dispute if !self.valid.is_empty() => self.dispute(),
+                TxType::Resolve if !self.disputed.is_empty() => self.resolve(),
+                TxType::Chargeback if !self.disputed.is_empty() => self.chargeback(),
+                // Nothing to reference yet
+                _ => self.deposit(),
+            }
+        }
+    }
+
+    fn tx_type(&mut self) -> TxType {
+        let Mix(weights) = &self.args.mix;
+        let total = weights.iter().map(|(_, weight)| weight).sum::<u32>();
+        let mut roll = self.rng.random_range(0..total);
+        for (tx_type, weight) in weights {
+            if roll < *weight {
+                return *tx_type;
+            }
+            roll -= weight;
+        }
+        unreachable!("Roll is below the sum of weights")
+    }
+
+    fn fresh_id(&mut self) -> TxId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn client_id(&mut self) -> ClientId {
+        self.rng.random_range(1..=self.args.clients)
+    }
+
+    /// An amount with up to four decimal places, between 0.0001 and `max`.
+    fn amount(&mut self, max: Amount) -> Amount {
+        let max = (max.max(1.) * 10_000.) as u32;
+        self.rng.random_range(1..=max) as Amount / 10_000.
+    }
+
+    fn deposit(&mut self) -> Row {
+        let client_id = self.client_id();
+        let tx_id = self.fresh_id();
+        let amount = self.amount(1_000.);
+
+        let account = self
+            .accounts
+            .entry(client_id)
+            .or_insert_with(|| ClientAccount {
+                client_id,
+                ..Default::default()
+            });
+        if !account.locked {
+            account.available += amount;
+            account.total += amount;
+            self.deposits.insert(tx_id, (client_id, amount));
+            self.valid.push(tx_id);
+            self.stored.push(tx_id);
+        }
+
+        Row {
+            tx_type: TxType::Deposit,
+            client_id,
+            tx_id,
+            amount: Some(amount),
+            destination: None,
+        }
+    }
+
+    fn withdrawal(&mut self) -> Row {
+        let client_id = self.client_id();
+        let tx_id = self.fresh_id();
+        // Occasionally overdraw the account
+        let available = self.accounts.get(&client_id).map_or(0., |a| a.available);
+        let amount = self.amount(available * 1.25);
+
+        if let Some(account) = self.accounts.get_mut(&client_id)
+            && !account.locked
+            && account.available >= amount
+        {
+            account.available -= amount;
+            account.total -= amount;
+            self.withdrawals.push(tx_id);
+            self.stored.push(tx_id);
+        }
+
+        Row {
+            tx_type: TxType::Withdrawal,
+            client_id,
+            tx_id,
+            amount: Some(amount),
+            destination: None,
+        }
+    }
+
+    /// Moves funds to another Client's account in the default currency, which the engine rejects
+    /// unless both accounts exist, neither is locked and the source has the funds.
+    fn transfer(&mut self) -> Row {
+        let client_id = self.client_id();
+        let destination = self.client_id();
+        let tx_id = self.fresh_id();
+        // Occasionally overdraw the account
+        let available = self.accounts.get(&client_id).map_or(0., |a| a.available);
+        let amount = self.amount(available * 1.25);
+
+        let accepted = destination != client_id
+            && self
+                .accounts
+                .get(&client_id)
+                .is_some_and(|account| !account.locked && account.available >= amount)
+            && self
+                .accounts
+                .get(&destination)
+                .is_some_and(|account| !account.locked);
+        if accepted {
+            for (id, amount) in [(client_id, -amount), (destination, amount)] {
+                let account = self.accounts.get_mut(&id).expect("Transfer account");
+                account.available += amount;
+                account.total += amount;
+            }
+            self.stored.push(tx_id);
+        }
+
+        Row {
+            tx_type: TxType::Transfer,
+            client_id,
+            tx_id,
+            amount: Some(amount),
+            destination: Some(destination),
+        }
+    }
+
+    fn dispute(&mut self) -> Row {
+        let index = self.rng.random_range(0..self.valid.len());
+        let tx_id = self.valid[index];
+        let (client_id, amount) = self.deposits[&tx_id];
+
+        let account = self.accounts.get_mut(&client_id).expect("Deposit account");
+        if !account.locked {
+            account.available -= amount;
+            account.held += amount;
+            self.valid.swap_remove(index);
+            self.disputed.push(tx_id);
+        }
+
+        Row {
+            tx_type: TxType::Dispute,
+            client_id,
+            tx_id,
+            amount: None,
+            destination: None,
+        }
+    }
+
+    fn resolve(&mut self) -> Row {
+        let index = self.rng.random_range(0..self.disputed.len());
+        let tx_id = self.disputed[index];
+        let (client_id, amount) = self.deposits[&tx_id];
+
+        let account = self.accounts.get_mut(&client_id).expect("Deposit account");
+        if !account.locked {
+            // The engine returns the deposit to a valid state before checking the held funds
+            self.disputed.swap_remove(index);
+            self.valid.push(tx_id);
+            if account.held >= amount {
+                account.held -= amount;
+                account.available += amount;
+            }
+        }
+
+        Row {
+            tx_type: TxType::Resolve,
+            client_id,
+            tx_id,
+            amount: None,
+            destination: None,
+        }
+    }
+
+    fn chargeback(&mut self) -> Row {
+        let index = self.rng.random_range(0..self.disputed.len());
+        let tx_id = self.disputed[index];
+        let (client_id, amount) = self.deposits[&tx_id];
+
+        let account = self.accounts.get_mut(&client_id).expect("Deposit account");
+        if !account.locked {
+            // The engine marks the deposit as charged back before checking the balances
+            self.disputed.swap_remove(index);
+            if account.available >= 0. && account.held >= amount {
+                account.held -= amount;
+                account.total -= amount;
+                account.locked = true;
+            }
+        }
+
+        Row {
+            tx_type: TxType::Chargeback,
+            client_id,
+            tx_id,
+            amount: None,
+            destination: None,
+        }
+    }
+
+    /// Re-uses the id of a stored transaction, which the engine rejects before any other check.
+    fn duplicate(&mut self) -> Row {
+        let tx_id = self.stored[self.rng.random_range(0..self.stored.len())];
+        let client_id = self.client_id();
+        let amount = self.amount(1_000.);
+        let tx_type = if self.rng.random_bool(0.5) {
+            TxType::Deposit
+        } else {
+            TxType::Withdrawal
+        };
+
+        Row {
+            tx_type,
+            client_id,
+            tx_id,
+            amount: Some(amount),
+            destination: None,
+        }
+    }
+
+    /// A row that parses, but that the engine rejects without changing any balances.
+    fn malformed(&mut self) -> Row {
+        let client_id = self.client_id();
+        match self.rng.random_range(0..5) {
+            0 => Row {
+                tx_type: TxType::Deposit,
+                client_id,
+                tx_id: self.fresh_id(),
+                amount: None,
+                destination: None,
+            },
+            1 => Row {
+                tx_type: TxType::Withdrawal,
+                client_id,
+                tx_id: self.fresh_id(),
+                amount: Some(-self.amount(1_000.)),
+                destination: None,
+            },
+            2 if !self.withdrawals.is_empty() => Row {
+                tx_type: TxType::Dispute,
+                client_id,
+                tx_id: self.withdrawals[self.rng.random_range(0..self.withdrawals.len())],
+                amount: None,
+                destination: None,
+            },
+            3 if !self.valid.is_empty() && self.args.clients > 1 => {
+                let tx_id = self.valid[self.rng.random_range(0..self.valid.len())];
+                let (owner, _) = self.deposits[&tx_id];
+                Row {
+                    tx_type: TxType::Dispute,
+                    client_id: owner % self.args.clients + 1,
+                    tx_id,
+                    amount: None,
+                    destination: None,
+                }
+            }
+            _ => {
+                let tx_type = [TxType::Dispute, TxType::Resolve, TxType::Chargeback]
+                    [self.rng.random_range(0..3)];
+                Row {
+                    tx_type,
+                    client_id,
+                    tx_id: self.fresh_id(),
+                    amount: None,
+                    destination: None,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{csv::parse_csv, model::State};
+
+    fn args(
+        seed: u64,
+        clients: ClientId,
+        mix: &str,
+        duplicate_rate: f64,
+        malformed_rate: f64,
+    ) -> GenerateArgs {
+        GenerateArgs {
+            clients,
+            rows: 5_000,
+            mix: mix.parse().unwrap(),
+            duplicate_rate,
+            malformed_rate,
+            seed,
+            output: None,
+            expected: None,
+        }
+    }
+
+    #[rstest]
+    #[case::defaults(args(
+        0,
+        100,
+        "deposit=60,withdrawal=30,dispute=6,resolve=3,chargeback=1",
+        0.01,
+        0.01
+    ))]
+    #[case::single_client(args(
+        1,
+        1,
+        "deposit=60,withdrawal=30,dispute=6,resolve=3,chargeback=1",
+        0.05,
+        0.05
+    ))]
+    #[case::dispute_heavy(args(
+        2,
+        10,
+        "deposit=30,withdrawal=20,dispute=30,resolve=10,chargeback=10",
+        0.0,
+        0.0
+    ))]
+    #[case::rejection_heavy(args(3, 20, "deposit=1,withdrawal=1", 0.3, 0.3))]
+    #[case::transfers(args(
+        4,
+        10,
+        "deposit=30,withdrawal=10,transfer=30,dispute=15,resolve=10,chargeback=5",
+        0.05,
+        0.05
+    ))]
+    #[tokio::test]
+    async fn test_generate_matches_engine(#[case] args: GenerateArgs) {
+        let mut out = Vec::new();
+        let expected = generate(&args, &mut out).unwrap();
+
+        let mut state = State::default();
+        let txs = parse_csv(out.as_slice())
+            .await
+            .try_collect::<Vec<_>>()
+            .await
+            .expect("Failed to parse");
+        assert_eq!(txs.len(), args.rows);
+        for tx in txs {
+            let _ = state.apply(tx);
+        }
+
+        let mut actual = state
+            .accounts
+            .values()
+            .map(format_account)
+            .collect::<Vec<_>>();
+        actual.sort();
+        let mut expected = expected.iter().map(format_account).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(actual, expected);
+    }
+
+    #[rstest]
+    fn test_generate_reproducible() {
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+
+        generate(
+            &args(42, 10, "deposit=1,withdrawal=1,dispute=1", 0.1, 0.1),
+            &mut first,
+        )
+        .unwrap();
+        generate(
+            &args(42, 10, "deposit=1,withdrawal=1,dispute=1", 0.1, 0.1),
+            &mut second,
+        )
+        .unwrap();
+
+        assert_eq!(first, second);
+    }
+
+    #[rstest]
+    #[case::unknown_type("deposit=1,payment=1")]
+    #[case::missing_weight("deposit")]
+    #[case::zero_weights("deposit=0,withdrawal=0")]
+    fn test_mix_invalid(#[case] input: &str) {
+        assert!(input.parse::<Mix>().is_err());
+    }
+}
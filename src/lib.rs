@@ -0,0 +1,17 @@
+//! Transaction processing engine, parsing transactions from CSV and applying them to Client
+//! accounts.
+
+pub mod csv;
+pub mod diff;
+pub mod error;
+pub mod explain;
+pub mod fx;
+pub mod generate;
+pub mod model;
+pub mod policy;
+pub mod reconcile;
+pub mod rules;
+pub mod server;
+pub mod shard;
+pub mod summary;
+pub mod validate;
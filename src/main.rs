@@ -1,36 +1,171 @@
 //! Entrypoint binary to the transaction application.
 
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
-use clap::{Parser, command};
+use clap::{Parser, Subcommand, ValueEnum};
+use tracing_subscriber::EnvFilter;
 
 #[derive(Debug, Parser)]
-#[command(version, about, long_about=None)]
+#[command(version, about, long_about=None, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
+    /// Filter for the trace logs written to stderr: a level such as `debug`, or directives such
+    /// as `warn,txn_assignment::model=trace` or `[handle{client=3}]=trace`
+    #[arg(long, global = true, default_value = "warn", value_parser = parse_filter)]
+    log_level: String,
+    /// Format of the trace logs written to stderr
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Number of worker tasks processing transactions in parallel, sharded by Client Id. Defaults
+    /// to the number of available CPUs
+    #[arg(short = 'j', long)]
+    shards: Option<NonZeroUsize>,
+    /// Stop processing and fail on the first rejected transaction, without printing balances
+    #[arg(long)]
+    strict: bool,
+    /// TOML file of the business policies the transaction handlers apply. Defaults to the
+    /// built-in policies
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    reports: Reports,
     /// CSV file to parse
-    filename: PathBuf,
+    #[arg(required = true)]
+    filename: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-use std::path::Path;
+/// Reports on a file run, besides the account states.
+#[derive(Debug, Default, clap::Args)]
+pub struct Reports {
+    /// File to write rejected transactions to as CSV, with their error and source line
+    #[arg(long)]
+    rejects: Option<PathBuf>,
+    /// Print a summary of the run to stderr
+    #[arg(long)]
+    summary: bool,
+    /// File to write a summary of the run to as JSON
+    #[arg(long)]
+    summary_json: Option<PathBuf>,
+    /// File to write the transactions flagged by the risk rules to as CSV, for review
+    #[arg(long)]
+    review: Option<PathBuf>,
+    #[command(flatten)]
+    fx: FxArgs,
+}
 
-use futures_util::StreamExt;
+/// Exit status when a file, socket or signal couldn't be read, written or listened on.
+const EXIT_IO: i32 = 1;
+/// Exit status when the CSV can't be parsed.
+const EXIT_PARSE: i32 = 3;
+/// Exit status when a transaction is rejected in `--strict` mode.
+const EXIT_REJECTED: i32 = 4;
+/// Exit status when the State is inconsistent once processed, which is a bug in the engine.
+const EXIT_INVARIANT: i32 = 5;
+/// Exit status when compared balances differ.
+const EXIT_DIFFERENT: i32 = 6;
+/// Exit status when the policy config is invalid.
+const EXIT_CONFIG: i32 = 7;
+
+/// Maps an error ending the application to its documented exit status. Status 2 is left to clap,
+/// which exits with it on invalid arguments.
+fn exit_code(error: &(dyn std::error::Error + 'static)) -> i32 {
+    if error.is::<ParsingError>() {
+        EXIT_PARSE
+    } else if let Some(error) = error.downcast_ref::<RunError>() {
+        match error {
+            RunError::Rejected { .. } => EXIT_REJECTED,
+            RunError::Invariant(_) => EXIT_INVARIANT,
+            RunError::Invalid { schema: 0, .. } => EXIT_REJECTED,
+            RunError::Invalid { .. } => EXIT_PARSE,
+            RunError::Differences { .. } => EXIT_DIFFERENT,
+        }
+    } else if let Some(Error::ConfigError { .. } | Error::RatesError { .. }) =
+        error.downcast_ref::<Error>()
+    {
+        EXIT_CONFIG
+    } else {
+        EXIT_IO
+    }
+}
 
-use crate::error::Error;
-use crate::model::{
-    State, TransactionHandler, TxType, chargeback::Chargeback, deposit::Deposit, dispute::Dispute,
-    resolve::Resolve, withdrawal::Withdrawal,
-};
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
 
-mod csv;
-mod error;
-mod model;
+fn parse_filter(filter: &str) -> Result<String, String> {
+    EnvFilter::try_new(filter)
+        .map(|_| filter.to_owned())
+        .map_err(|e| e.to_string())
+}
+
+/// Installs the subscriber writing trace logs to stderr.
+fn init_tracing(filter: &str, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(filter))
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.with_ansi(std::io::stderr().is_terminal()).init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    Generate(GenerateArgs),
+    Serve(ServeArgs),
+    Validate(ValidateArgs),
+    Explain(ExplainArgs),
+    Diff(DiffArgs),
+    Reconcile(ReconcileArgs),
+}
+
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::{BufWriter, IsTerminal, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use futures_util::{StreamExt, future};
+use tracing::Instrument;
+use txn_assignment::csv;
+use txn_assignment::diff::{self, DiffArgs};
+use txn_assignment::error::{Error, ParsingError, RunError};
+use txn_assignment::explain::{self, ExplainArgs};
+use txn_assignment::fx::{self, FxArgs, Rates};
+use txn_assignment::generate::{self, GenerateArgs};
+use txn_assignment::policy::Policy;
+use txn_assignment::reconcile::{self, ReconcileArgs};
+use txn_assignment::server::{self, ServeArgs};
+use txn_assignment::shard;
+use txn_assignment::summary::Summary;
+use txn_assignment::validate::{self, ValidateArgs};
 
 /// Runs the application, reading the CSV file and parsing transactions. CSV parsing errors and
 /// File I/O errors are bubbled up, whereas Transaction errors are optionally logged and skipped to
-/// process the entire file.
-pub async fn run(file: impl AsRef<Path>, verbose: bool) -> Result<(), Box<dyn std::error::Error>> {
+/// process the entire file. Rejected transactions are written to the `rejects` file, ordered by
+/// source line, even if a CSV parsing error stops processing, whereas a summary, the `review`
+/// queue of flagged transactions and the `fx_report` of converted balances are only written once
+/// the whole file is processed.
+///
+/// Transactions are applied under `policy`. When `strict`, the first rejected transaction stops
+/// processing and fails the run instead. Transactions already routed to other shards still
+/// complete, so the earliest rejection by source line is reported, and every rejection is written
+/// to the `rejects` file. Balances are only printed once the State passes its invariant checks.
+pub async fn run(
+    file: impl AsRef<Path>,
+    verbose: bool,
+    shards: NonZeroUsize,
+    strict: bool,
+    policy: &Policy,
+    reports: &Reports,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
     let fp = tokio::fs::File::open(&file)
         .await
         .map_err(|e| Error::IOError {
@@ -38,77 +173,215 @@ pub async fn run(file: impl AsRef<Path>, verbose: bool) -> Result<(), Box<dyn st
             source: e,
         })?;
 
-    let stream = csv::parse_csv(fp).await;
-
-    let mut state = State::default();
-    futures_util::pin_mut!(stream);
-    while let Some(transaction) = stream.next().await {
-        let tx = transaction?;
-        let res = match tx.tx_type {
-            TxType::Deposit => Deposit::new(tx).handle(&mut state),
-            TxType::Withdrawal => Withdrawal::new(tx).handle(&mut state),
-            TxType::Resolve => Resolve::new(tx).handle(&mut state),
-            TxType::Chargeback => Chargeback::new(tx).handle(&mut state),
-            TxType::Dispute => Dispute::new(tx).handle(&mut state),
-        };
+    // Created upfront so that an unwritable path fails before processing the whole file
+    let rejects = match &reports.rejects {
+        Some(path) => Some((path, create_report(path).await?)),
+        None => None,
+    };
+    let review = match &reports.review {
+        Some(path) => Some((path, create_report(path).await?)),
+        None => None,
+    };
+    let fx_report = match (&reports.fx.fx_report, &reports.fx.rates) {
+        (Some(path), Some(rates)) => {
+            Some((path, Rates::load(rates).await?, create_report(path).await?))
+        }
+        _ => None,
+    };
 
-        match res {
-            Ok(_) => {}
-            // We skip transaction errors and continue processing
-            Err(e) => {
-                if verbose {
-                    eprintln!("{e}")
-                }
+    // Rows are counted as they are parsed, and rejections as they are reported, both while
+    // processing
+    let summary = RefCell::new(Summary::default());
+    let failed = Cell::new(false);
+    let stream = csv::parse_csv_lines(fp)
+        .await
+        .take_while(|_| future::ready(!failed.get()))
+        .inspect(|row| {
+            if let Ok((_, tx)) = row {
+                summary.borrow_mut().row(tx);
             }
+        });
+
+    let mut rejections = Vec::new();
+    let res = shard::process(stream, shards, policy, |rejection| {
+        // We skip transaction errors and continue processing
+        if verbose {
+            eprintln!("{}", rejection.error)
+        }
+        summary
+            .borrow_mut()
+            .rejected(&rejection.tx, &rejection.error);
+        if strict {
+            failed.set(true);
         }
+        if rejects.is_some() || strict {
+            rejections.push(rejection);
+        }
+    })
+    .await;
+
+    if let Some((path, fp)) = rejects {
+        rejections.sort_by_key(|rejection| rejection.line);
+        csv::write_rejections(fp, &rejections)
+            .await
+            .map_err(|e| Error::WriteError {
+                filename: path.to_path_buf(),
+                source: e.into(),
+            })?;
     }
+    let mut state = res?;
 
-    for balance in state.accounts.into_values() {
-        println!(
-            "{},{},{},{},{}",
-            balance.client_id,
-            fmt_decimals(balance.available),
-            fmt_decimals(balance.held),
-            fmt_decimals(balance.total),
-            balance.locked
-        );
+    if strict && let Some(first) = rejections.into_iter().min_by_key(|r| r.line) {
+        return Err(RunError::Rejected {
+            line: first.line,
+            source: first.error,
+        }
+        .into());
+    }
+    state.check_invariants().map_err(RunError::from)?;
+
+    if let Some((path, fp)) = review {
+        // Shards flag their Clients' transactions in input order, so sorting by Client is stable
+        // across shard counts
+        let mut flagged = std::mem::take(&mut state.flagged);
+        flagged.sort_by_key(|flag| flag.tx.client_id);
+        csv::write_flags(fp, &flagged)
+            .await
+            .map_err(|e| Error::WriteError {
+                filename: path.to_path_buf(),
+                source: e.into(),
+            })?;
+    }
+
+    if let (Some((path, rates, fp)), Some(currency)) = (fx_report, reports.fx.report_currency) {
+        let consolidations = fx::consolidate(
+            state.accounts.values(),
+            &rates,
+            currency,
+            reports.fx.as_of,
+            reports.fx.rounding,
+        )
+        .map_err(|e| Error::RatesError {
+            filename: reports.fx.rates.clone().unwrap_or_default(),
+            source: e,
+        })?;
+        fx::write_csv(fp, &consolidations, currency)
+            .await
+            .map_err(|e| Error::WriteError {
+                filename: path.to_path_buf(),
+                source: e.into(),
+            })?;
+    }
+
+    let mut summary = summary.into_inner();
+    summary.finish(&state, started.elapsed());
+    if reports.summary {
+        eprintln!("{summary}");
+    }
+    if let Some(path) = &reports.summary_json {
+        let write = |path: &Path| -> std::io::Result<()> {
+            let mut writer = BufWriter::new(File::create(path)?);
+            serde_json::to_writer_pretty(&mut writer, &summary)?;
+            writer.write_all(b"\n")?;
+            writer.flush()
+        };
+        write(path).map_err(|e| Error::WriteError {
+            filename: path.clone(),
+            source: e,
+        })?;
+    }
+
+    for balance in state.accounts.values() {
+        println!("{}", csv::format_account(balance));
     }
 
     Ok(())
 }
 
+/// Creates a report file, before processing starts.
+async fn create_report(path: &Path) -> Result<tokio::fs::File, Error> {
+    tokio::fs::File::create(path)
+        .await
+        .map_err(|e| Error::WriteError {
+            filename: path.to_path_buf(),
+            source: e,
+        })
+}
+
+/// Number of shards to process a file across, defaulting to the number of available CPUs.
+fn shards(shards: Option<NonZeroUsize>) -> NonZeroUsize {
+    shards.unwrap_or_else(|| std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN))
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    match run(args.filename, args.verbose).await {
+    init_tracing(&args.log_level, args.log_format);
+
+    let res: Result<(), Box<dyn std::error::Error>> = async {
+        let policy = match &args.config {
+            Some(path) => Policy::load(path).await?,
+            None => Policy::default(),
+        };
+        match (args.command, args.filename) {
+            (Some(Command::Generate(generate_args)), _) => {
+                generate::run(&generate_args).map_err(Into::into)
+            }
+            (Some(Command::Serve(serve_args)), _) => server::run(&serve_args, args.verbose, policy)
+                .await
+                .map_err(Into::into),
+            (Some(Command::Validate(validate_args)), _) => {
+                validate::run(&validate_args, &policy).await
+            }
+            (Some(Command::Explain(explain_args)), _) => explain::run(&explain_args, &policy).await,
+            (Some(Command::Diff(diff_args)), _) => {
+                diff::run(&diff_args, shards(args.shards), &policy).await
+            }
+            (Some(Command::Reconcile(reconcile_args)), _) => {
+                reconcile::run(&reconcile_args, shards(args.shards), &policy).await
+            }
+            (None, Some(filename)) => {
+                let shards = shards(args.shards);
+                let span = tracing::info_span!("run", file = %filename.display(), shards);
+                run(
+                    &filename,
+                    args.verbose,
+                    shards,
+                    args.strict,
+                    &policy,
+                    &args.reports,
+                )
+                .instrument(span)
+                .await
+            }
+            (None, None) => unreachable!("Filename is required without a subcommand"),
+        }
+    }
+    .await;
+    match res {
         Ok(_) => (),
         Err(e) => {
             eprintln!("{}", e);
-            std::process::exit(1)
+            std::process::exit(exit_code(e.as_ref()))
         }
     }
 }
 
-fn fmt_decimals(value: f32) -> String {
-    let formatted = format!("{:.4}", value);
-
-    formatted
-        .trim_end_matches('0')
-        .trim_end_matches('.')
-        .to_string()
-}
-
 #[cfg(test)]
 mod tests {
+    use std::num::NonZeroUsize;
 
     use rstest::rstest;
 
-    use crate::{
-        error::TransactionError,
-        model::{Transaction, TxType},
+    use txn_assignment::{
+        error::{InvariantError, TransactionError},
+        model::{
+            State, Transaction, TransactionHandler, TxType, chargeback::Chargeback,
+            deposit::Deposit, dispute::Dispute, resolve::Resolve, withdrawal::Withdrawal,
+        },
     };
 
-    use super::*;
+    use super::{ParsingError, Policy, Reports, run};
 
     #[rstest]
     #[case::deposit(
@@ -117,12 +390,16 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.),
+            currency: None,
+            destination: None,
         }),
         Deposit::new(Transaction {
             tx_type: TxType::Deposit,
             tx_id: 2,
             client_id: 1,
             amount: Some(50.),
+            currency: None,
+            destination: None,
         })
     )]
     fn test_deposit(#[case] deposit1: Deposit, #[case] deposit2: Deposit) {
@@ -130,10 +407,10 @@ mod tests {
 
         deposit1.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 100.);
-        assert_eq!(state.accounts[&1].held, 0.);
-        assert_eq!(state.accounts[&1].total, 100.);
-        assert!(!state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 100.);
+        assert_eq!(state.accounts[&(1, None)].held, 0.);
+        assert_eq!(state.accounts[&(1, None)].total, 100.);
+        assert!(!state.accounts[&(1, None)].locked);
         assert_eq!(state.transactions[&1].tx_type(), TxType::Deposit);
         assert_eq!(state.transactions[&1].tx_id(), 1);
         assert_eq!(state.transactions[&1].client_id(), 1);
@@ -141,10 +418,10 @@ mod tests {
 
         deposit2.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 150.);
-        assert_eq!(state.accounts[&1].held, 0.);
-        assert_eq!(state.accounts[&1].total, 150.);
-        assert!(!state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 150.);
+        assert_eq!(state.accounts[&(1, None)].held, 0.);
+        assert_eq!(state.accounts[&(1, None)].total, 150.);
+        assert!(!state.accounts[&(1, None)].locked);
     }
 
     #[rstest]
@@ -154,12 +431,16 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Deposit::new(Transaction {
             tx_type: TxType::Deposit,
             tx_id: 2,
             client_id: 2,
             amount: Some(200.0),
+            currency: None,
+            destination: None,
         })
     )]
     fn test_deposit_multi_user(#[case] deposit1: Deposit, #[case] deposit2: Deposit) {
@@ -169,19 +450,19 @@ mod tests {
 
         deposit2.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 100.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 100.0);
-        assert!(!state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 100.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 100.0);
+        assert!(!state.accounts[&(1, None)].locked);
         assert_eq!(state.transactions[&1].tx_type(), TxType::Deposit);
         assert_eq!(state.transactions[&1].tx_id(), 1);
         assert_eq!(state.transactions[&1].client_id(), 1);
         assert_eq!(state.transactions[&1].amount(), Some(100.0));
 
-        assert_eq!(state.accounts[&2].available, 200.0);
-        assert_eq!(state.accounts[&2].held, 0.0);
-        assert_eq!(state.accounts[&2].total, 200.0);
-        assert!(!state.accounts[&2].locked);
+        assert_eq!(state.accounts[&(2, None)].available, 200.0);
+        assert_eq!(state.accounts[&(2, None)].held, 0.0);
+        assert_eq!(state.accounts[&(2, None)].total, 200.0);
+        assert!(!state.accounts[&(2, None)].locked);
         assert_eq!(state.transactions[&2].tx_type(), TxType::Deposit);
         assert_eq!(state.transactions[&2].tx_id(), 2);
         assert_eq!(state.transactions[&2].client_id(), 2);
@@ -195,12 +476,16 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Withdrawal::new(Transaction {
             tx_type: TxType::Withdrawal,
             tx_id: 2,
             client_id: 1,
             amount: Some(50.0),
+            currency: None,
+            destination: None,
         })
     )]
     fn test_withdrawal(#[case] deposit: Deposit, #[case] withdrawal: Withdrawal) {
@@ -210,10 +495,10 @@ mod tests {
 
         withdrawal.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 50.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 50.0);
-        assert!(!state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 50.0);
+        assert!(!state.accounts[&(1, None)].locked);
         assert_eq!(state.transactions[&2].tx_type(), TxType::Withdrawal);
         assert_eq!(state.transactions[&2].tx_id(), 2);
         assert_eq!(state.transactions[&2].client_id(), 1);
@@ -227,12 +512,16 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Withdrawal::new(Transaction {
             tx_type: TxType::Withdrawal,
             tx_id: 2,
             client_id: 1,
             amount: Some(101.0),
+            currency: None,
+            destination: None,
         })
     )]
     fn test_withdrawal_overdraw(#[case] deposit: Deposit, #[case] withdrawal: Withdrawal) {
@@ -247,10 +536,10 @@ mod tests {
             Err(TransactionError::BalanceInsufficient { .. })
         ));
 
-        assert_eq!(state.accounts[&1].available, 100.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 100.0);
-        assert!(!state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 100.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 100.0);
+        assert!(!state.accounts[&(1, None)].locked);
     }
 
     #[rstest]
@@ -260,6 +549,8 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         })
     )]
     fn test_withdrawal_from_nonexistent_account(#[case] withdrawal: Withdrawal) {
@@ -273,7 +564,7 @@ mod tests {
         ));
 
         // Ensure no account was created
-        assert!(!state.accounts.contains_key(&1));
+        assert!(!state.accounts.contains_key(&(1, None)));
     }
 
     #[rstest]
@@ -283,6 +574,8 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         // Duplicate - attempt to process same transaction ID again
         Deposit::new(Transaction {
@@ -290,6 +583,8 @@ mod tests {
             tx_id: 1, // Same tx_id
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         })
     )]
     fn test_duplicate_transaction(#[case] deposit: Deposit, #[case] duplicate_deposit: Deposit) {
@@ -304,10 +599,10 @@ mod tests {
             Err(TransactionError::DuplicateTransaction { id: 1 })
         ));
 
-        assert_eq!(state.accounts[&1].available, 100.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 100.0);
-        assert!(!state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 100.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 100.0);
+        assert!(!state.accounts[&(1, None)].locked);
     }
 
     #[rstest]
@@ -317,6 +612,28 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(-100.0),
+            currency: None,
+            destination: None,
+        })
+    )]
+    #[case::test_nan_amount_deposit(
+        Deposit::new(Transaction {
+            tx_type: TxType::Deposit,
+            tx_id: 1,
+            client_id: 1,
+            amount: Some(f32::NAN),
+            currency: None,
+            destination: None,
+        })
+    )]
+    #[case::test_infinite_amount_deposit(
+        Deposit::new(Transaction {
+            tx_type: TxType::Deposit,
+            tx_id: 1,
+            client_id: 1,
+            amount: Some(f32::INFINITY),
+            currency: None,
+            destination: None,
         })
     )]
     fn test_negative_amount_deposit(#[case] deposit: Deposit) {
@@ -327,7 +644,7 @@ mod tests {
         assert!(matches!(res, Err(TransactionError::MustBePositive { .. })));
 
         // Ensure no account was created
-        assert!(!state.accounts.contains_key(&1));
+        assert!(!state.accounts.contains_key(&(1, None)));
     }
 
     #[rstest]
@@ -337,12 +654,16 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Withdrawal::new(Transaction {
             tx_type: TxType::Withdrawal,
             tx_id: 2,
             client_id: 1,
             amount: Some(-50.0),
+            currency: None,
+            destination: None,
         })
     )]
     fn test_negative_amount_withdrawal(#[case] deposit: Deposit, #[case] withdrawal: Withdrawal) {
@@ -355,9 +676,9 @@ mod tests {
         assert!(matches!(res, Err(TransactionError::MustBePositive { .. })));
 
         // Balance should remain unchanged
-        assert_eq!(state.accounts[&1].available, 100.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 100.0);
+        assert_eq!(state.accounts[&(1, None)].available, 100.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 100.0);
     }
 
     #[rstest]
@@ -367,6 +688,8 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_deposit_missing_amount(#[case] deposit: Deposit) {
@@ -377,7 +700,7 @@ mod tests {
         assert!(matches!(res, Err(TransactionError::MissingAmount { .. })));
 
         // Ensure no account was created
-        assert!(!state.accounts.contains_key(&1));
+        assert!(!state.accounts.contains_key(&(1, None)));
     }
 
     #[rstest]
@@ -387,12 +710,16 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Withdrawal::new(Transaction {
             tx_type: TxType::Withdrawal,
             tx_id: 2,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_withdrawal_missing_amount(#[case] deposit: Deposit, #[case] withdrawal: Withdrawal) {
@@ -405,9 +732,9 @@ mod tests {
         assert!(matches!(res, Err(TransactionError::MissingAmount { .. })));
 
         // Balance should remain unchanged
-        assert_eq!(state.accounts[&1].available, 100.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 100.0);
+        assert_eq!(state.accounts[&(1, None)].available, 100.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 100.0);
     }
 
     #[rstest]
@@ -417,6 +744,8 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_dispute_non_existent_tx(#[case] dispute: Dispute) {
@@ -434,6 +763,8 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Dispute::new(Transaction {
             tx_type: TxType::Dispute,
@@ -441,6 +772,8 @@ mod tests {
             // Client ID does not match.
             client_id: 2,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_dispute_client_mismatch(#[case] deposit: Deposit, #[case] dispute: Dispute) {
@@ -463,18 +796,24 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Deposit::new(Transaction {
             tx_type: TxType::Deposit,
             tx_id: 2,
             client_id: 1,
             amount: Some(50.0),
+            currency: None,
+            destination: None,
         }),
         Dispute::new(Transaction {
             tx_type: TxType::Dispute,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_dispute_transaction(
@@ -490,9 +829,9 @@ mod tests {
 
         dispute.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 50.0);
-        assert_eq!(state.accounts[&1].held, 100.0);
-        assert_eq!(state.accounts[&1].total, 150.0);
+        assert_eq!(state.accounts[&(1, None)].available, 50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 100.0);
+        assert_eq!(state.accounts[&(1, None)].total, 150.0);
     }
 
     #[rstest]
@@ -502,18 +841,24 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Withdrawal::new(Transaction {
             tx_type: TxType::Withdrawal,
             tx_id: 2,
             client_id: 1,
             amount: Some(50.0),
+            currency: None,
+            destination: None,
         }),
         Dispute::new(Transaction {
             tx_type: TxType::Dispute,
             tx_id: 2,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_dispute_withdrawal(
@@ -536,9 +881,9 @@ mod tests {
             res
         );
 
-        assert_eq!(state.accounts[&1].available, 50.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 50.0);
+        assert_eq!(state.accounts[&(1, None)].available, 50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 50.0);
     }
 
     #[rstest]
@@ -548,6 +893,8 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_resolve_non_existent_tx(#[case] resolve: Resolve) {
@@ -565,6 +912,8 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Resolve::new(Transaction {
             tx_type: TxType::Resolve,
@@ -572,6 +921,8 @@ mod tests {
             // Client ID does not match.
             client_id: 2,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_resolve_incorrect_state(#[case] deposit: Deposit, #[case] resolve: Resolve) {
@@ -595,6 +946,8 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_chargeback_non_existent_tx(#[case] chargeback: Chargeback) {
@@ -612,12 +965,16 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Chargeback::new(Transaction {
             tx_type: TxType::Chargeback,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_chargeback_non_disputed_transaction(
@@ -638,10 +995,10 @@ mod tests {
             res
         );
 
-        assert_eq!(state.accounts[&1].available, 100.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 100.0);
-        assert!(!state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 100.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 100.0);
+        assert!(!state.accounts[&(1, None)].locked);
     }
 
     #[rstest]
@@ -651,24 +1008,32 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Deposit::new(Transaction {
             tx_type: TxType::Deposit,
             tx_id: 2,
             client_id: 1,
             amount: Some(50.0),
+            currency: None,
+            destination: None,
         }),
         Dispute::new(Transaction {
             tx_type: TxType::Dispute,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         }),
         Chargeback::new(Transaction {
             tx_type: TxType::Chargeback,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_chargeback_transaction(
@@ -685,16 +1050,16 @@ mod tests {
 
         dispute.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 50.0);
-        assert_eq!(state.accounts[&1].held, 100.0);
-        assert_eq!(state.accounts[&1].total, 150.0);
+        assert_eq!(state.accounts[&(1, None)].available, 50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 100.0);
+        assert_eq!(state.accounts[&(1, None)].total, 150.0);
 
         chargeback.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 50.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 50.0);
-        assert!(state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 50.0);
+        assert!(state.accounts[&(1, None)].locked);
     }
 
     #[rstest]
@@ -704,24 +1069,32 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Withdrawal::new(Transaction {
             tx_type: TxType::Withdrawal,
             tx_id: 2,
             client_id: 1,
             amount: Some(50.0),
+            currency: None,
+            destination: None,
         }),
         Dispute::new(Transaction {
             tx_type: TxType::Dispute,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         }),
         Resolve::new(Transaction {
             tx_type: TxType::Resolve,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_chargeback_transaction_negative_balance_resolve(
@@ -738,15 +1111,15 @@ mod tests {
 
         dispute.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, -50.0);
-        assert_eq!(state.accounts[&1].held, 100.0);
-        assert_eq!(state.accounts[&1].total, 50.0);
+        assert_eq!(state.accounts[&(1, None)].available, -50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 100.0);
+        assert_eq!(state.accounts[&(1, None)].total, 50.0);
 
         resolve.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 50.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 50.0);
+        assert_eq!(state.accounts[&(1, None)].available, 50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 50.0);
     }
 
     #[rstest]
@@ -756,24 +1129,32 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Withdrawal::new(Transaction {
             tx_type: TxType::Withdrawal,
             tx_id: 2,
             client_id: 1,
             amount: Some(50.0),
+            currency: None,
+            destination: None,
         }),
         Dispute::new(Transaction {
             tx_type: TxType::Dispute,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         }),
         Chargeback::new(Transaction {
             tx_type: TxType::Chargeback,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_chargeback_transaction_negative_balance_failed_chargeback(
@@ -790,9 +1171,9 @@ mod tests {
 
         dispute.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, -50.0);
-        assert_eq!(state.accounts[&1].held, 100.0);
-        assert_eq!(state.accounts[&1].total, 50.0);
+        assert_eq!(state.accounts[&(1, None)].available, -50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 100.0);
+        assert_eq!(state.accounts[&(1, None)].total, 50.0);
 
         let res = chargeback.handle(&mut state);
 
@@ -803,9 +1184,9 @@ mod tests {
             res
         );
 
-        assert_eq!(state.accounts[&1].available, -50.0);
-        assert_eq!(state.accounts[&1].held, 100.0);
-        assert_eq!(state.accounts[&1].total, 50.0);
+        assert_eq!(state.accounts[&(1, None)].available, -50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 100.0);
+        assert_eq!(state.accounts[&(1, None)].total, 50.0);
     }
 
     #[rstest]
@@ -815,30 +1196,40 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Withdrawal::new(Transaction {
             tx_type: TxType::Withdrawal,
             tx_id: 2,
             client_id: 1,
             amount: Some(50.0),
+            currency: None,
+            destination: None,
         }),
         Dispute::new(Transaction {
             tx_type: TxType::Dispute,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         }),
         Resolve::new(Transaction {
             tx_type: TxType::Resolve,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         }),
         Chargeback::new(Transaction {
             tx_type: TxType::Chargeback,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         })
     )]
     fn test_chargeback_transaction_negative_balance_chargeback_on_resolved(
@@ -856,15 +1247,15 @@ mod tests {
 
         dispute.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, -50.0);
-        assert_eq!(state.accounts[&1].held, 100.0);
-        assert_eq!(state.accounts[&1].total, 50.0);
+        assert_eq!(state.accounts[&(1, None)].available, -50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 100.0);
+        assert_eq!(state.accounts[&(1, None)].total, 50.0);
 
         resolve.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 50.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 50.0);
+        assert_eq!(state.accounts[&(1, None)].available, 50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 50.0);
 
         let res = chargeback.handle(&mut state);
 
@@ -875,9 +1266,9 @@ mod tests {
             res
         );
 
-        assert_eq!(state.accounts[&1].available, 50.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 50.0);
+        assert_eq!(state.accounts[&(1, None)].available, 50.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 50.0);
     }
 
     #[rstest]
@@ -887,24 +1278,32 @@ mod tests {
             tx_id: 1,
             client_id: 1,
             amount: Some(100.0),
+            currency: None,
+            destination: None,
         }),
         Dispute::new(Transaction {
             tx_type: TxType::Dispute,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         }),
         Chargeback::new(Transaction {
             tx_type: TxType::Chargeback,
             tx_id: 1,
             client_id: 1,
             amount: None,
+            currency: None,
+            destination: None,
         }),
         Deposit::new(Transaction {
             tx_type: TxType::Deposit,
             tx_id: 2,
             client_id: 1,
             amount: Some(50.0),
+            currency: None,
+            destination: None,
         })
     )]
     fn test_account_locked_after_chargeback(
@@ -919,17 +1318,17 @@ mod tests {
 
         dispute.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 0.0);
-        assert_eq!(state.accounts[&1].held, 100.0);
-        assert_eq!(state.accounts[&1].total, 100.0);
-        assert!(!state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 0.0);
+        assert_eq!(state.accounts[&(1, None)].held, 100.0);
+        assert_eq!(state.accounts[&(1, None)].total, 100.0);
+        assert!(!state.accounts[&(1, None)].locked);
 
         chargeback.handle(&mut state).unwrap();
 
-        assert_eq!(state.accounts[&1].available, 0.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 0.0);
-        assert!(state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 0.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 0.0);
+        assert!(state.accounts[&(1, None)].locked);
 
         let res = new_deposit.handle(&mut state);
         assert!(matches!(
@@ -937,9 +1336,248 @@ mod tests {
             Err(TransactionError::AccountLocked { id: 1 })
         ));
 
-        assert_eq!(state.accounts[&1].available, 0.0);
-        assert_eq!(state.accounts[&1].held, 0.0);
-        assert_eq!(state.accounts[&1].total, 0.0);
-        assert!(state.accounts[&1].locked);
+        assert_eq!(state.accounts[&(1, None)].available, 0.0);
+        assert_eq!(state.accounts[&(1, None)].held, 0.0);
+        assert_eq!(state.accounts[&(1, None)].total, 0.0);
+        assert!(state.accounts[&(1, None)].locked);
+    }
+
+    fn transaction(
+        tx_type: TxType,
+        tx_id: u32,
+        client_id: u16,
+        amount: Option<f32>,
+    ) -> Transaction {
+        Transaction {
+            tx_type,
+            tx_id,
+            client_id,
+            amount,
+            currency: None,
+            destination: None,
+        }
+    }
+
+    fn transfer(tx_id: u32, amount: f32, destination: Option<u16>) -> Transaction {
+        Transaction {
+            destination,
+            ..transaction(TxType::Transfer, tx_id, 1, Some(amount))
+        }
+    }
+
+    /// State with 100 available to Client 1 and 5 to Client 2.
+    fn funded() -> State {
+        let mut state = State::default();
+        state
+            .apply(transaction(TxType::Deposit, 1, 1, Some(100.)))
+            .unwrap();
+        state
+            .apply(transaction(TxType::Deposit, 2, 2, Some(5.)))
+            .unwrap();
+        state
+    }
+
+    #[rstest]
+    #[case::transferred(transfer(3, 40., Some(2)), |res: &Result<_, _>| res.is_ok(), (60., 45.))]
+    #[case::insufficient_funds(
+        transfer(3, 100.5, Some(2)),
+        |res: &Result<_, _>| matches!(res, Err(TransactionError::BalanceInsufficient { id: 3, .. })),
+        (100., 5.)
+    )]
+    #[case::missing_destination_account(
+        transfer(3, 40., Some(3)),
+        |res: &Result<_, _>| matches!(res, Err(TransactionError::AccountNotFound { id: 3 })),
+        (100., 5.)
+    )]
+    #[case::no_destination(
+        transfer(3, 40., None),
+        |res: &Result<_, _>| matches!(
+            res,
+            Err(TransactionError::InvalidDestination { id: 3, destination: None })
+        ),
+        (100., 5.)
+    )]
+    #[case::self_transfer(
+        transfer(3, 40., Some(1)),
+        |res: &Result<_, _>| matches!(
+            res,
+            Err(TransactionError::InvalidDestination { id: 3, destination: Some(1) })
+        ),
+        (100., 5.)
+    )]
+    #[case::duplicate(
+        transfer(2, 40., Some(2)),
+        |res: &Result<_, _>| matches!(res, Err(TransactionError::DuplicateTransaction { id: 2 })),
+        (100., 5.)
+    )]
+    #[case::negative(
+        transfer(3, -40., Some(2)),
+        |res: &Result<_, _>| matches!(res, Err(TransactionError::MustBePositive { .. })),
+        (100., 5.)
+    )]
+    fn test_transfer(
+        #[case] tx: Transaction,
+        #[case] expected: fn(&Result<(), TransactionError>) -> bool,
+        #[case] available: (f32, f32),
+    ) {
+        let mut state = funded();
+
+        let res = state.apply(tx);
+
+        assert!(expected(&res), "{res:?}");
+        assert_eq!(
+            (
+                state.accounts[&(1, None)].available,
+                state.accounts[&(2, None)].available
+            ),
+            available
+        );
+        assert_eq!(state.accounts[&(1, None)].total, available.0);
+        assert_eq!(state.accounts[&(2, None)].total, available.1);
+        assert!(state.check_invariants().is_ok());
+    }
+
+    #[rstest]
+    #[case::source(1)]
+    #[case::destination(2)]
+    fn test_transfer_locked(#[case] locked: u16) {
+        let mut state = funded();
+        state.accounts.get_mut(&(locked, None)).unwrap().locked = true;
+
+        let res = state.apply(transfer(3, 40., Some(2)));
+
+        assert!(matches!(
+            res,
+            Err(TransactionError::AccountLocked { id }) if id == locked
+        ));
+        assert_eq!(state.accounts[&(1, None)].available, 100.);
+        assert_eq!(state.accounts[&(2, None)].available, 5.);
+    }
+
+    #[rstest]
+    fn test_dispute_transfer() {
+        let mut state = funded();
+        state.apply(transfer(3, 40., Some(2))).unwrap();
+
+        let res = state.apply(transaction(TxType::Dispute, 3, 1, None));
+        assert!(matches!(
+            res,
+            Err(TransactionError::NotFound {
+                tx_type: TxType::Dispute,
+                id: 3
+            })
+        ));
+        let res = state.apply(transaction(TxType::Chargeback, 3, 1, None));
+        assert!(matches!(
+            res,
+            Err(TransactionError::NotFound {
+                tx_type: TxType::Chargeback,
+                id: 3
+            })
+        ));
+
+        assert_eq!(state.accounts[&(1, None)].available, 60.);
+        assert_eq!(state.accounts[&(2, None)].available, 45.);
+        assert!(!state.accounts[&(1, None)].locked);
+    }
+
+    #[rstest]
+    #[case::consistent(|_: &mut State| {}, |res: &Result<_, _>| res.is_ok())]
+    #[case::non_finite(
+        |state: &mut State| state.accounts.get_mut(&(1, None)).unwrap().available = f32::NAN,
+        |res: &Result<_, _>| matches!(res, Err(InvariantError::NonFinite { id: 1 }))
+    )]
+    #[case::unbalanced(
+        |state: &mut State| state.accounts.get_mut(&(1, None)).unwrap().total = 101.,
+        |res: &Result<_, _>| matches!(res, Err(InvariantError::Unbalanced { id: 1, .. }))
+    )]
+    #[case::negative_held(
+        |state: &mut State| {
+            let account = state.accounts.get_mut(&(1, None)).unwrap();
+            account.held = -10.;
+            account.available = 110.;
+        },
+        |res: &Result<_, _>| matches!(res, Err(InvariantError::NegativeHeld { id: 1, .. }))
+    )]
+    #[case::orphaned(
+        |state: &mut State| {
+            state.accounts.remove(&(1, None));
+        },
+        |res: &Result<_, _>| matches!(res, Err(InvariantError::Orphaned { tx_id: 1, id: 1 }))
+    )]
+    fn test_check_invariants(
+        #[case] corrupt: fn(&mut State),
+        #[case] expected: fn(&Result<(), InvariantError>) -> bool,
+    ) {
+        let mut state = State::default();
+        for tx in [
+            Transaction {
+                tx_type: TxType::Deposit,
+                tx_id: 1,
+                client_id: 1,
+                amount: Some(100.),
+                currency: None,
+                destination: None,
+            },
+            Transaction {
+                tx_type: TxType::Dispute,
+                tx_id: 1,
+                client_id: 1,
+                amount: None,
+                currency: None,
+                destination: None,
+            },
+        ] {
+            state.apply(tx).unwrap();
+        }
+
+        corrupt(&mut state);
+
+        let res = state.check_invariants();
+        assert!(expected(&res), "{res:?}");
+    }
+
+    #[rstest]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_run_writes_rejections_before_parsing_error(#[values(1, 2)] shards: usize) {
+        let dir = std::env::temp_dir().join(format!("txn-run-{}-{shards}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("transactions.csv");
+        let rejects = dir.join("rejects.csv");
+        std::fs::write(
+            &input,
+            indoc::indoc! {"
+                type,client,tx,amount
+                deposit,1,1,100.0
+                withdrawal,1,2,250.0
+                deposit,2,3,10.0
+                deposit,not_a_number,4,100.0
+            "},
+        )
+        .unwrap();
+        let reports = Reports {
+            rejects: Some(rejects.clone()),
+            ..Default::default()
+        };
+
+        let res = run(
+            &input,
+            false,
+            NonZeroUsize::new(shards).unwrap(),
+            false,
+            &Policy::default(),
+            &reports,
+        )
+        .await;
+
+        assert!(res.unwrap_err().is::<ParsingError>());
+        assert_eq!(
+            std::fs::read_to_string(&rejects).unwrap(),
+            indoc::indoc! {"
+                type,client,tx,amount,currency,destination,code,message,line
+                withdrawal,1,2,250,,,E_INSUFFICIENT_FUNDS,\"Balance insufficient: available '100', Withdrawal amount '250', Transaction Id '2'\",3
+            "}
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,59 +1,365 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use strum::{AsRefStr, EnumString};
 
-use crate::error::TransactionError;
+use crate::error::{InvariantError, TransactionError};
+use crate::model::{
+    chargeback::Chargeback, deposit::Deposit, dispute::Dispute, resolve::Resolve,
+    transfer::Transfer, withdrawal::Withdrawal,
+};
+use crate::policy::{Limits, Policy};
+use crate::rules::{Flag, RuleSet};
 
 pub mod chargeback;
 pub mod deposit;
 pub mod dispute;
+#[cfg(test)]
+mod reference;
 pub mod resolve;
+pub mod transfer;
 pub mod withdrawal;
 
 pub type ClientId = u16;
 pub type TxId = u32;
 pub type Amount = f32;
+/// Identifies a Client account: the Client, and the currency of its balances, if any.
+pub type AccountId = (ClientId, Option<Currency>);
 
 /// Represents the Transaction type.
-#[derive(Copy, Clone, Debug, PartialEq, AsRefStr, EnumString, Deserialize)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, AsRefStr, EnumString, Deserialize, Serialize,
+)]
 #[serde(rename_all = "lowercase")]
+#[strum(ascii_case_insensitive)]
 pub enum TxType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Transfer,
+}
+
+/// Three-letter currency code of an account and its transactions, such as `EUR`, upper-cased when
+/// parsed. Transactions without a currency apply to the Client's account without one.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        // Only ASCII letters are parsed
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        match code.as_bytes() {
+            code @ [_, _, _] if code.iter().all(u8::is_ascii_alphabetic) => {
+                let mut currency = [0; 3];
+                currency.copy_from_slice(code);
+                Ok(Self(currency.map(|c| c.to_ascii_uppercase())))
+            }
+            _ => Err(format!(
+                "Invalid currency '{code}', expected a three-letter code"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = Currency;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a three-letter currency code")
+            }
+
+            fn visit_str<E: de::Error>(self, code: &str) -> Result<Currency, E> {
+                code.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(Visitor)
+    }
 }
 
 /// Holds the mutable world state for the application, including Client accounts and previous
-/// transactions.
+/// transactions, along with the policies the handlers apply to them.
 #[derive(Default)]
 pub struct State {
-    pub accounts: HashMap<ClientId, ClientAccount>,
+    /// Client accounts, one per currency the Client transacted in.
+    pub accounts: HashMap<AccountId, ClientAccount>,
     pub transactions: HashMap<TxId, Box<dyn TransactionHandler>>,
+    /// Recent withdrawals and transfers out of each account, only tracked when the policy limits
+    /// them over a window. Amounts in different currencies are never added up.
+    pub velocity: HashMap<AccountId, Velocity>,
+    /// Risk rules judging every transaction before its handler, copied from the policy.
+    pub rules: RuleSet,
+    /// Accepted transactions the rules flagged for review, in the order they were applied.
+    pub flagged: Vec<Flag>,
+    pub policy: Policy,
+}
+
+impl State {
+    /// Creates an empty State applying the given policies.
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            rules: policy.rules(),
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Judges a deserialized Transaction by the rules, then dispatches it to the handler for its
+    /// type and applies it to the State.
+    pub fn apply(&mut self, tx: Transaction) -> Result<(), TransactionError> {
+        let _span = tracing::debug_span!(
+            "handle",
+            tx_type = tx.tx_type.as_ref(),
+            tx = tx.tx_id,
+            client = tx.client_id,
+        )
+        .entered();
+
+        let (client_id, account_id) = (tx.client_id, (tx.client_id, tx.currency));
+        // Transfers take funds out of the account as withdrawals do
+        let withdrawal = tx
+            .amount
+            .filter(|_| matches!(tx.tx_type, TxType::Withdrawal | TxType::Transfer));
+        // Rules see the State as it is before the transaction, and record it once accepted
+        let judged = (!self.rules.is_empty()).then(|| tx.clone());
+        let rules = std::mem::take(&mut self.rules);
+        let verdict = rules.check(&tx, self);
+        self.rules = rules;
+        let res = verdict.and_then(|flags| {
+            match tx.tx_type {
+                TxType::Deposit => Deposit::new(tx).handle(self),
+                TxType::Withdrawal => Withdrawal::new(tx).handle(self),
+                TxType::Resolve => Resolve::new(tx).handle(self),
+                TxType::Chargeback => Chargeback::new(tx).handle(self),
+                TxType::Dispute => Dispute::new(tx).handle(self),
+                TxType::Transfer => Transfer::new(tx).handle(self),
+            }
+            .map(|()| flags)
+        });
+        let res = res.map(|flags| {
+            if let Some(tx) = &judged {
+                self.rules.accepted(tx);
+            }
+            for flag in flags {
+                tracing::info!(rule = flag.rule, reason = flag.reason, "flagged");
+                self.flagged.push(flag);
+            }
+        });
+        match &res {
+            Ok(()) => tracing::debug!("accepted"),
+            Err(e) => tracing::debug!(code = e.code(), error = %e, "rejected"),
+        }
+        if res.is_ok()
+            && let Some(window) = self.policy.limits(client_id).window
+        {
+            self.velocity
+                .entry(account_id)
+                .or_default()
+                .accepted(window.get(), withdrawal);
+        }
+        res
+    }
+
+    /// Checks the invariants every account and stored transaction hold after any sequence of
+    /// transactions, returning the first violation. Each balance accumulates its own `f32`
+    /// rounding errors, which grow with the amounts that went through the account rather than
+    /// with its final balances, so balances are compared with a tolerance relative to the sum of
+    /// the Client's stored transactions.
+    pub fn check_invariants(&self) -> Result<(), InvariantError> {
+        let mut volumes = HashMap::<AccountId, f64>::new();
+        for tx in self.transactions.values() {
+            // Transfers went through the destination's account as well
+            let destination = tx.destination().map(|client_id| (client_id, tx.currency()));
+            for id in std::iter::once(tx.account_id()).chain(destination) {
+                if !self.accounts.contains_key(&id) {
+                    return Err(InvariantError::Orphaned {
+                        tx_id: tx.tx_id(),
+                        id: id.0,
+                    });
+                }
+                *volumes.entry(id).or_default() += f64::from(tx.amount().unwrap_or_default().abs());
+            }
+        }
+
+        for account in self.accounts.values() {
+            let ClientAccount {
+                client_id: id,
+                available,
+                held,
+                total,
+                ..
+            } = *account;
+            if ![available, held, total].iter().all(|b| b.is_finite()) {
+                return Err(InvariantError::NonFinite { id });
+            }
+
+            let volume = volumes.get(&account.id()).copied().unwrap_or_default();
+            let tolerance = INVARIANT_TOLERANCE * volume.max(1.);
+            if f64::from(available + held - total).abs() > tolerance {
+                return Err(InvariantError::Unbalanced {
+                    id,
+                    available,
+                    held,
+                    total,
+                });
+            }
+            if f64::from(held) < -tolerance {
+                return Err(InvariantError::NegativeHeld { id, held });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Difference tolerated between balances that should be equal, relative to the amounts that went
+/// through the account.
+const INVARIANT_TOLERANCE: f64 = 1e-4;
+
+/// Traces a Client account after a handler changed its balances or lock.
+fn trace_account(account: &ClientAccount) {
+    tracing::trace!(
+        client = account.client_id,
+        currency = account.currency.as_ref().map(Currency::as_str),
+        available = account.available,
+        held = account.held,
+        total = account.total,
+        locked = account.locked,
+        "account updated"
+    );
+}
+
+/// Traces a stored transaction after a handler changed its status.
+fn trace_status(tx: &dyn TransactionHandler) {
+    tracing::trace!(
+        tx = tx.tx_id(),
+        status = tx.status().as_ref(),
+        "status updated"
+    );
+}
+
+/// Withdrawals and transfers out of an account within its most recent accepted transactions, for
+/// limits over a window. Only accepted transactions count, as every one of them is applied to the
+/// State owning the Client's accounts, whether the file is sharded or not.
+#[derive(Clone, Debug, Default)]
+pub struct Velocity {
+    /// Number of the account's accepted transactions.
+    transactions: u64,
+    /// Position of each withdrawal among the account's accepted transactions, and its amount,
+    /// oldest first.
+    withdrawals: VecDeque<(u64, Amount)>,
+}
+
+impl Velocity {
+    /// Number and sum of the withdrawals within the account's last `window` accepted transactions.
+    fn within(&self, window: u32) -> (u32, f64) {
+        let start = self.transactions.saturating_sub(u64::from(window));
+        self.withdrawals
+            .iter()
+            .filter(|(transaction, _)| *transaction > start)
+            .fold((0, 0.), |(count, total), (_, amount)| {
+                (count + 1, total + f64::from(*amount))
+            })
+    }
+
+    /// Counts an accepted transaction, along with its amount if it is a withdrawal, forgetting the
+    /// withdrawals that fell out of the window.
+    fn accepted(&mut self, window: u32, withdrawal: Option<Amount>) {
+        self.transactions += 1;
+        if let Some(amount) = withdrawal {
+            self.withdrawals.push_back((self.transactions, amount));
+        }
+        let start = self.transactions.saturating_sub(u64::from(window));
+        while self
+            .withdrawals
+            .front()
+            .is_some_and(|(transaction, _)| *transaction <= start)
+        {
+            self.withdrawals.pop_front();
+        }
+    }
 }
 
 /// Identifies a Transaction as deserialized from the CSV file.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub tx_type: TxType,
+    #[serde(rename = "client")]
     pub client_id: u16,
+    #[serde(rename = "tx")]
     pub tx_id: u32,
     pub amount: Option<f32>,
+    /// Currency of the amount, or of the referenced transaction, read from an optional fifth
+    /// column.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// Client a transfer credits, read from an optional sixth column and ignored by the other
+    /// types.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<ClientId>,
 }
 
-/// Embodies a Client account with a total balance, funds available to withdraw and funds held
-/// against chargebacks. A client account will be locked on a Chargeback transaction, which
-/// prevents further operations on that Client. This implementation never unlocks a client.
-#[derive(Debug, Default)]
+/// Embodies a Client account in a currency with a total balance, funds available to withdraw and
+/// funds held against chargebacks. A client account will be locked on a Chargeback transaction,
+/// which prevents further operations on that Client in the account's currency, unless the
+/// [`Policy`] says otherwise. This implementation never unlocks a client.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct ClientAccount {
+    #[serde(rename = "client")]
     pub client_id: u16,
     pub available: f32,
     pub held: f32,
     pub total: f32,
     pub locked: bool,
+    /// Currency of the balances, read from an optional sixth column.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+}
+
+impl ClientAccount {
+    pub fn id(&self) -> AccountId {
+        (self.client_id, self.currency)
+    }
+
+    /// Whether the available funds are below zero, through an overdraft or a dispute of funds
+    /// already withdrawn.
+    pub fn overdrawn(&self) -> bool {
+        self.available < 0.
+    }
 }
 
 /// A deposit transaction can have a status, dispute, resolve and chargeback transactions can only
@@ -62,7 +368,10 @@ pub struct ClientAccount {
 ///  - Dispute requires a Valid state, and sets the Deposit transaction into a Disputed state.
 ///  - Resolve requires a Disputed state, and sets the Deposit transaction back to Valid state.
 ///  - Chargeback requires a Disputed state, and sets the Deposit transaction to a Chargeback state.
-#[derive(Copy, Clone, Debug, Default, PartialEq, AsRefStr, EnumString)]
+///
+/// Withdrawals go through the same states when the [`Policy`] allows disputing them.
+#[derive(Copy, Clone, Debug, Default, PartialEq, AsRefStr, EnumString, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TxStatus {
     #[default]
     Valid,
@@ -70,14 +379,25 @@ pub enum TxStatus {
     Chargeback,
 }
 
-pub trait TransactionHandler {
+pub trait TransactionHandler: Send {
     fn client_id(&self) -> ClientId;
     fn tx_id(&self) -> TxId;
     fn tx_type(&self) -> TxType;
     fn amount(&self) -> Option<Amount>;
+    fn currency(&self) -> Option<Currency>;
     fn status(&self) -> TxStatus;
     fn set_status(&mut self, state: TxStatus);
 
+    /// The account of the Client in the transaction's currency.
+    fn account_id(&self) -> AccountId {
+        (self.client_id(), self.currency())
+    }
+
+    /// The Client a transfer credits, besides debiting its own Client.
+    fn destination(&self) -> Option<ClientId> {
+        None
+    }
+
     /// Processes a transaction and updates the application's State.
     fn handle(self, state: &mut State) -> Result<(), TransactionError>;
 }
@@ -89,18 +409,36 @@ trait TransactionExt {
         available: Amount,
         amount: Amount,
     ) -> Result<(), TransactionError>;
+    fn check_funds(
+        &self,
+        available: Amount,
+        amount: Amount,
+        overdraft: Option<Amount>,
+    ) -> Result<(), TransactionError>;
     fn check_client_id_mismatch(&self, client_id: ClientId) -> Result<(), TransactionError>;
+    fn check_currency_mismatch(&self, currency: Option<Currency>) -> Result<(), TransactionError>;
     fn check_duplicate(
         &self,
         transactions: &HashMap<TxId, Box<dyn TransactionHandler>>,
     ) -> Result<(), TransactionError>;
-    fn check_locked(&self, account: &ClientAccount) -> Result<(), TransactionError>;
+    fn check_locked(
+        &self,
+        account: &ClientAccount,
+        policy: &Policy,
+    ) -> Result<(), TransactionError>;
+    fn check_limits(
+        &self,
+        amount: Amount,
+        limits: &Limits,
+        velocity: Option<&Velocity>,
+    ) -> Result<(), TransactionError>;
 }
 
 impl<T: TransactionHandler> TransactionExt for T {
-    /// Returns a MustBePositive error if the balance is below zero.
+    /// Returns a MustBePositive error if the balance is below zero, or isn't a finite number such
+    /// as a `NaN` or `inf` amount parsed from the CSV.
     fn check_positive(&self, amount: Amount) -> Result<(), TransactionError> {
-        if amount < 0.0 {
+        if amount < 0.0 || !amount.is_finite() {
             Err(TransactionError::MustBePositive {
                 tx_type: self.tx_type(),
                 id: self.tx_id(),
@@ -129,6 +467,31 @@ impl<T: TransactionHandler> TransactionExt for T {
         }
     }
 
+    /// Returns an OverdraftLimit error if the amount would take the available funds below the
+    /// Client's overdraft, or a BalanceInsufficient error if it would take them below zero without
+    /// an overdraft.
+    fn check_funds(
+        &self,
+        available: Amount,
+        amount: Amount,
+        overdraft: Option<Amount>,
+    ) -> Result<(), TransactionError> {
+        let Some(overdraft) = overdraft else {
+            return self.check_sufficient_balance(available, amount);
+        };
+        if available + overdraft < amount {
+            Err(TransactionError::OverdraftLimit {
+                available,
+                tx_type: self.tx_type(),
+                id: self.tx_id(),
+                amount,
+                overdraft,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
     /// Returns a ClientIdMismatch error if the Client Id doesn't match this transaction's Client
     /// Id.
     fn check_client_id_mismatch(&self, client_id: ClientId) -> Result<(), TransactionError> {
@@ -142,13 +505,25 @@ impl<T: TransactionHandler> TransactionExt for T {
         }
     }
 
+    /// Returns a CurrencyMismatch error if the currency doesn't match this transaction's currency.
+    fn check_currency_mismatch(&self, currency: Option<Currency>) -> Result<(), TransactionError> {
+        if currency != self.currency() {
+            Err(TransactionError::CurrencyMismatch {
+                expected: currency,
+                actual: self.currency(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
     /// Returns a DuplicateTransaction error if the Deposit or Withdrawal has an identical
-    /// transaction id in the State.
+    /// transaction id in the State, whatever its currency.
     fn check_duplicate(
         &self,
         transactions: &HashMap<TxId, Box<dyn TransactionHandler>>,
     ) -> Result<(), TransactionError> {
-        if transactions.keys().any(|tx| *tx == self.tx_id()) {
+        if transactions.contains_key(&self.tx_id()) {
             Err(TransactionError::DuplicateTransaction { id: self.tx_id() })
         } else {
             Ok(())
@@ -156,14 +531,61 @@ impl<T: TransactionHandler> TransactionExt for T {
     }
 
     /// Returns an AccountLocked error if the Client Account was locked through a successful
-    /// Chargeback transaction.
-    fn check_locked(&self, account: &ClientAccount) -> Result<(), TransactionError> {
-        if account.locked {
+    /// Chargeback transaction, and the policy rejects this type of transaction on locked accounts.
+    fn check_locked(
+        &self,
+        account: &ClientAccount,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        if account.locked && policy.rejects_locked(self.tx_type()) {
             Err(TransactionError::AccountLocked {
-                id: self.client_id(),
+                id: account.client_id,
             })
         } else {
             Ok(())
         }
     }
+
+    /// Returns a WithdrawalLimit error if the amount exceeds the largest single withdrawal, or a
+    /// VelocityTotal or VelocityCount error if it would exceed the limits over the window, which
+    /// ends with this withdrawal.
+    fn check_limits(
+        &self,
+        amount: Amount,
+        limits: &Limits,
+        velocity: Option<&Velocity>,
+    ) -> Result<(), TransactionError> {
+        if let Some(limit) = limits.max_withdrawal.filter(|limit| amount > *limit) {
+            return Err(TransactionError::WithdrawalLimit {
+                id: self.tx_id(),
+                amount,
+                limit,
+            });
+        }
+        let Some(window) = limits.window else {
+            return Ok(());
+        };
+        let (count, total) = velocity.map_or((0, 0.), |v| v.within(window.get() - 1));
+        let total = total + f64::from(amount);
+        if let Some(limit) = limits
+            .max_window_total
+            .filter(|limit| total > f64::from(*limit))
+        {
+            return Err(TransactionError::VelocityTotal {
+                id: self.tx_id(),
+                total,
+                limit,
+                window: window.get(),
+            });
+        }
+        if let Some(limit) = limits.max_window_count.filter(|limit| count + 1 > *limit) {
+            return Err(TransactionError::VelocityCount {
+                id: self.tx_id(),
+                count: count + 1,
+                limit,
+                window: window.get(),
+            });
+        }
+        Ok(())
+    }
 }
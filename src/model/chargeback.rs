@@ -1,8 +1,8 @@
 use crate::{
     error::TransactionError,
     model::{
-        Amount, ClientId, State, Transaction, TransactionExt, TransactionHandler, TxId, TxStatus,
-        TxType,
+        Amount, ClientId, Currency, State, Transaction, TransactionExt, TransactionHandler, TxId,
+        TxStatus, TxType, trace_account, trace_status,
     },
 };
 
@@ -39,6 +39,10 @@ impl TransactionHandler for Chargeback {
         self.inner.amount
     }
     #[inline]
+    fn currency(&self) -> Option<Currency> {
+        self.inner.currency
+    }
+    #[inline]
     fn status(&self) -> TxStatus {
         self.status
     }
@@ -50,7 +54,7 @@ impl TransactionHandler for Chargeback {
         let tx = state
             .transactions
             .get_mut(&self.tx_id())
-            .filter(|tx| tx.tx_type() == TxType::Deposit)
+            .filter(|tx| state.policy.disputable(tx.tx_type()))
             .ok_or_else(|| TransactionError::NotFound {
                 tx_type: self.tx_type(),
                 id: self.tx_id(),
@@ -68,13 +72,14 @@ impl TransactionHandler for Chargeback {
             })?;
 
         self.check_client_id_mismatch(tx.client_id())?;
+        self.check_currency_mismatch(tx.currency())?;
 
         let account = state
             .accounts
-            .get_mut(&tx.client_id())
+            .get_mut(&tx.account_id())
             .ok_or_else(|| TransactionError::AccountNotFound { id: tx.client_id() })?;
 
-        self.check_locked(account)?;
+        self.check_locked(account, &state.policy)?;
 
         let amount = tx.amount().ok_or_else(|| TransactionError::MissingAmount {
             tx_type: self.tx_type(),
@@ -82,23 +87,46 @@ impl TransactionHandler for Chargeback {
         })?;
 
         tx.set_status(TxStatus::Chargeback);
+        trace_status(tx.as_ref());
 
-        // Check if a previous dispute(s) left the account in arrears
-        // and should fail the chargeback due to a negative balance
-        if account.available < 0. {
-            return Err(TransactionError::BalanceInsufficient {
-                available: account.available + amount,
-                tx_type: self.tx_type(),
-                id: self.tx_id(),
-                amount,
-            });
+        // Check if a previous dispute(s) left the account in arrears, beyond its overdraft if it
+        // has one, and should fail the chargeback due to a negative balance
+        let overdraft = state.policy.limits(tx.client_id()).overdraft;
+        if tx.tx_type() == TxType::Deposit {
+            match overdraft {
+                Some(overdraft) if account.available < -overdraft => {
+                    return Err(TransactionError::OverdraftLimit {
+                        available: account.available,
+                        tx_type: self.tx_type(),
+                        id: self.tx_id(),
+                        amount,
+                        overdraft,
+                    });
+                }
+                None if account.available < 0. => {
+                    return Err(TransactionError::BalanceInsufficient {
+                        available: account.available + amount,
+                        tx_type: self.tx_type(),
+                        id: self.tx_id(),
+                        amount,
+                    });
+                }
+                _ => {}
+            }
         }
 
         self.check_sufficient_balance(account.held, amount)?;
 
         account.held -= amount;
-        account.total -= amount;
-        account.locked = true;
+        match tx.tx_type() {
+            // The withdrawal is reversed, returning the held funds to the Client
+            TxType::Withdrawal => account.available += amount,
+            _ => account.total -= amount,
+        }
+        if state.policy.chargebacks.lock_account {
+            account.locked = true;
+        }
+        trace_account(account);
 
         Ok(())
     }
@@ -1,8 +1,8 @@
 use crate::{
     error::TransactionError,
     model::{
-        Amount, ClientAccount, ClientId, State, Transaction, TransactionExt, TransactionHandler,
-        TxId, TxStatus, TxType,
+        Amount, ClientAccount, ClientId, Currency, State, Transaction, TransactionExt,
+        TransactionHandler, TxId, TxStatus, TxType, trace_account,
     },
 };
 
@@ -38,6 +38,10 @@ impl TransactionHandler for Deposit {
         self.inner.amount
     }
     #[inline]
+    fn currency(&self) -> Option<Currency> {
+        self.inner.currency
+    }
+    #[inline]
     fn status(&self) -> TxStatus {
         self.status
     }
@@ -57,16 +61,18 @@ impl TransactionHandler for Deposit {
 
         let account = state
             .accounts
-            .entry(self.client_id())
+            .entry(self.account_id())
             .or_insert_with(|| ClientAccount {
                 client_id: self.client_id(),
+                currency: self.currency(),
                 ..Default::default()
             });
 
-        self.check_locked(account)?;
+        self.check_locked(account, &state.policy)?;
 
         account.available += amount;
         account.total += amount;
+        trace_account(account);
 
         self.status = TxStatus::Valid;
 
@@ -1,8 +1,8 @@
 use crate::{
     error::TransactionError,
     model::{
-        Amount, ClientId, State, Transaction, TransactionExt, TransactionHandler, TxId, TxStatus,
-        TxType,
+        Amount, ClientId, Currency, State, Transaction, TransactionExt, TransactionHandler, TxId,
+        TxStatus, TxType, trace_account, trace_status,
     },
 };
 
@@ -39,6 +39,10 @@ impl TransactionHandler for Dispute {
         self.inner.amount
     }
     #[inline]
+    fn currency(&self) -> Option<Currency> {
+        self.inner.currency
+    }
+    #[inline]
     fn status(&self) -> TxStatus {
         self.status
     }
@@ -50,7 +54,7 @@ impl TransactionHandler for Dispute {
         let tx = state
             .transactions
             .get_mut(&self.tx_id())
-            .filter(|tx| tx.tx_type() == TxType::Deposit)
+            .filter(|tx| state.policy.disputable(tx.tx_type()))
             .ok_or_else(|| TransactionError::NotFound {
                 tx_type: self.tx_type(),
                 id: self.tx_id(),
@@ -68,26 +72,45 @@ impl TransactionHandler for Dispute {
             })?;
 
         self.check_client_id_mismatch(tx.client_id())?;
+        self.check_currency_mismatch(tx.currency())?;
 
         let account = state
             .accounts
-            .get_mut(&tx.client_id())
+            .get_mut(&tx.account_id())
             .ok_or_else(|| TransactionError::AccountNotFound { id: tx.client_id() })?;
 
-        self.check_locked(account)?;
+        self.check_locked(account, &state.policy)?;
 
         let amount = tx.amount().ok_or_else(|| TransactionError::MissingAmount {
             tx_type: self.tx_type(),
             id: self.tx_id(),
         })?;
 
-        tx.set_status(TxStatus::Disputed);
+        match tx.tx_type() {
+            TxType::Withdrawal => {
+                tx.set_status(TxStatus::Disputed);
+                trace_status(tx.as_ref());
 
-        // Could result in a negative amount of available funds,
-        // we check if we're able to release those funds on the Chargeback transaction
+                // The withdrawn funds are held until the dispute is resolved or charged back
+                account.held += amount;
+                account.total += amount;
+            }
+            _ => {
+                if !state.policy.disputes.allow_negative_available {
+                    let overdraft = state.policy.limits(tx.client_id()).overdraft;
+                    self.check_funds(account.available, amount, overdraft)?;
+                }
 
-        account.available -= amount;
-        account.held += amount;
+                tx.set_status(TxStatus::Disputed);
+                trace_status(tx.as_ref());
+
+                // Could result in a negative amount of available funds,
+                // we check if we're able to release those funds on the Chargeback transaction
+                account.available -= amount;
+                account.held += amount;
+            }
+        }
+        trace_account(account);
 
         Ok(())
     }
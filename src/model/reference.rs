@@ -0,0 +1,377 @@
+//! A deliberately naive reference model of the transaction engine, used to check the handlers
+//! against generated transaction sequences. The model keeps plain records instead of handler
+//! trait objects, and encodes the business rules as a single flat match so that it can be read
+//! side by side with the assumptions documented in the README.
+
+use std::collections::HashMap;
+use std::mem::discriminant;
+
+use proptest::prelude::*;
+
+use crate::{
+    error::TransactionError,
+    model::{Amount, ClientId, State, Transaction, TxId, TxStatus, TxType},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Balance {
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Record {
+    client_id: ClientId,
+    tx_type: TxType,
+    amount: Amount,
+    status: TxStatus,
+}
+
+#[derive(Debug, Default)]
+struct Model {
+    balances: HashMap<ClientId, Balance>,
+    records: HashMap<TxId, Record>,
+}
+
+impl Model {
+    fn apply(&mut self, tx: &Transaction) -> Result<(), TransactionError> {
+        let id = tx.tx_id;
+        match tx.tx_type {
+            TxType::Deposit | TxType::Withdrawal => {
+                if self.records.contains_key(&id) {
+                    return Err(TransactionError::DuplicateTransaction { id });
+                }
+                let amount = tx.amount.ok_or(TransactionError::MissingAmount {
+                    tx_type: tx.tx_type,
+                    id,
+                })?;
+                if amount < 0. || !amount.is_finite() {
+                    return Err(TransactionError::MustBePositive {
+                        tx_type: tx.tx_type,
+                        id,
+                        amount,
+                    });
+                }
+
+                let balance = if tx.tx_type == TxType::Deposit {
+                    self.balances.entry(tx.client_id).or_default()
+                } else {
+                    self.balances
+                        .get_mut(&tx.client_id)
+                        .ok_or(TransactionError::AccountNotFound { id: tx.client_id })?
+                };
+                if balance.locked {
+                    return Err(TransactionError::AccountLocked { id: tx.client_id });
+                }
+
+                if tx.tx_type == TxType::Deposit {
+                    balance.available += amount;
+                    balance.total += amount;
+                } else {
+                    if balance.available < amount {
+                        return Err(TransactionError::BalanceInsufficient {
+                            available: balance.available,
+                            tx_type: tx.tx_type,
+                            id,
+                            amount,
+                        });
+                    }
+                    balance.available -= amount;
+                    balance.total -= amount;
+                }
+
+                self.records.insert(
+                    id,
+                    Record {
+                        client_id: tx.client_id,
+                        tx_type: tx.tx_type,
+                        amount,
+                        status: TxStatus::Valid,
+                    },
+                );
+            }
+            TxType::Transfer => {
+                if self.records.contains_key(&id) {
+                    return Err(TransactionError::DuplicateTransaction { id });
+                }
+                let amount = tx.amount.ok_or(TransactionError::MissingAmount {
+                    tx_type: tx.tx_type,
+                    id,
+                })?;
+                if amount < 0. || !amount.is_finite() {
+                    return Err(TransactionError::MustBePositive {
+                        tx_type: tx.tx_type,
+                        id,
+                        amount,
+                    });
+                }
+                let destination = tx.destination.filter(|d| *d != tx.client_id).ok_or(
+                    TransactionError::InvalidDestination {
+                        id,
+                        destination: tx.destination,
+                    },
+                )?;
+
+                let source = *self
+                    .balances
+                    .get(&tx.client_id)
+                    .ok_or(TransactionError::AccountNotFound { id: tx.client_id })?;
+                if source.locked {
+                    return Err(TransactionError::AccountLocked { id: tx.client_id });
+                }
+                if source.available < amount {
+                    return Err(TransactionError::BalanceInsufficient {
+                        available: source.available,
+                        tx_type: tx.tx_type,
+                        id,
+                        amount,
+                    });
+                }
+                let target = self
+                    .balances
+                    .get(&destination)
+                    .ok_or(TransactionError::AccountNotFound { id: destination })?;
+                if target.locked {
+                    return Err(TransactionError::AccountLocked { id: destination });
+                }
+
+                for (client_id, amount) in [(tx.client_id, -amount), (destination, amount)] {
+                    let balance = self.balances.entry(client_id).or_default();
+                    balance.available += amount;
+                    balance.total += amount;
+                }
+
+                // Never disputable, as only deposits are by default
+                self.records.insert(
+                    id,
+                    Record {
+                        client_id: tx.client_id,
+                        tx_type: tx.tx_type,
+                        amount,
+                        status: TxStatus::Valid,
+                    },
+                );
+            }
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => {
+                let record = self
+                    .records
+                    .get_mut(&id)
+                    .filter(|record| record.tx_type == TxType::Deposit)
+                    .ok_or(TransactionError::NotFound {
+                        tx_type: tx.tx_type,
+                        id,
+                    })?;
+
+                let required = if tx.tx_type == TxType::Dispute {
+                    TxStatus::Valid
+                } else {
+                    TxStatus::Disputed
+                };
+                if record.status != required {
+                    return Err(TransactionError::IncorrectState {
+                        tx_type: tx.tx_type,
+                        state: record.status,
+                        id,
+                    });
+                }
+                if record.client_id != tx.client_id {
+                    return Err(TransactionError::ClientIdMismatch {
+                        expected: record.client_id,
+                        actual: tx.client_id,
+                    });
+                }
+
+                let balance = self.balances.get_mut(&record.client_id).ok_or(
+                    TransactionError::AccountNotFound {
+                        id: record.client_id,
+                    },
+                )?;
+                if balance.locked {
+                    return Err(TransactionError::AccountLocked { id: tx.client_id });
+                }
+
+                let amount = record.amount;
+                match tx.tx_type {
+                    TxType::Dispute => {
+                        record.status = TxStatus::Disputed;
+                        balance.available -= amount;
+                        balance.held += amount;
+                    }
+                    TxType::Resolve => {
+                        // The deposit is released even if the held funds can't cover it
+                        record.status = TxStatus::Valid;
+                        if balance.held < amount {
+                            return Err(TransactionError::BalanceInsufficient {
+                                available: balance.held,
+                                tx_type: tx.tx_type,
+                                id,
+                                amount,
+                            });
+                        }
+                        balance.held -= amount;
+                        balance.available += amount;
+                    }
+                    _ => {
+                        // The deposit is charged back even if the account can't cover it
+                        record.status = TxStatus::Chargeback;
+                        if balance.available < 0. {
+                            return Err(TransactionError::BalanceInsufficient {
+                                available: balance.available + amount,
+                                tx_type: tx.tx_type,
+                                id,
+                                amount,
+                            });
+                        }
+                        if balance.held < amount {
+                            return Err(TransactionError::BalanceInsufficient {
+                                available: balance.held,
+                                tx_type: tx.tx_type,
+                                id,
+                                amount,
+                            });
+                        }
+                        balance.held -= amount;
+                        balance.total -= amount;
+                        balance.locked = true;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Asserts that the engine and the model agree on every account and stored transaction.
+fn assert_same_state(state: &State, model: &Model) -> Result<(), TestCaseError> {
+    prop_assert_eq!(state.accounts.len(), model.balances.len());
+    for ((client_id, currency), account) in &state.accounts {
+        prop_assert_eq!(account.client_id, *client_id);
+        prop_assert_eq!(*currency, None);
+        let actual = Balance {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        };
+        prop_assert_eq!(Some(&actual), model.balances.get(client_id));
+        prop_assert!(
+            (account.total - (account.available + account.held)).abs() < 0.01,
+            "Client {} total {} diverged from available {} and held {}",
+            client_id,
+            account.total,
+            account.available,
+            account.held
+        );
+    }
+
+    prop_assert_eq!(state.transactions.len(), model.records.len());
+    for (tx_id, tx) in &state.transactions {
+        let record = model.records.get(tx_id);
+        prop_assert!(record.is_some(), "Transaction {} missing from model", tx_id);
+        let record = record.unwrap();
+        prop_assert_eq!(tx.client_id(), record.client_id);
+        prop_assert_eq!(tx.tx_type(), record.tx_type);
+        prop_assert_eq!(tx.amount(), Some(record.amount));
+        prop_assert_eq!(tx.status(), record.status);
+    }
+
+    Ok(())
+}
+
+fn tx_type() -> impl Strategy<Value = TxType> {
+    prop_oneof![
+        4 => Just(TxType::Deposit),
+        3 => Just(TxType::Withdrawal),
+        2 => Just(TxType::Dispute),
+        1 => Just(TxType::Resolve),
+        1 => Just(TxType::Chargeback),
+        2 => Just(TxType::Transfer),
+    ]
+}
+
+fn amount() -> impl Strategy<Value = Option<Amount>> {
+    prop_oneof![
+        1 => Just(None),
+        1 => Just(Some(Amount::NAN)),
+        1 => (-10_000i32..0).prop_map(|cents| Some(cents as Amount / 100.)),
+        18 => (0i32..100_000).prop_map(|cents| Some(cents as Amount / 100.)),
+    ]
+}
+
+/// Small client and transaction id ranges make duplicates, client mismatches and references to
+/// existing transactions likely.
+fn transaction() -> impl Strategy<Value = Transaction> {
+    (
+        tx_type(),
+        1u16..=3,
+        1u32..=12,
+        amount(),
+        prop::option::of(1u16..=4),
+    )
+        .prop_map(
+            |(tx_type, client_id, tx_id, amount, destination)| Transaction {
+                tx_type,
+                client_id,
+                tx_id,
+                amount,
+                currency: None,
+                destination: destination.filter(|_| tx_type == TxType::Transfer),
+            },
+        )
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn test_handlers_match_reference_model(txs in prop::collection::vec(transaction(), 1..64)) {
+        let mut state = State::default();
+        let mut model = Model::default();
+
+        for (row, tx) in txs.into_iter().enumerate() {
+            let expected = model.apply(&tx);
+            let actual = state.apply(tx);
+
+            prop_assert_eq!(
+                actual.as_ref().err().map(discriminant),
+                expected.as_ref().err().map(discriminant),
+                "Row {}: engine returned {:?}, model returned {:?}",
+                row,
+                actual,
+                expected
+            );
+            assert_same_state(&state, &model)?;
+            prop_assert!(
+                state.check_invariants().is_ok(),
+                "Row {}: {:?}",
+                row,
+                state.check_invariants()
+            );
+        }
+    }
+
+    #[test]
+    fn test_deposits_without_disputes_never_lock(
+        txs in prop::collection::vec(
+            transaction().prop_filter("Deposits and withdrawals only", |tx| {
+                matches!(tx.tx_type, TxType::Deposit | TxType::Withdrawal)
+            }),
+            1..64,
+        )
+    ) {
+        let mut state = State::default();
+
+        for tx in txs {
+            let _ = state.apply(tx);
+        }
+
+        for account in state.accounts.values() {
+            prop_assert!(!account.locked);
+            prop_assert_eq!(account.held, 0.);
+            prop_assert!(account.available >= 0.);
+        }
+    }
+}
@@ -1,8 +1,8 @@
 use crate::{
     error::TransactionError,
     model::{
-        Amount, ClientId, State, Transaction, TransactionExt, TransactionHandler, TxId, TxStatus,
-        TxType,
+        Amount, ClientId, Currency, State, Transaction, TransactionExt, TransactionHandler, TxId,
+        TxStatus, TxType, trace_account, trace_status,
     },
 };
 
@@ -39,6 +39,10 @@ impl TransactionHandler for Resolve {
         self.inner.amount
     }
     #[inline]
+    fn currency(&self) -> Option<Currency> {
+        self.inner.currency
+    }
+    #[inline]
     fn status(&self) -> TxStatus {
         self.status
     }
@@ -50,7 +54,7 @@ impl TransactionHandler for Resolve {
         let tx = state
             .transactions
             .get_mut(&self.tx_id())
-            .filter(|tx| tx.tx_type() == TxType::Deposit)
+            .filter(|tx| state.policy.disputable(tx.tx_type()))
             .ok_or_else(|| TransactionError::NotFound {
                 tx_type: self.tx_type(),
                 id: self.tx_id(),
@@ -68,13 +72,14 @@ impl TransactionHandler for Resolve {
             })?;
 
         self.check_client_id_mismatch(tx.client_id())?;
+        self.check_currency_mismatch(tx.currency())?;
 
         let account = state
             .accounts
-            .get_mut(&tx.client_id())
+            .get_mut(&tx.account_id())
             .ok_or_else(|| TransactionError::AccountNotFound { id: tx.client_id() })?;
 
-        self.check_locked(account)?;
+        self.check_locked(account, &state.policy)?;
 
         let amount = tx.amount().ok_or_else(|| TransactionError::MissingAmount {
             tx_type: self.tx_type(),
@@ -82,11 +87,17 @@ impl TransactionHandler for Resolve {
         })?;
 
         tx.set_status(TxStatus::Valid);
+        trace_status(tx.as_ref());
 
         self.check_sufficient_balance(account.held, amount)?;
 
         account.held -= amount;
-        account.available += amount;
+        match tx.tx_type() {
+            // The withdrawal stands, so the held funds leave the account again
+            TxType::Withdrawal => account.total -= amount,
+            _ => account.available += amount,
+        }
+        trace_account(account);
 
         Ok(())
     }
@@ -0,0 +1,109 @@
+use crate::{
+    error::TransactionError,
+    model::{
+        Amount, ClientId, Currency, State, Transaction, TransactionExt, TransactionHandler, TxId,
+        TxStatus, TxType, trace_account,
+    },
+};
+
+#[derive(Debug, PartialEq)]
+pub struct Transfer {
+    inner: Transaction,
+    status: TxStatus,
+}
+
+impl Transfer {
+    pub fn new(tx: Transaction) -> Self {
+        Self {
+            inner: tx,
+            status: TxStatus::default(),
+        }
+    }
+}
+
+impl TransactionHandler for Transfer {
+    #[inline]
+    fn client_id(&self) -> ClientId {
+        self.inner.client_id
+    }
+    #[inline]
+    fn tx_id(&self) -> TxId {
+        self.inner.tx_id
+    }
+    #[inline]
+    fn tx_type(&self) -> TxType {
+        self.inner.tx_type
+    }
+    #[inline]
+    fn amount(&self) -> Option<Amount> {
+        self.inner.amount
+    }
+    #[inline]
+    fn currency(&self) -> Option<Currency> {
+        self.inner.currency
+    }
+    #[inline]
+    fn destination(&self) -> Option<ClientId> {
+        self.inner.destination
+    }
+    #[inline]
+    fn status(&self) -> TxStatus {
+        self.status
+    }
+    #[inline]
+    fn set_status(&mut self, state: TxStatus) {
+        self.status = state;
+    }
+    fn handle(mut self, state: &mut State) -> Result<(), TransactionError> {
+        self.check_duplicate(&state.transactions)?;
+
+        let amount = self.amount().ok_or(TransactionError::MissingAmount {
+            tx_type: self.tx_type(),
+            id: self.tx_id(),
+        })?;
+
+        self.check_positive(amount)?;
+
+        let destination = self
+            .destination()
+            .filter(|destination| *destination != self.client_id())
+            .ok_or(TransactionError::InvalidDestination {
+                id: self.tx_id(),
+                destination: self.destination(),
+            })?;
+        let destination = (destination, self.currency());
+
+        // Both accounts are checked before either is updated, so that a transfer is applied in
+        // full or not at all
+        let source = state.accounts.get(&self.account_id()).ok_or_else(|| {
+            TransactionError::AccountNotFound {
+                id: self.client_id(),
+            }
+        })?;
+        self.check_locked(source, &state.policy)?;
+        // Funds leave the source account as they would by withdrawal
+        let limits = state.policy.limits(self.client_id());
+        self.check_limits(amount, &limits, state.velocity.get(&self.account_id()))?;
+        self.check_funds(source.available, amount, limits.overdraft)?;
+
+        let target = state
+            .accounts
+            .get(&destination)
+            .ok_or(TransactionError::AccountNotFound { id: destination.0 })?;
+        self.check_locked(target, &state.policy)?;
+
+        for (id, amount) in [(self.account_id(), -amount), (destination, amount)] {
+            if let Some(account) = state.accounts.get_mut(&id) {
+                account.available += amount;
+                account.total += amount;
+                trace_account(account);
+            }
+        }
+
+        self.status = TxStatus::Valid;
+
+        state.transactions.insert(self.tx_id(), Box::new(self));
+
+        Ok(())
+    }
+}
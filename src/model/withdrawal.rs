@@ -1,8 +1,8 @@
 use crate::{
     error::TransactionError,
     model::{
-        Amount, ClientId, State, Transaction, TransactionExt, TransactionHandler, TxId, TxStatus,
-        TxType,
+        Amount, ClientId, Currency, State, Transaction, TransactionExt, TransactionHandler, TxId,
+        TxStatus, TxType, trace_account,
     },
 };
 
@@ -39,6 +39,10 @@ impl TransactionHandler for Withdrawal {
         self.inner.amount
     }
     #[inline]
+    fn currency(&self) -> Option<Currency> {
+        self.inner.currency
+    }
+    #[inline]
     fn status(&self) -> TxStatus {
         self.status
     }
@@ -56,18 +60,22 @@ impl TransactionHandler for Withdrawal {
 
         self.check_positive(amount)?;
 
-        let account = state.accounts.get_mut(&self.client_id()).ok_or_else(|| {
+        let account = state.accounts.get_mut(&self.account_id()).ok_or_else(|| {
             TransactionError::AccountNotFound {
                 id: self.client_id(),
             }
         })?;
 
-        self.check_locked(account)?;
+        self.check_locked(account, &state.policy)?;
+
+        let limits = state.policy.limits(self.client_id());
+        self.check_limits(amount, &limits, state.velocity.get(&self.account_id()))?;
 
-        self.check_sufficient_balance(account.available, amount)?;
+        self.check_funds(account.available, amount, limits.overdraft)?;
 
         account.available -= amount;
         account.total -= amount;
+        trace_account(account);
 
         self.status = TxStatus::Valid;
 
@@ -0,0 +1,590 @@
+//! Business policies consulted by the transaction handlers, loaded from a TOML config file. The
+//! defaults are the policies the engine applied before they were configurable:
+//!
+//! ```toml
+//! [disputes]
+//! types = ["deposit"]
+//! allow_negative_available = true
+//!
+//! [chargebacks]
+//! lock_account = true
+//!
+//! [locked_accounts]
+//! reject = ["deposit", "withdrawal", "dispute", "resolve", "chargeback", "transfer"]
+//! ```
+//!
+//! Withdrawals are unlimited, and accounts have no overdraft, by default. Limits apply to every
+//! Client, and tiers override them for the Clients assigned to the tier in the `client_tiers` file.
+
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::{
+    csv::parse_client_tiers,
+    error::{Error, PolicyError},
+    model::{Amount, ClientId, TxType},
+    rules::{Rule, RuleSet, RulesPolicy},
+};
+
+/// Policies of the engine, every section and key of which is optional.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// CSV file of `client,tier` rows assigning Clients to the `tiers`, relative to the config.
+    pub client_tiers: Option<PathBuf>,
+    pub disputes: DisputePolicy,
+    pub chargebacks: ChargebackPolicy,
+    pub locked_accounts: LockedAccountPolicy,
+    /// Withdrawal limits of every Client without a tier.
+    pub limits: Limits,
+    /// Withdrawal limits by tier name, falling back to `limits` for the limits a tier doesn't set.
+    pub tiers: BTreeMap<String, Limits>,
+    /// Built-in risk rules judging every transaction.
+    pub rules: RulesPolicy,
+    /// Limits of the Clients assigned to a tier.
+    #[serde(skip)]
+    clients: HashMap<ClientId, Limits>,
+    /// Risk rules registered at startup, applied after the built-in ones.
+    #[serde(skip)]
+    registered: RuleSet,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisputePolicy {
+    /// Types of the stored transactions a dispute can reference, deposits and/or withdrawals.
+    pub types: Vec<TxType>,
+    /// Whether disputing a deposit may leave the available funds negative, in which case the
+    /// chargeback fails instead.
+    pub allow_negative_available: bool,
+}
+
+impl Default for DisputePolicy {
+    fn default() -> Self {
+        Self {
+            types: vec![TxType::Deposit],
+            allow_negative_available: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChargebackPolicy {
+    /// Whether a chargeback locks the Client account.
+    pub lock_account: bool,
+}
+
+impl Default for ChargebackPolicy {
+    fn default() -> Self {
+        Self { lock_account: true }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockedAccountPolicy {
+    /// Types of the transactions rejected on a locked account.
+    pub reject: Vec<TxType>,
+}
+
+impl Default for LockedAccountPolicy {
+    fn default() -> Self {
+        Self {
+            reject: vec![
+                TxType::Deposit,
+                TxType::Withdrawal,
+                TxType::Dispute,
+                TxType::Resolve,
+                TxType::Chargeback,
+                TxType::Transfer,
+            ],
+        }
+    }
+}
+
+/// Withdrawal limits and overdraft of a Client, applied to each of its accounts separately in the
+/// units of the account's currency. Transfers out of an account count as withdrawals. Windows have
+/// no notion of time, as transactions carry no timestamps: a window covers the account's last
+/// `window` accepted transactions, the withdrawal included.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Largest single withdrawal.
+    pub max_withdrawal: Option<Amount>,
+    /// Number of the account's accepted transactions the window limits apply to.
+    pub window: Option<NonZeroU32>,
+    /// Largest sum of the withdrawals within the window.
+    pub max_window_total: Option<Amount>,
+    /// Largest number of withdrawals within the window.
+    pub max_window_count: Option<u32>,
+    /// Funds the Client can withdraw beyond its available funds, which can go as low as
+    /// `-overdraft`.
+    pub overdraft: Option<Amount>,
+}
+
+impl Limits {
+    /// Fills the limits this doesn't set from `fallback`.
+    fn or(self, fallback: Limits) -> Limits {
+        Limits {
+            max_withdrawal: self.max_withdrawal.or(fallback.max_withdrawal),
+            window: self.window.or(fallback.window),
+            max_window_total: self.max_window_total.or(fallback.max_window_total),
+            max_window_count: self.max_window_count.or(fallback.max_window_count),
+            overdraft: self.overdraft.or(fallback.overdraft),
+        }
+    }
+
+    fn validate(&self, key: &str) -> Result<(), PolicyError> {
+        for (name, limit) in [
+            ("max_withdrawal", self.max_withdrawal),
+            ("max_window_total", self.max_window_total),
+            ("overdraft", self.overdraft),
+        ] {
+            if let Some(value) = limit.filter(|value| *value <= 0. || !value.is_finite()) {
+                return Err(PolicyError::InvalidLimit {
+                    key: format!("{key}.{name}"),
+                    value,
+                });
+            }
+        }
+        let windowed = self.max_window_total.is_some() || self.max_window_count.is_some();
+        if windowed && self.window.is_none() {
+            return Err(PolicyError::MissingWindow {
+                key: key.to_owned(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Policy {
+    /// Whether a dispute can reference a stored transaction of this type.
+    pub fn disputable(&self, tx_type: TxType) -> bool {
+        self.disputes.types.contains(&tx_type)
+    }
+
+    /// Whether a locked account rejects a transaction of this type.
+    pub fn rejects_locked(&self, tx_type: TxType) -> bool {
+        self.locked_accounts.reject.contains(&tx_type)
+    }
+
+    /// Withdrawal limits of a Client, those of its tier if it has one.
+    pub fn limits(&self, client_id: ClientId) -> Limits {
+        self.clients.get(&client_id).copied().unwrap_or(self.limits)
+    }
+
+    /// Registers a risk rule, judging every transaction after the built-in rules.
+    pub fn register_rule(&mut self, rule: impl Rule + 'static) {
+        self.registered.push(Box::new(rule));
+    }
+
+    /// The built-in and registered risk rules, with no history, for a State applying the policy.
+    pub fn rules(&self) -> RuleSet {
+        let mut rules = self.rules.build();
+        rules.extend(self.registered.clone());
+        rules
+    }
+
+    /// Assigns Clients to tiers, each of which must be defined in the config.
+    pub fn assign_tiers(&mut self, tiers: Vec<(ClientId, String)>) -> Result<(), PolicyError> {
+        let mut clients = HashMap::with_capacity(tiers.len());
+        for (client, tier) in tiers {
+            let Some(limits) = self.tiers.get(&tier) else {
+                return Err(PolicyError::UnknownTier { client, tier });
+            };
+            if clients.insert(client, limits.or(self.limits)).is_some() {
+                return Err(PolicyError::DuplicateClient { client });
+            }
+        }
+        self.clients = clients;
+        Ok(())
+    }
+
+    /// Reads and validates a policy config file, along with the `client_tiers` file it names.
+    pub async fn load(path: &Path) -> Result<Self, Error> {
+        let config = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| Error::IOError {
+                filename: path.to_path_buf(),
+                source: e,
+            })?;
+        let mut policy = config.parse::<Policy>().map_err(|e| Error::ConfigError {
+            filename: path.to_path_buf(),
+            source: e,
+        })?;
+
+        if let Some(client_tiers) = &policy.client_tiers {
+            let tiers_path = path.parent().unwrap_or(Path::new("")).join(client_tiers);
+            let fp = tokio::fs::File::open(&tiers_path)
+                .await
+                .map_err(|e| Error::IOError {
+                    filename: tiers_path.clone(),
+                    source: e,
+                })?;
+            let assign = async {
+                let tiers = parse_client_tiers(fp).await?;
+                policy.assign_tiers(tiers)
+            };
+            assign.await.map_err(|e| Error::ConfigError {
+                filename: tiers_path,
+                source: e,
+            })?;
+        }
+        Ok(policy)
+    }
+
+    fn validate(&self) -> Result<(), PolicyError> {
+        if let Some(&tx_type) = self
+            .disputes
+            .types
+            .iter()
+            .find(|tx_type| !matches!(tx_type, TxType::Deposit | TxType::Withdrawal))
+        {
+            return Err(PolicyError::NotDisputable { tx_type });
+        }
+        for (key, types) in [
+            ("disputes.types", &self.disputes.types),
+            ("locked_accounts.reject", &self.locked_accounts.reject),
+        ] {
+            for (i, tx_type) in types.iter().enumerate() {
+                if types[..i].contains(tx_type) {
+                    return Err(PolicyError::Duplicate {
+                        key,
+                        tx_type: *tx_type,
+                    });
+                }
+            }
+        }
+        self.limits.validate("limits")?;
+        for (name, tier) in &self.tiers {
+            tier.or(self.limits).validate(&format!("tiers.{name}"))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Policy {
+    type Err = PolicyError;
+
+    /// Parses a policy config, rejecting unknown keys and policies the handlers can't apply.
+    fn from_str(config: &str) -> Result<Self, Self::Err> {
+        let policy = toml::from_str::<Policy>(config)?;
+        policy.validate()?;
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+    use rstest::rstest;
+
+    use super::{Limits, Policy};
+    use crate::{
+        csv::{format_account, parse_csv_lines},
+        model::{State, TxType},
+    };
+
+    /// Applies the CSV rows under the policy config, returning the codes of the rejected rows and
+    /// the final account of Client 1.
+    async fn apply(config: &str, input: &str) -> (Vec<&'static str>, String) {
+        let mut state = State::new(config.parse().unwrap());
+        let rows = parse_csv_lines(input.as_bytes())
+            .await
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let errors = rows
+            .into_iter()
+            .filter_map(|(_, tx)| state.apply(tx).err().map(|e| e.code()))
+            .collect();
+        (errors, format_account(&state.accounts[&(1, None)]))
+    }
+
+    #[rstest]
+    #[case::empty("", Policy::default())]
+    #[case::partial(
+        "[disputes]\ntypes = [\"deposit\", \"withdrawal\"]\n[chargebacks]\nlock_account = false\n",
+        {
+            let mut policy = Policy::default();
+            policy.disputes.types.push(TxType::Withdrawal);
+            policy.chargebacks.lock_account = false;
+            policy
+        }
+    )]
+    #[case::nothing_rejected("[locked_accounts]\nreject = []\n", {
+        let mut policy = Policy::default();
+        policy.locked_accounts.reject.clear();
+        policy
+    })]
+    fn test_parse(#[case] config: &str, #[case] expected: Policy) {
+        assert_eq!(config.parse::<Policy>().unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::unknown_key(
+        "[disputes]\nallow_negative = false\n",
+        "unknown field `allow_negative`"
+    )]
+    #[case::unknown_type("[disputes]\ntypes = [\"refund\"]\n", "unknown variant `refund`")]
+    #[case::wrong_type("[chargebacks]\nlock_account = \"yes\"\n", "invalid type")]
+    #[case::not_disputable(
+        "[disputes]\ntypes = [\"deposit\", \"chargeback\"]\n",
+        "`disputes.types` includes Chargeback, only deposits and withdrawals can be disputed"
+    )]
+    #[case::duplicate(
+        "[locked_accounts]\nreject = [\"deposit\", \"deposit\"]\n",
+        "`locked_accounts.reject` includes Deposit more than once"
+    )]
+    #[case::negative_limit(
+        "[tiers.gold]\nmax_withdrawal = -5\n",
+        "`tiers.gold.max_withdrawal` must be a positive amount, got -5"
+    )]
+    #[case::zero_window("[limits]\nwindow = 0\n", "nonzero")]
+    #[case::zero_overdraft(
+        "[limits]\noverdraft = 0\n",
+        "`limits.overdraft` must be a positive amount, got 0"
+    )]
+    #[case::missing_window(
+        "[limits]\nmax_window_count = 3\n",
+        "`limits` limits withdrawals over a window, but doesn't set its `window`"
+    )]
+    fn test_parse_invalid(#[case] config: &str, #[case] expected: &str) {
+        let error = config.parse::<Policy>().unwrap_err().to_string();
+
+        assert!(error.contains(expected), "{error}");
+    }
+
+    const WITHDRAWAL_DISPUTES: &str = "[disputes]\ntypes = [\"deposit\", \"withdrawal\"]\n";
+
+    #[rstest]
+    #[case::withdrawal_not_disputable("", "dispute,1,2,\n", vec!["E_TX_NOT_FOUND"], "1,60,0,60,false")]
+    #[case::withdrawal_disputed(WITHDRAWAL_DISPUTES, "dispute,1,2,\n", vec![], "1,60,40,100,false")]
+    #[case::withdrawal_resolved(
+        WITHDRAWAL_DISPUTES,
+        "dispute,1,2,\nresolve,1,2,\n",
+        vec![],
+        "1,60,0,60,false"
+    )]
+    #[case::withdrawal_charged_back(
+        WITHDRAWAL_DISPUTES,
+        "dispute,1,2,\nchargeback,1,2,\n",
+        vec![],
+        "1,100,0,100,true"
+    )]
+    #[case::negative_available(
+        "",
+        "dispute,1,1,\n",
+        vec![],
+        "1,-40,100,60,false"
+    )]
+    #[case::no_negative_available(
+        "[disputes]\nallow_negative_available = false\n",
+        "dispute,1,1,\nresolve,1,1,\n",
+        vec!["E_INSUFFICIENT_FUNDS", "E_INVALID_TX_STATE"],
+        "1,60,0,60,false"
+    )]
+    #[case::chargeback_locks(
+        "",
+        "deposit,1,3,10\ndispute,1,3,\nchargeback,1,3,\ndeposit,1,4,5\n",
+        vec!["E_ACCOUNT_LOCKED"],
+        "1,60,0,60,true"
+    )]
+    #[case::chargeback_keeps_unlocked(
+        "[chargebacks]\nlock_account = false\n",
+        "deposit,1,3,10\ndispute,1,3,\nchargeback,1,3,\ndeposit,1,4,5\n",
+        vec![],
+        "1,65,0,65,false"
+    )]
+    #[case::withdrawal_limit(
+        "[limits]\nmax_withdrawal = 25\n",
+        "withdrawal,1,3,25\n",
+        vec!["E_WITHDRAWAL_LIMIT"],
+        "1,75,0,75,false"
+    )]
+    #[case::overdraft_withdrawal(
+        "[limits]\noverdraft = 50\n",
+        "withdrawal,1,3,110\n",
+        vec![],
+        "1,-50,0,-50,false"
+    )]
+    #[case::overdraft_exceeded(
+        "[limits]\noverdraft = 50\n",
+        "withdrawal,1,3,110.5\n",
+        vec!["E_OVERDRAFT_LIMIT"],
+        "1,60,0,60,false"
+    )]
+    #[case::overdraft_dispute(
+        "[disputes]\nallow_negative_available = false\n[limits]\noverdraft = 50\n",
+        "dispute,1,1,\n",
+        vec![],
+        "1,-40,100,60,false"
+    )]
+    #[case::overdraft_chargeback(
+        "[limits]\noverdraft = 50\n",
+        "dispute,1,1,\nchargeback,1,1,\n",
+        vec![],
+        "1,-40,0,-40,true"
+    )]
+    #[case::overdraft_chargeback_exceeded(
+        "[limits]\noverdraft = 30\n",
+        "dispute,1,1,\nchargeback,1,1,\n",
+        vec!["E_OVERDRAFT_LIMIT"],
+        "1,-40,100,60,false"
+    )]
+    #[case::locked_accepts_deposits(
+        "[locked_accounts]\nreject = [\"withdrawal\"]\n",
+        "deposit,1,3,10\ndispute,1,3,\nchargeback,1,3,\ndeposit,1,4,5\nwithdrawal,1,5,1\n",
+        vec!["E_ACCOUNT_LOCKED"],
+        "1,65,0,65,true"
+    )]
+    #[case::transfer_withdrawal_limit(
+        "[limits]\nmax_withdrawal = 45\n",
+        "deposit,2,3,1\ntransfer,1,4,50,,2\n",
+        vec!["E_WITHDRAWAL_LIMIT"],
+        "1,60,0,60,false"
+    )]
+    #[case::transfer_velocity_total(
+        "[limits]\nwindow = 3\nmax_window_total = 50\n",
+        "deposit,2,3,1\ntransfer,1,4,20,,2\n",
+        vec!["E_VELOCITY_TOTAL"],
+        "1,60,0,60,false"
+    )]
+    #[case::transfer_counts_towards_window(
+        "[limits]\nwindow = 3\nmax_window_count = 2\n",
+        "deposit,2,3,1\ntransfer,1,4,10,,2\nwithdrawal,1,5,10\n",
+        vec!["E_VELOCITY_COUNT"],
+        "1,50,0,50,false"
+    )]
+    #[case::transfer_overdraft(
+        "[limits]\noverdraft = 50\n",
+        "deposit,2,3,1\ntransfer,1,4,110,,2\n",
+        vec![],
+        "1,-50,0,-50,false"
+    )]
+    #[tokio::test]
+    async fn test_apply(
+        #[case] config: &str,
+        #[case] input: &str,
+        #[case] errors: Vec<&str>,
+        #[case] account: &str,
+    ) {
+        let input = format!("type,client,tx,amount\ndeposit,1,1,100\nwithdrawal,1,2,40\n{input}");
+
+        assert_eq!(apply(config, &input).await, (errors, account.to_owned()));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_velocity() {
+        let config = "[limits]\nwindow = 3\nmax_window_count = 2\nmax_window_total = 50\n";
+        let input = indoc::indoc! {"
+            type,client,tx,amount
+            deposit,1,1,100
+            withdrawal,1,2,10
+            withdrawal,1,3,10
+            withdrawal,1,4,10
+            deposit,1,5,1
+            deposit,2,6,1
+            withdrawal,1,7,35
+            withdrawal,1,8,20
+        "};
+
+        // Rejected transactions don't count, so the window of tx 7 holds tx 3 and 5, and the
+        // window of tx 8 holds tx 5 and 7
+        assert_eq!(
+            apply(config, input).await,
+            (
+                vec!["E_VELOCITY_COUNT", "E_VELOCITY_TOTAL"],
+                "1,46,0,46,false".to_owned()
+            )
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_velocity_per_currency() {
+        let config = "[limits]\nmax_withdrawal = 40\nwindow = 3\nmax_window_total = 50\n";
+        let input = indoc::indoc! {"
+            type,client,tx,amount,currency
+            deposit,1,1,100
+            deposit,1,2,100,JPY
+            withdrawal,1,3,30
+            withdrawal,1,4,30,JPY
+            withdrawal,1,5,15,JPY
+            withdrawal,1,6,30,JPY
+            withdrawal,1,7,45,JPY
+        "};
+
+        // Each account has its own window, so tx 3 doesn't count towards the JPY withdrawals,
+        // and the single withdrawal limit applies to the amounts of every currency
+        let (errors, account) = apply(config, input).await;
+        assert_eq!(
+            (errors, account),
+            (
+                vec!["E_VELOCITY_TOTAL", "E_WITHDRAWAL_LIMIT"],
+                "1,70,0,70,false".to_owned()
+            )
+        );
+    }
+
+    #[rstest]
+    #[case::unknown(vec![(1, "gold"), (2, "silver")], Some("unknown tier `silver`"))]
+    #[case::duplicate(vec![(1, "gold"), (1, "gold")], Some("more than one tier"))]
+    #[case::valid(vec![(1, "gold")], None)]
+    fn test_assign_tiers(#[case] tiers: Vec<(u16, &str)>, #[case] expected: Option<&str>) {
+        let mut policy = "[tiers.gold]\n".parse::<Policy>().unwrap();
+
+        let res = policy.assign_tiers(
+            tiers
+                .into_iter()
+                .map(|(client, tier)| (client, tier.to_owned()))
+                .collect(),
+        );
+
+        match (res, expected) {
+            (Err(e), Some(expected)) => assert!(e.to_string().contains(expected), "{e}"),
+            (res, expected) => assert_eq!(res.is_ok(), expected.is_none()),
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_load_client_tiers() {
+        let dir = std::env::temp_dir().join(format!("txn-policy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("policy.toml"),
+            indoc::indoc! {r#"
+                client_tiers = "tiers.csv"
+
+                [limits]
+                max_withdrawal = 100
+                window = 10
+                max_window_count = 3
+
+                [tiers.gold]
+                max_withdrawal = 1000
+            "#},
+        )
+        .unwrap();
+        std::fs::write(dir.join("tiers.csv"), "client,tier\n2,gold\n").unwrap();
+
+        let policy = Policy::load(&dir.join("policy.toml")).await.unwrap();
+
+        assert_eq!(policy.limits(1), policy.limits);
+        assert_eq!(
+            policy.limits(2),
+            Limits {
+                max_withdrawal: Some(1000.),
+                ..policy.limits
+            }
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+//! Reconciliation of the balances computed from a transaction file against an expected balances
+//! file, such as the end-of-day balances provided by the bank.
+
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use csv_async::AsyncWriterBuilder;
+use serde::Serialize;
+use strum::AsRefStr;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    csv::{CURRENCY, fmt_decimals},
+    diff::{
+        Change, Comparison, Field, compare, parse_tolerance, read_accounts, replay, with_currency,
+    },
+    error::{Error, RunError},
+    model::{ClientAccount, ClientId, Currency},
+    policy::Policy,
+};
+
+/// Process a transaction file and reconcile the balances against an expected balances file
+#[derive(Debug, clap::Args)]
+pub struct ReconcileArgs {
+    /// CSV file of transactions to process
+    pub filename: PathBuf,
+    /// Expected balances, in the application's output format
+    #[arg(long)]
+    pub expected: PathBuf,
+    /// Format of the reconciliation report
+    #[arg(long, value_enum, default_value_t = ReportFormat::Csv)]
+    pub format: ReportFormat,
+    /// File to write the reconciliation report to, defaults to stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// Largest difference between a computed and an expected balance that still reconciles
+    #[arg(long, default_value_t = 0., value_parser = parse_tolerance)]
+    pub tolerance: f32,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+/// Why a Client doesn't reconcile.
+#[derive(Copy, Clone, Debug, PartialEq, AsRefStr, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Status {
+    /// The Client is expected, but has no computed account.
+    Missing,
+    /// The Client has a computed account, but isn't expected.
+    Extra,
+    /// A field of the computed account doesn't match the expected one.
+    Mismatch,
+}
+
+/// Value of a mismatched field.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    Balance(f64),
+    Locked(bool),
+}
+
+impl Value {
+    /// Balances are reported with the 4 decimal places of the output, rather than the `f32`
+    /// representation error.
+    fn balance(balance: f32) -> Self {
+        Self::Balance((f64::from(balance) * 1e4).round() / 1e4)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Balance(balance) => write!(f, "{}", fmt_decimals(*balance)),
+            Value::Locked(locked) => write!(f, "{locked}"),
+        }
+    }
+}
+
+/// A Client, or a field of a Client's account, that doesn't reconcile.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Item {
+    pub client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub status: Status,
+    pub field: Option<Field>,
+    pub expected: Option<Value>,
+    pub actual: Option<Value>,
+    /// Actual minus expected balance.
+    pub difference: Option<f64>,
+}
+
+/// Reconciliation report: counts of Clients by outcome, and the items that don't reconcile.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub clients: usize,
+    pub matched: usize,
+    pub mismatched: usize,
+    pub missing: usize,
+    pub extra: usize,
+    pub items: Vec<Item>,
+}
+
+impl From<Comparison> for Report {
+    fn from(comparison: Comparison) -> Self {
+        let mut report = Report {
+            clients: comparison.clients,
+            matched: comparison.identical,
+            ..Default::default()
+        };
+        for ((client, currency), change) in comparison.changes {
+            let item = |status| Item {
+                client,
+                currency,
+                status,
+                field: None,
+                expected: None,
+                actual: None,
+                difference: None,
+            };
+            match change {
+                Change::OnlyLeft(_) => {
+                    report.missing += 1;
+                    report.items.push(item(Status::Missing));
+                }
+                Change::OnlyRight(_) => {
+                    report.extra += 1;
+                    report.items.push(item(Status::Extra));
+                }
+                Change::Changed {
+                    left: expected,
+                    right: actual,
+                    fields,
+                } => {
+                    report.mismatched += 1;
+                    for field in fields {
+                        let (expected, actual) = values(field, &expected, &actual);
+                        let difference = match (expected, actual) {
+                            (Value::Balance(e), Value::Balance(a)) => {
+                                Some(((a - e) * 1e4).round() / 1e4)
+                            }
+                            _ => None,
+                        };
+                        report.items.push(Item {
+                            field: Some(field),
+                            expected: Some(expected),
+                            actual: Some(actual),
+                            difference,
+                            ..item(Status::Mismatch)
+                        });
+                    }
+                }
+            }
+        }
+        report
+    }
+}
+
+fn values(field: Field, expected: &ClientAccount, actual: &ClientAccount) -> (Value, Value) {
+    match field {
+        Field::Available => (
+            Value::balance(expected.available),
+            Value::balance(actual.available),
+        ),
+        Field::Held => (Value::balance(expected.held), Value::balance(actual.held)),
+        Field::Total => (Value::balance(expected.total), Value::balance(actual.total)),
+        Field::Locked => (Value::Locked(expected.locked), Value::Locked(actual.locked)),
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Reconciled {} clients: {} matched, {} mismatched, {} missing, {} extra",
+            self.clients, self.matched, self.mismatched, self.missing, self.extra
+        )
+    }
+}
+
+/// Writes the items of a report as CSV rows with a header:
+/// `client,status,field,expected,actual,difference`, followed by `currency` when any item is for
+/// an account with a currency.
+pub async fn write_csv(
+    write: impl AsyncWrite + Unpin,
+    report: &Report,
+) -> Result<(), csv_async::Error> {
+    let currencies = report.items.iter().any(|item| item.currency.is_some());
+    let mut wtr = AsyncWriterBuilder::new().create_writer(write);
+    wtr.write_record(with_currency(
+        [
+            "client",
+            "status",
+            "field",
+            "expected",
+            "actual",
+            "difference",
+        ],
+        currencies.then_some(CURRENCY),
+    ))
+    .await?;
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    for item in &report.items {
+        wtr.write_record(with_currency(
+            [
+                item.client.to_string(),
+                item.status.as_ref().to_owned(),
+                optional(item.field.map(|field| field.as_ref().to_owned())),
+                optional(item.expected.map(|value| value.to_string())),
+                optional(item.actual.map(|value| value.to_string())),
+                optional(item.difference.map(fmt_decimals)),
+            ],
+            currencies.then(|| optional(item.currency.map(|c| c.to_string()))),
+        ))
+        .await?;
+    }
+    wtr.flush().await?;
+    Ok(())
+}
+
+async fn write_report(
+    mut write: impl AsyncWrite + Unpin,
+    report: &Report,
+    format: ReportFormat,
+) -> std::io::Result<()> {
+    match format {
+        ReportFormat::Csv => write_csv(write, report).await.map_err(Into::into),
+        ReportFormat::Json => {
+            let mut json = serde_json::to_vec_pretty(report)?;
+            json.push(b'\n');
+            write.write_all(&json).await?;
+            write.flush().await
+        }
+    }
+}
+
+/// Rounds an account's balances to the 4 decimal places a run prints, which is the precision of
+/// the expected file, so that representation errors beyond it don't count as mismatches.
+fn as_output(account: &ClientAccount) -> ClientAccount {
+    let round = |balance: f32| fmt_decimals(balance).parse().unwrap_or(balance);
+    ClientAccount {
+        available: round(account.available),
+        held: round(account.held),
+        total: round(account.total),
+        ..account.clone()
+    }
+}
+
+/// Processes the transaction file across `shards` under `policy`, reconciles the balances against
+/// the expected file and writes the report, with a summary to `stderr`. Rejected transactions are
+/// skipped, as in a run. Fails when any Client doesn't reconcile.
+pub async fn run(
+    args: &ReconcileArgs,
+    shards: NonZeroUsize,
+    policy: &Policy,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let expected = read_accounts(&args.expected).await?;
+    let actual = replay(&args.filename, shards, policy)
+        .await?
+        .iter()
+        .map(as_output)
+        .collect::<Vec<_>>();
+
+    let report = Report::from(compare(&expected, &actual, args.tolerance));
+    match &args.output {
+        Some(path) => {
+            let write = async {
+                let fp = tokio::fs::File::create(path).await?;
+                write_report(fp, &report, args.format).await
+            };
+            write.await.map_err(|e| Error::WriteError {
+                filename: path.clone(),
+                source: e,
+            })?
+        }
+        None => write_report(tokio::io::stdout(), &report, args.format).await?,
+    }
+    eprintln!("{report}");
+
+    match report.clients - report.matched {
+        0 => Ok(()),
+        changes => Err(RunError::Differences { changes }.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use serde_json::json;
+
+    use super::{Report, as_output, write_csv};
+    use crate::{csv::parse_accounts, diff::compare, model::ClientAccount};
+
+    async fn report() -> Report {
+        let expected =
+            parse_accounts(b"1,10,0,10,false\n2,5,0,5,false\n3,1,0,1,false\n".as_slice())
+                .await
+                .unwrap();
+        let actual =
+            parse_accounts(b"1,10,0,10,false\n2,4.5,0,4.5,true\n4,1,0,1,false\n".as_slice())
+                .await
+                .unwrap();
+        Report::from(compare(&expected, &actual, 0.))
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_report_csv() {
+        let report = report().await;
+        let mut out = Vec::new();
+
+        write_csv(&mut out, &report).await.unwrap();
+
+        assert_eq!(
+            report.to_string(),
+            "Reconciled 4 clients: 1 matched, 1 mismatched, 1 missing, 1 extra"
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {"
+                client,status,field,expected,actual,difference
+                2,mismatch,available,5,4.5,-0.5
+                2,mismatch,total,5,4.5,-0.5
+                2,mismatch,locked,false,true,
+                3,missing,,,,
+                4,extra,,,,
+            "}
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_report_csv_precision() {
+        let expected = parse_accounts(b"1,1000000,0,1000000,false\n".as_slice())
+            .await
+            .unwrap();
+        let actual = parse_accounts(b"1,0.0001,0,1000000,false\n".as_slice())
+            .await
+            .unwrap();
+        let report = Report::from(compare(&expected, &actual, 0.));
+        let mut out = Vec::new();
+
+        write_csv(&mut out, &report).await.unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {"
+                client,status,field,expected,actual,difference
+                1,mismatch,available,1000000,0.0001,-999999.9999
+            "}
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_as_output() {
+        let expected = parse_accounts(b"1,604.4717,0.3,604.7717,false\n".as_slice())
+            .await
+            .unwrap();
+        let computed = ClientAccount {
+            client_id: 1,
+            available: 600.1 + 4.37174,
+            held: 0.1 + 0.2,
+            total: 604.47174 + 0.3,
+            locked: false,
+            currency: None,
+        };
+
+        let report = Report::from(compare(&expected, &[as_output(&computed)], 0.));
+
+        assert_eq!(report.matched, 1);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_report_json() {
+        let report = report().await;
+
+        let json = serde_json::to_value(&report).unwrap();
+
+        assert_eq!(
+            json,
+            json!({
+                "clients": 4,
+                "matched": 1,
+                "mismatched": 1,
+                "missing": 1,
+                "extra": 1,
+                "items": [
+                    {"client": 2, "status": "mismatch", "field": "available", "expected": 5.0, "actual": 4.5, "difference": -0.5},
+                    {"client": 2, "status": "mismatch", "field": "total", "expected": 5.0, "actual": 4.5, "difference": -0.5},
+                    {"client": 2, "status": "mismatch", "field": "locked", "expected": false, "actual": true, "difference": null},
+                    {"client": 3, "status": "missing", "field": null, "expected": null, "actual": null, "difference": null},
+                    {"client": 4, "status": "extra", "field": null, "expected": null, "actual": null, "difference": null},
+                ]
+            })
+        );
+    }
+}
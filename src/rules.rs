@@ -0,0 +1,489 @@
+//! Risk rules judging every transaction before its handler applies it. A rule approves, rejects
+//! or flags a transaction for review, and can keep a history of the transactions the engine
+//! accepted. The built-in rules are enabled in the `[rules]` section of the policy config, and
+//! further rules are registered at startup with
+//! [`Policy::register_rule`](crate::policy::Policy::register_rule):
+//!
+//! ```toml
+//! [rules.quick_withdrawal]
+//! within = 5
+//! action = "flag"
+//!
+//! [rules.disputes]
+//! max = 3
+//! action = "reject"
+//! ```
+//!
+//! Each State applying the policy gets its own copy of the rules. A sharded run applies the
+//! transactions of a Client to the State owning its account, so rules only see the accounts and
+//! the history of the Clients of their shard.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::num::NonZeroU32;
+
+use serde::Deserialize;
+
+use crate::{
+    error::TransactionError,
+    model::{AccountId, Amount, ClientId, State, Transaction, TxType},
+};
+
+/// How a rule judges a transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Approve,
+    /// Applies the transaction, and queues it for review with the reason.
+    Flag(String),
+    /// Rejects the transaction with the reason, before its handler applies it.
+    Reject(String),
+}
+
+/// What a built-in rule does with the transactions it matches.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Flag,
+    Reject,
+}
+
+impl Action {
+    fn verdict(self, reason: String) -> Verdict {
+        match self {
+            Action::Flag => Verdict::Flag(reason),
+            Action::Reject => Verdict::Reject(reason),
+        }
+    }
+}
+
+pub trait Rule: Send + Sync + fmt::Debug {
+    /// Name of the rule in rejections and the review queue.
+    fn name(&self) -> &'static str;
+
+    /// Judges a transaction against the State before its handler applies it.
+    fn check(&self, tx: &Transaction, state: &State) -> Verdict;
+
+    /// Records a transaction the engine accepted, for rules judging a Client's history.
+    fn accepted(&mut self, _tx: &Transaction) {}
+
+    /// Copies the rule, as rules are copied into every State applying the policy.
+    fn clone_box(&self) -> Box<dyn Rule>;
+}
+
+/// A transaction flagged for review by a rule. Only transactions the engine accepted are flagged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Flag {
+    pub tx: Transaction,
+    pub rule: &'static str,
+    pub reason: String,
+}
+
+/// Rules applied in the order they were registered. The first rule rejecting a transaction rejects
+/// it, whereas every rule flagging it adds a [`Flag`].
+#[derive(Default)]
+pub struct RuleSet(Vec<Box<dyn Rule>>);
+
+impl RuleSet {
+    pub fn push(&mut self, rule: Box<dyn Rule>) {
+        self.0.push(rule);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns a RuleRejected error if a rule rejects the transaction, or the flags it raised.
+    pub fn check(&self, tx: &Transaction, state: &State) -> Result<Vec<Flag>, TransactionError> {
+        let mut flags = Vec::new();
+        for rule in &self.0 {
+            match rule.check(tx, state) {
+                Verdict::Approve => {}
+                Verdict::Flag(reason) => flags.push(Flag {
+                    tx: tx.clone(),
+                    rule: rule.name(),
+                    reason,
+                }),
+                Verdict::Reject(reason) => {
+                    return Err(TransactionError::RuleRejected {
+                        id: tx.tx_id,
+                        rule: rule.name(),
+                        reason,
+                    });
+                }
+            }
+        }
+        Ok(flags)
+    }
+
+    pub fn accepted(&mut self, tx: &Transaction) {
+        for rule in &mut self.0 {
+            rule.accepted(tx);
+        }
+    }
+}
+
+impl Clone for RuleSet {
+    fn clone(&self) -> Self {
+        Self(self.0.iter().map(|rule| rule.clone_box()).collect())
+    }
+}
+
+impl Extend<Box<dyn Rule>> for RuleSet {
+    fn extend<I: IntoIterator<Item = Box<dyn Rule>>>(&mut self, rules: I) {
+        self.0.extend(rules);
+    }
+}
+
+impl IntoIterator for RuleSet {
+    type Item = Box<dyn Rule>;
+    type IntoIter = std::vec::IntoIter<Box<dyn Rule>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl fmt::Debug for RuleSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|rule| rule.name()))
+            .finish()
+    }
+}
+
+/// Rule sets are equal when they apply the same rules in the same order.
+impl PartialEq for RuleSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .map(|rule| rule.name())
+            .eq(other.0.iter().map(|rule| rule.name()))
+    }
+}
+
+/// Built-in rules, each of which is disabled unless its section is set.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesPolicy {
+    pub quick_withdrawal: Option<QuickWithdrawal>,
+    pub disputes: Option<DisputeCount>,
+}
+
+impl RulesPolicy {
+    /// The enabled built-in rules.
+    pub fn build(&self) -> RuleSet {
+        let mut rules = RuleSet::default();
+        if let Some(rule) = &self.quick_withdrawal {
+            rules.push(rule.clone_box());
+        }
+        if let Some(rule) = &self.disputes {
+            rules.push(rule.clone_box());
+        }
+        rules
+    }
+}
+
+/// Matches a withdrawal taking out at least the amount of a deposit within the account's last
+/// `within` accepted transactions, the withdrawal included, as when funds are moved straight
+/// through the account. Transfers out of the account are judged as withdrawals. Only deposits in
+/// the withdrawal's currency are compared with it.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct QuickWithdrawal {
+    pub within: NonZeroU32,
+    #[serde(default)]
+    pub action: Action,
+    /// Number of each account's accepted transactions, and the position and amount of its
+    /// deposits within the last `within` of them, oldest first.
+    #[serde(skip)]
+    accounts: HashMap<AccountId, (u64, VecDeque<(u64, Amount)>)>,
+}
+
+impl Rule for QuickWithdrawal {
+    fn name(&self) -> &'static str {
+        "quick_withdrawal"
+    }
+
+    fn check(&self, tx: &Transaction, _state: &State) -> Verdict {
+        let (Some(amount), Some((_, deposits))) = (
+            tx.amount
+                .filter(|_| matches!(tx.tx_type, TxType::Withdrawal | TxType::Transfer)),
+            self.accounts.get(&(tx.client_id, tx.currency)),
+        ) else {
+            return Verdict::Approve;
+        };
+        match deposits.iter().find(|(_, deposit)| amount >= *deposit) {
+            Some((_, deposit)) => self.action.verdict(format!(
+                "{} of {amount} follows a deposit of {deposit} within {} transactions",
+                tx.tx_type.as_ref().to_lowercase(),
+                self.within
+            )),
+            None => Verdict::Approve,
+        }
+    }
+
+    fn accepted(&mut self, tx: &Transaction) {
+        let (transactions, deposits) = self
+            .accounts
+            .entry((tx.client_id, tx.currency))
+            .or_default();
+        *transactions += 1;
+        if let Some(amount) = tx.amount.filter(|_| tx.tx_type == TxType::Deposit) {
+            deposits.push_back((*transactions, amount));
+        }
+        // The withdrawal being judged is the last transaction of the window
+        let start = transactions.saturating_sub(u64::from(self.within.get() - 1));
+        while deposits
+            .front()
+            .is_some_and(|(position, _)| *position <= start)
+        {
+            deposits.pop_front();
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Rule> {
+        Box::new(self.clone())
+    }
+}
+
+/// Matches a dispute that takes the Client over `max` accepted disputes.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DisputeCount {
+    pub max: u32,
+    #[serde(default)]
+    pub action: Action,
+    /// Number of each Client's accepted disputes.
+    #[serde(skip)]
+    disputes: HashMap<ClientId, u32>,
+}
+
+impl Rule for DisputeCount {
+    fn name(&self) -> &'static str {
+        "disputes"
+    }
+
+    fn check(&self, tx: &Transaction, _state: &State) -> Verdict {
+        let disputes = self
+            .disputes
+            .get(&tx.client_id)
+            .copied()
+            .unwrap_or_default();
+        if tx.tx_type == TxType::Dispute && disputes >= self.max {
+            self.action.verdict(format!(
+                "Client already has {disputes} disputes, the limit is {}",
+                self.max
+            ))
+        } else {
+            Verdict::Approve
+        }
+    }
+
+    fn accepted(&mut self, tx: &Transaction) {
+        if tx.tx_type == TxType::Dispute {
+            *self.disputes.entry(tx.client_id).or_default() += 1;
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Rule> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+    use rstest::rstest;
+
+    use super::{Rule, Verdict};
+    use crate::{
+        csv::parse_csv_lines,
+        model::{State, Transaction},
+        policy::Policy,
+    };
+
+    /// Rejects every transaction of a Client.
+    #[derive(Clone, Debug)]
+    struct BlockClient(u16);
+
+    impl Rule for BlockClient {
+        fn name(&self) -> &'static str {
+            "block_client"
+        }
+
+        fn check(&self, tx: &Transaction, _state: &State) -> Verdict {
+            if tx.client_id == self.0 {
+                Verdict::Reject("Client is blocked".to_owned())
+            } else {
+                Verdict::Approve
+            }
+        }
+
+        fn clone_box(&self) -> Box<dyn Rule> {
+            Box::new(self.clone())
+        }
+    }
+
+    /// Applies the CSV rows under the policy, returning the codes of the rejected rows, and the
+    /// rule and transaction id of the flagged rows.
+    async fn apply(policy: Policy, input: &str) -> (Vec<&'static str>, Vec<(&'static str, u32)>) {
+        let mut state = State::new(policy);
+        let rows = parse_csv_lines(input.as_bytes())
+            .await
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let errors = rows
+            .into_iter()
+            .filter_map(|(_, tx)| state.apply(tx).err().map(|e| e.code()))
+            .collect();
+        let flagged = state
+            .flagged
+            .iter()
+            .map(|flag| (flag.rule, flag.tx.tx_id))
+            .collect();
+        (errors, flagged)
+    }
+
+    const INPUT: &str = indoc::indoc! {"
+        type,client,tx,amount
+        deposit,1,1,100
+        deposit,2,2,50
+        withdrawal,1,3,100
+        deposit,1,4,20
+        deposit,1,5,50
+        withdrawal,1,6,10
+        withdrawal,1,7,20
+        dispute,1,1,
+        resolve,1,1,
+        dispute,1,4,
+        dispute,1,5,
+        dispute,2,2,
+    "};
+
+    #[rstest]
+    #[case::none("", vec![], vec![])]
+    #[case::quick_withdrawal(
+        "[rules.quick_withdrawal]\nwithin = 2\n",
+        vec![],
+        vec![("quick_withdrawal", 3)]
+    )]
+    #[case::quick_withdrawal_wider_window(
+        "[rules.quick_withdrawal]\nwithin = 4\n",
+        vec![],
+        vec![("quick_withdrawal", 3), ("quick_withdrawal", 7)]
+    )]
+    #[case::quick_withdrawal_rejected(
+        "[rules.quick_withdrawal]\nwithin = 2\naction = \"reject\"\n",
+        vec!["E_RULE_REJECTED"],
+        vec![]
+    )]
+    #[case::disputes(
+        "[rules.disputes]\nmax = 2\n",
+        vec![],
+        vec![("disputes", 5)]
+    )]
+    #[case::disputes_rejected(
+        "[rules.disputes]\nmax = 2\naction = \"reject\"\n",
+        vec!["E_RULE_REJECTED"],
+        vec![]
+    )]
+    #[tokio::test]
+    async fn test_built_in_rules(
+        #[case] config: &str,
+        #[case] errors: Vec<&str>,
+        #[case] flagged: Vec<(&str, u32)>,
+    ) {
+        assert_eq!(
+            apply(config.parse().unwrap(), INPUT).await,
+            (errors, flagged)
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_quick_withdrawal_per_currency() {
+        let input = indoc::indoc! {"
+            type,client,tx,amount,currency
+            deposit,1,1,100,EUR
+            deposit,1,2,500
+            withdrawal,1,3,100
+            withdrawal,1,4,100,EUR
+        "};
+        let policy = "[rules.quick_withdrawal]\nwithin = 3\n".parse().unwrap();
+
+        // Tx 3 is only compared with the deposit in its own currency, whereas tx 4 follows the
+        // EUR deposit within the last 3 transactions of the EUR account
+        assert_eq!(
+            apply(policy, input).await,
+            (vec![], vec![("quick_withdrawal", 4)])
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_quick_transfer() {
+        let input = indoc::indoc! {"
+            type,client,tx,amount,currency,destination
+            deposit,1,1,100
+            deposit,2,2,500
+            transfer,1,3,100,,2
+            transfer,2,4,100,,1
+        "};
+        let policy = "[rules.quick_withdrawal]\nwithin = 2\n".parse().unwrap();
+
+        let mut state = State::new(policy);
+        for (_, tx) in parse_csv_lines(input.as_bytes())
+            .await
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+        {
+            state.apply(tx).unwrap();
+        }
+
+        // The funds Client 2 received aren't a deposit, so its transfer only compares with tx 2
+        assert_eq!(
+            state
+                .flagged
+                .iter()
+                .map(|flag| (flag.tx.tx_id, flag.reason.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(
+                3,
+                "transfer of 100 follows a deposit of 100 within 2 transactions"
+            )]
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_registered_rule() {
+        let mut policy = "[rules.disputes]\nmax = 0\n".parse::<Policy>().unwrap();
+        policy.register_rule(BlockClient(2));
+
+        let (errors, flagged) = apply(policy, INPUT).await;
+
+        // Client 2's deposit and dispute are both rejected, so it never gets an account, and the
+        // disputes rule's flag on its dispute is dropped along with the rejected row
+        assert_eq!(errors, vec!["E_RULE_REJECTED", "E_RULE_REJECTED"]);
+        assert_eq!(
+            flagged,
+            vec![("disputes", 1), ("disputes", 4), ("disputes", 5)]
+        );
+    }
+
+    #[rstest]
+    #[case::unknown_rule("[rules.velocity]\nmax = 1\n", "unknown field `velocity`")]
+    #[case::unknown_action(
+        "[rules.disputes]\nmax = 1\naction = \"block\"\n",
+        "unknown variant `block`"
+    )]
+    #[case::zero_window("[rules.quick_withdrawal]\nwithin = 0\n", "nonzero")]
+    fn test_parse_invalid(#[case] config: &str, #[case] expected: &str) {
+        let error = config.parse::<Policy>().unwrap_err().to_string();
+
+        assert!(error.contains(expected), "{error}");
+    }
+}
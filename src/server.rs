@@ -0,0 +1,257 @@
+//! Long-running server mode, ingesting transactions from concurrent connections into one shared
+//! engine.
+
+use std::net::SocketAddr;
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+use crate::{
+    csv::format_account,
+    error::{Error, TransactionError},
+    model::{ClientAccount, ClientId, Currency, State, Transaction, TxId, TxStatus},
+    policy::Policy,
+    server::metrics::{Gauges, Metrics},
+};
+
+#[cfg(unix)]
+pub mod control;
+pub mod http;
+pub mod metrics;
+pub mod tcp;
+
+/// Run a server ingesting transactions over TCP and, optionally, HTTP
+#[derive(Debug, clap::Args)]
+pub struct ServeArgs {
+    /// Address to accept TCP connections on
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    pub listen: SocketAddr,
+    /// Address to serve the HTTP/JSON API on
+    #[arg(long)]
+    pub http: Option<SocketAddr>,
+    /// Path of a Unix domain socket to accept admin commands on
+    #[cfg(unix)]
+    #[arg(long)]
+    pub control: Option<PathBuf>,
+    /// File to write Prometheus metrics to periodically, for node-exporter's textfile collector
+    #[arg(long)]
+    pub metrics_file: Option<PathBuf>,
+    /// Seconds between writes of the metrics file
+    #[arg(long, default_value = "15")]
+    pub metrics_interval: NonZeroU64,
+}
+
+/// Shared handle to the State, applying transactions from every connection in the order they
+/// arrive.
+#[derive(Clone, Default)]
+pub struct Engine {
+    state: Arc<Mutex<State>>,
+    control: Arc<Control>,
+}
+
+/// Runtime settings and counters, adjustable over the control socket.
+#[derive(Default)]
+struct Control {
+    verbose: AtomicBool,
+    paused: watch::Sender<bool>,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    metrics: Metrics,
+}
+
+/// Snapshot of the engine's counters.
+#[derive(Debug, PartialEq)]
+pub struct Counters {
+    pub accepted: u64,
+    pub rejected: u64,
+    pub accounts: usize,
+    pub locked: usize,
+    pub transactions: usize,
+    pub paused: bool,
+    pub verbose: bool,
+}
+
+impl Engine {
+    /// Creates an engine with an empty State applying `policy`, logging rejected transactions to
+    /// `stderr` if `verbose`.
+    pub fn new(verbose: bool, policy: Policy) -> Self {
+        let engine = Self {
+            state: Arc::new(Mutex::new(State::new(policy))),
+            ..Default::default()
+        };
+        engine.set_verbose(verbose);
+        engine
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Handlers don't panic halfway through a mutation, so the State is consistent even if
+        // another connection panicked while holding the lock
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Applies a transaction to the shared State, first waiting for ingestion to be resumed if it
+    /// is paused.
+    pub async fn apply(&self, tx: Transaction) -> Result<(), TransactionError> {
+        let mut paused = self.control.paused.subscribe();
+        // The sender lives as long as the engine, so this only returns once resumed
+        let _ = paused.wait_for(|paused| !paused).await;
+
+        let tx_type = tx.tx_type;
+        let mut state = self.lock();
+        let started = Instant::now();
+        let res = state.apply(tx);
+        let elapsed = started.elapsed();
+        // Flagged transactions are only traced, as a server has no review queue file to write
+        state.flagged.clear();
+        drop(state);
+
+        self.control
+            .metrics
+            .record(tx_type, res.as_ref().err(), elapsed);
+        match &res {
+            Ok(()) => self.control.accepted.fetch_add(1, Ordering::Relaxed),
+            Err(e) => {
+                if self.verbose() {
+                    eprintln!("{e}");
+                }
+                self.control.rejected.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+        res
+    }
+
+    /// Pauses or resumes ingestion. Queries are still answered while paused, and transactions
+    /// already being applied complete.
+    pub fn set_paused(&self, paused: bool) {
+        self.control.paused.send_replace(paused);
+    }
+
+    pub fn paused(&self) -> bool {
+        *self.control.paused.borrow()
+    }
+
+    /// Turns logging of rejected transactions to `stderr` on or off.
+    pub fn set_verbose(&self, verbose: bool) {
+        self.control.verbose.store(verbose, Ordering::Relaxed);
+    }
+
+    pub fn verbose(&self) -> bool {
+        self.control.verbose.load(Ordering::Relaxed)
+    }
+
+    pub fn counters(&self) -> Counters {
+        let state = self.lock();
+        Counters {
+            accepted: self.control.accepted.load(Ordering::Relaxed),
+            rejected: self.control.rejected.load(Ordering::Relaxed),
+            accounts: state.accounts.len(),
+            locked: state.accounts.values().filter(|a| a.locked).count(),
+            transactions: state.transactions.len(),
+            paused: self.paused(),
+            verbose: self.verbose(),
+        }
+    }
+
+    /// Renders the engine's metrics in the Prometheus text exposition format.
+    pub fn metrics(&self) -> String {
+        let gauges = Gauges::read(&self.lock());
+        self.control.metrics.render(&gauges)
+    }
+
+    /// Returns a copy of a Client's account in `currency`, or its account without a currency.
+    pub fn account(
+        &self,
+        client_id: ClientId,
+        currency: Option<Currency>,
+    ) -> Option<ClientAccount> {
+        self.lock().accounts.get(&(client_id, currency)).cloned()
+    }
+
+    /// Returns a copy of every Client account, ordered by Client Id, then currency.
+    pub fn accounts(&self) -> Vec<ClientAccount> {
+        let mut accounts = self.lock().accounts.values().cloned().collect::<Vec<_>>();
+        accounts.sort_by_key(ClientAccount::id);
+        accounts
+    }
+
+    /// Returns a stored deposit or withdrawal along with its current status.
+    pub fn transaction(&self, tx_id: TxId) -> Option<(Transaction, TxStatus)> {
+        self.lock().transactions.get(&tx_id).map(|tx| {
+            (
+                Transaction {
+                    tx_type: tx.tx_type(),
+                    client_id: tx.client_id(),
+                    tx_id: tx.tx_id(),
+                    amount: tx.amount(),
+                    currency: tx.currency(),
+                    destination: tx.destination(),
+                },
+                tx.status(),
+            )
+        })
+    }
+}
+
+/// Accepts connections on every configured protocol until interrupted with Ctrl-C, then prints the
+/// final account states to `stdout`, as a file run would.
+pub async fn run(args: &ServeArgs, verbose: bool, policy: Policy) -> Result<(), Error> {
+    let engine = Engine::new(verbose, policy);
+
+    let listener = bind(args.listen).await?;
+    eprintln!("Listening on {}", args.listen);
+    tokio::spawn(tcp::serve(listener, engine.clone()));
+
+    if let Some(address) = args.http {
+        let listener = bind(address).await?;
+        eprintln!("Serving HTTP API on {address}");
+        tokio::spawn(http::serve(listener, engine.clone()));
+    }
+
+    if let Some(path) = &args.metrics_file {
+        let interval = Duration::from_secs(args.metrics_interval.get());
+        tokio::spawn(metrics::write_periodically(
+            engine.clone(),
+            path.clone(),
+            interval,
+        ));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &args.control {
+        let listener =
+            tokio::net::UnixListener::bind(path).map_err(|e| Error::SocketBindError {
+                path: path.clone(),
+                source: e,
+            })?;
+        eprintln!("Accepting admin commands on {}", path.display());
+        tokio::spawn(control::serve(listener, engine.clone()));
+    }
+
+    let interrupted = tokio::signal::ctrl_c()
+        .await
+        .map_err(|e| Error::SignalError { source: e });
+
+    // The socket file outlives the listener, and would make the next bind fail
+    #[cfg(unix)]
+    if let Some(path) = &args.control {
+        let _ = std::fs::remove_file(path);
+    }
+    interrupted?;
+
+    for account in engine.accounts() {
+        println!("{}", format_account(&account));
+    }
+
+    Ok(())
+}
+
+async fn bind(address: SocketAddr) -> Result<TcpListener, Error> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|e| Error::BindError { address, source: e })
+}
@@ -0,0 +1,279 @@
+//! Line-based admin protocol on a Unix domain socket, for operators on the same host. Every line
+//! received is a command:
+//!
+//!  - `dump` replies with a `client,available,held,total,locked` row for every Client, ordered by
+//!    Client Id, followed by `end`.
+//!  - `snapshot <path>` writes the same rows to a file, as a file run would print them, and replies
+//!    `ok`. Relative paths are resolved against the server's working directory.
+//!  - `pause` and `resume` stop and restart ingestion over every other protocol, replying `ok`.
+//!    Queries are still answered while paused.
+//!  - `verbose` toggles logging of rejected transactions to `stderr`, and `verbose on` or
+//!    `verbose off` sets it, replying `verbose,<true|false>`.
+//!  - `counters` replies with a `<name>,<value>` row for every counter, followed by `end`.
+//!
+//! Failed and unknown commands are answered with `error,<reason>`.
+
+use std::path::Path;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::UnixListener;
+use tracing::Instrument;
+
+use crate::{csv::format_account, error::Error, server::Engine};
+
+/// Accepts connections forever, handling each one on its own task.
+pub async fn serve(listener: UnixListener, engine: Engine) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                let engine = engine.clone();
+                tokio::spawn(
+                    async move {
+                        let (read, write) = socket.into_split();
+                        if let Err(e) = handle_connection(&engine, read, write).await
+                            && engine.verbose()
+                        {
+                            eprintln!("Control connection failed: {e}");
+                        }
+                    }
+                    .instrument(tracing::info_span!("control")),
+                );
+            }
+            Err(e) => eprintln!("Couldn't accept control connection: {e}"),
+        }
+    }
+}
+
+/// Replies to every command received on a connection until the client hangs up.
+pub async fn handle_connection(
+    engine: &Engine,
+    read: impl AsyncRead + Unpin,
+    write: impl AsyncWrite + Unpin,
+) -> std::io::Result<()> {
+    let mut lines = BufReader::new(read).lines();
+    let mut writer = BufWriter::new(write);
+
+    while let Some(line) = lines.next_line().await? {
+        tracing::info!(command = line.trim(), "admin command");
+        let reply = respond(engine, line.trim()).await;
+        writer.write_all(reply.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+        writer.flush().await?;
+    }
+
+    Ok(())
+}
+
+async fn respond(engine: &Engine, line: &str) -> String {
+    let (command, argument) = match line.split_once(char::is_whitespace) {
+        Some((command, argument)) => (command, Some(argument.trim())),
+        None => (line, None),
+    };
+
+    match (command, argument) {
+        ("dump", None) => dump(engine) + "end",
+        ("snapshot", Some(path)) if !path.is_empty() => match snapshot(engine, path).await {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => format!("error,{e}"),
+        },
+        ("pause", None) => {
+            engine.set_paused(true);
+            "ok".to_owned()
+        }
+        ("resume", None) => {
+            engine.set_paused(false);
+            "ok".to_owned()
+        }
+        ("verbose", None | Some("on" | "off")) => {
+            let verbose = argument.map_or(!engine.verbose(), |arg| arg == "on");
+            engine.set_verbose(verbose);
+            format!("verbose,{verbose}")
+        }
+        ("counters", None) => {
+            let counters = engine.counters();
+            format!(
+                "accepted,{}\nrejected,{}\naccounts,{}\nlocked,{}\ntransactions,{}\npaused,{}\nverbose,{}\nend",
+                counters.accepted,
+                counters.rejected,
+                counters.accounts,
+                counters.locked,
+                counters.transactions,
+                counters.paused,
+                counters.verbose,
+            )
+        }
+        _ => format!("error,Unknown command '{line}'"),
+    }
+}
+
+fn dump(engine: &Engine) -> String {
+    engine
+        .accounts()
+        .iter()
+        .map(|account| format_account(account) + "\n")
+        .collect()
+}
+
+/// Writes the account rows next to the destination first, so that an existing snapshot is only
+/// ever replaced by a complete one.
+async fn snapshot(engine: &Engine, path: impl AsRef<Path>) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+
+    let write = async {
+        tokio::fs::write(&partial, dump(engine)).await?;
+        tokio::fs::rename(&partial, path).await
+    };
+    write.await.map_err(|e| Error::WriteError {
+        filename: path.to_path_buf(),
+        source: e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rstest::rstest;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+
+    use super::{handle_connection, serve};
+    use crate::model::{Amount, ClientId, Transaction, TxId, TxType};
+    use crate::server::Engine;
+
+    /// Sends the commands on a single connection and returns the lines received in reply.
+    async fn exchange(engine: &Engine, input: &str) -> Vec<String> {
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_read, server_write) = tokio::io::split(server);
+        let (client_read, mut client_write) = tokio::io::split(client);
+
+        let server = handle_connection(engine, server_read, server_write);
+        let client = async {
+            client_write.write_all(input.as_bytes()).await.unwrap();
+            client_write.shutdown().await.unwrap();
+
+            let mut replies = Vec::new();
+            let mut lines = BufReader::new(client_read).lines();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                replies.push(line);
+            }
+            replies
+        };
+
+        let (res, replies) = tokio::join!(server, client);
+        res.expect("Connection failed");
+        replies
+    }
+
+    fn deposit(client_id: ClientId, tx_id: TxId, amount: Amount) -> Transaction {
+        Transaction {
+            tx_type: TxType::Deposit,
+            client_id,
+            tx_id,
+            amount: Some(amount),
+            currency: None,
+            destination: None,
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_dump_and_counters() {
+        let engine = Engine::default();
+        engine.apply(deposit(2, 1, 10.5)).await.unwrap();
+        engine.apply(deposit(1, 2, 1.)).await.unwrap();
+        engine.apply(deposit(1, 2, 1.)).await.unwrap_err();
+
+        let replies = exchange(&engine, "dump\ncounters\n").await;
+
+        assert_eq!(
+            replies,
+            vec![
+                "1,1,0,1,false",
+                "2,10.5,0,10.5,false",
+                "end",
+                "accepted,2",
+                "rejected,1",
+                "accounts,2",
+                "locked,0",
+                "transactions,2",
+                "paused,false",
+                "verbose,false",
+                "end",
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case::toggle("verbose\nverbose\n", vec!["verbose,true", "verbose,false"], false)]
+    #[case::on("verbose on\nverbose on\n", vec!["verbose,true", "verbose,true"], true)]
+    #[case::off("verbose off\n", vec!["verbose,false"], false)]
+    #[case::unknown("verbose maybe\nrestart\nsnapshot\n", vec![
+        "error,Unknown command 'verbose maybe'",
+        "error,Unknown command 'restart'",
+        "error,Unknown command 'snapshot'",
+    ], false)]
+    #[tokio::test]
+    async fn test_verbose(#[case] input: &str, #[case] expected: Vec<&str>, #[case] verbose: bool) {
+        let engine = Engine::default();
+
+        let replies = exchange(&engine, input).await;
+
+        assert_eq!(replies, expected);
+        assert_eq!(engine.verbose(), verbose);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_snapshot() {
+        let dir = std::env::temp_dir().join(format!("txn-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("balances.csv");
+        let engine = Engine::default();
+        engine.apply(deposit(1, 1, 5.)).await.unwrap();
+
+        let replies = exchange(&engine, &format!("snapshot {}\n", path.display())).await;
+        let missing = exchange(
+            &engine,
+            &format!("snapshot {}\n", dir.join("a/b").display()),
+        )
+        .await;
+
+        assert_eq!(replies, vec!["ok"]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1,5,0,5,false\n");
+        assert!(missing[0].starts_with("error,Couldn't write to file"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[rstest]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_pause_resume() {
+        let path = std::env::temp_dir().join(format!("txn-control-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let engine = Engine::default();
+        tokio::spawn(serve(listener, engine.clone()));
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut replies = BufReader::new(read).lines();
+        write.write_all(b"pause\n").await.unwrap();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "ok");
+
+        let pending = tokio::spawn({
+            let engine = engine.clone();
+            async move { engine.apply(deposit(1, 1, 5.)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!pending.is_finished());
+        assert!(engine.accounts().is_empty());
+
+        write.write_all(b"resume\n").await.unwrap();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "ok");
+        pending.await.unwrap().unwrap();
+        assert_eq!(engine.counters().accepted, 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+//! HTTP/JSON API over the shared engine:
+//!
+//!  - `POST /transactions` applies a single transaction, or an array of transactions in order.
+//!  - `GET /transactions/{tx}` returns a stored deposit or withdrawal and its status.
+//!  - `GET /accounts` returns every Client account, ordered by Client Id.
+//!  - `GET /accounts/{client}` returns a single Client account, and
+//!    `GET /accounts/{client}/{currency}` the Client's account in a currency.
+//!
+//! Transactions use the CSV column names, e.g. `{"type": "deposit", "client": 1, "tx": 1,
+//! "amount": 100.0}`. Errors are returned as a JSON body with a human readable `message` and the
+//! stable `code` of the error, along with the fields of a [`TransactionError`].
+
+use axum::{
+    Json, Router,
+    extract::{Path, Request, State, rejection::JsonRejection},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tracing::Instrument;
+
+use crate::{
+    error::TransactionError,
+    model::{ClientAccount, ClientId, Currency, Transaction, TxId, TxStatus},
+    server::Engine,
+};
+
+/// Body of a `POST /transactions` request.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Submission {
+    Batch(Vec<Transaction>),
+    Single(Transaction),
+}
+
+/// Outcome of a submitted transaction.
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum Outcome {
+    Accepted { tx: TxId },
+    Rejected { tx: TxId, error: ErrorBody },
+}
+
+#[derive(Serialize)]
+struct TransactionBody {
+    #[serde(flatten)]
+    tx: Transaction,
+    status: TxStatus,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    message: String,
+    #[serde(flatten)]
+    details: Details,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Details {
+    Transaction(TransactionError),
+    Request { code: &'static str },
+}
+
+impl From<TransactionError> for ErrorBody {
+    fn from(e: TransactionError) -> Self {
+        Self {
+            message: e.to_string(),
+            details: Details::Transaction(e),
+        }
+    }
+}
+
+impl ErrorBody {
+    fn request(code: &'static str, message: String) -> Self {
+        Self {
+            message,
+            details: Details::Request { code },
+        }
+    }
+}
+
+/// An error response with a JSON body.
+struct ApiError(StatusCode, ErrorBody);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(self.1)).into_response()
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self(
+            rejection.status(),
+            ErrorBody::request("E_INVALID_REQUEST", rejection.body_text()),
+        )
+    }
+}
+
+/// Builds the API routes over the shared engine.
+pub fn router(engine: Engine) -> Router {
+    Router::new()
+        .route("/transactions", post(submit))
+        .route("/transactions/{tx}", get(transaction))
+        .route("/accounts", get(accounts))
+        .route("/accounts/{client}", get(account))
+        .route("/accounts/{client}/{currency}", get(currency_account))
+        .route("/metrics", get(metrics))
+        .layer(middleware::from_fn(trace_request))
+        .with_state(engine)
+}
+
+/// Runs every request in its own span, so that the transactions it applies can be traced back to
+/// it.
+async fn trace_request(request: Request, next: Next) -> Response {
+    let span = tracing::info_span!("request", method = %request.method(), uri = %request.uri());
+    async move {
+        let response = next.run(request).await;
+        tracing::debug!(status = response.status().as_u16(), "responded");
+        response
+    }
+    .instrument(span)
+    .await
+}
+
+/// Serves the API until the listener fails.
+pub async fn serve(listener: TcpListener, engine: Engine) {
+    if let Err(e) = axum::serve(listener, router(engine)).await {
+        eprintln!("HTTP API stopped: {e}");
+    }
+}
+
+async fn apply(engine: &Engine, tx: Transaction) -> Outcome {
+    let id = tx.tx_id;
+    match engine.apply(tx).await {
+        Ok(()) => Outcome::Accepted { tx: id },
+        Err(e) => Outcome::Rejected {
+            tx: id,
+            error: e.into(),
+        },
+    }
+}
+
+async fn submit(
+    State(engine): State<Engine>,
+    submission: Result<Json<Submission>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(submission) = submission?;
+
+    match submission {
+        Submission::Single(tx) => match apply(&engine, tx).await {
+            Outcome::Rejected { error, .. } => {
+                Err(ApiError(StatusCode::UNPROCESSABLE_ENTITY, error))
+            }
+            accepted => Ok(Json(accepted).into_response()),
+        },
+        Submission::Batch(txs) => {
+            let mut outcomes = Vec::with_capacity(txs.len());
+            for tx in txs {
+                outcomes.push(apply(&engine, tx).await);
+            }
+            Ok(Json(outcomes).into_response())
+        }
+    }
+}
+
+async fn transaction(
+    State(engine): State<Engine>,
+    Path(id): Path<TxId>,
+) -> Result<Json<TransactionBody>, ApiError> {
+    engine
+        .transaction(id)
+        .map(|(tx, status)| Json(TransactionBody { tx, status }))
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                ErrorBody::request(
+                    "E_TX_NOT_FOUND",
+                    format!("Transaction not found: Transaction Id '{id}'"),
+                ),
+            )
+        })
+}
+
+async fn metrics(State(engine): State<Engine>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        engine.metrics(),
+    )
+}
+
+async fn accounts(State(engine): State<Engine>) -> impl IntoResponse {
+    Json(engine.accounts())
+}
+
+fn find_account(
+    engine: &Engine,
+    id: ClientId,
+    currency: Option<Currency>,
+) -> Result<Json<ClientAccount>, ApiError> {
+    engine.account(id, currency).map(Json).ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            TransactionError::AccountNotFound { id }.into(),
+        )
+    })
+}
+
+async fn account(
+    State(engine): State<Engine>,
+    Path(id): Path<ClientId>,
+) -> Result<impl IntoResponse, ApiError> {
+    find_account(&engine, id, None)
+}
+
+async fn currency_account(
+    State(engine): State<Engine>,
+    Path((id, currency)): Path<(ClientId, Currency)>,
+) -> Result<impl IntoResponse, ApiError> {
+    find_account(&engine, id, Some(currency))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use rstest::rstest;
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    use super::router;
+    use crate::server::Engine;
+
+    /// Sends a request to a fresh router over the engine, returning the status and JSON body.
+    async fn send(
+        engine: &Engine,
+        method: Method,
+        uri: &str,
+        body: Option<&str>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(body.map(|b| Body::from(b.to_owned())).unwrap_or_default())
+            .unwrap();
+        let response = router(engine.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_submit_single() {
+        let engine = Engine::default();
+
+        let (status, body) = send(
+            &engine,
+            Method::POST,
+            "/transactions",
+            Some(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 100.0}"#),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"status": "accepted", "tx": 1}));
+        assert_eq!(engine.account(1, None).unwrap().available, 100.);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_submit_single_rejected() {
+        let engine = Engine::default();
+
+        let (status, body) = send(
+            &engine,
+            Method::POST,
+            "/transactions",
+            Some(r#"{"type": "withdrawal", "client": 1, "tx": 1, "amount": 100.0}"#),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "E_ACCOUNT_NOT_FOUND");
+        assert_eq!(body["id"], 1);
+        assert!(
+            body["message"]
+                .as_str()
+                .unwrap()
+                .contains("Account not found")
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_submit_batch() {
+        let engine = Engine::default();
+
+        let (status, body) = send(
+            &engine,
+            Method::POST,
+            "/transactions",
+            Some(
+                r#"[
+                    {"type": "deposit", "client": 1, "tx": 1, "amount": 100.0},
+                    {"type": "withdrawal", "client": 1, "tx": 2, "amount": 150.0},
+                    {"type": "dispute", "client": 1, "tx": 1}
+                ]"#,
+            ),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0], json!({"status": "accepted", "tx": 1}));
+        assert_eq!(body[1]["status"], "rejected");
+        assert_eq!(body[1]["error"]["code"], "E_INSUFFICIENT_FUNDS");
+        assert_eq!(body[2], json!({"status": "accepted", "tx": 1}));
+        assert_eq!(engine.account(1, None).unwrap().held, 100.);
+    }
+
+    #[rstest]
+    #[case::not_json("deposit,1,1,100.0")]
+    #[case::unknown_type("{\"type\": \"refund\", \"client\": 1, \"tx\": 1}")]
+    #[case::missing_client("{\"type\": \"deposit\", \"tx\": 1, \"amount\": 1.0}")]
+    #[tokio::test]
+    async fn test_submit_invalid(#[case] body: &str) {
+        let engine = Engine::default();
+
+        let (status, body) = send(&engine, Method::POST, "/transactions", Some(body)).await;
+
+        assert!(status.is_client_error());
+        assert_eq!(body["code"], "E_INVALID_REQUEST");
+        assert!(engine.accounts().is_empty());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_get_transaction() {
+        let engine = Engine::default();
+        send(
+            &engine,
+            Method::POST,
+            "/transactions",
+            Some(
+                r#"[
+                    {"type": "deposit", "client": 1, "tx": 1, "amount": 100.0},
+                    {"type": "dispute", "client": 1, "tx": 1}
+                ]"#,
+            ),
+        )
+        .await;
+
+        let (status, body) = send(&engine, Method::GET, "/transactions/1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"type": "deposit", "client": 1, "tx": 1, "amount": 100.0, "status": "disputed"})
+        );
+
+        let (status, body) = send(&engine, Method::GET, "/transactions/2", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "E_TX_NOT_FOUND");
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_get_accounts() {
+        let engine = Engine::default();
+        send(
+            &engine,
+            Method::POST,
+            "/transactions",
+            Some(
+                r#"[
+                    {"type": "deposit", "client": 2, "tx": 1, "amount": 5.0},
+                    {"type": "deposit", "client": 1, "tx": 2, "amount": 10.0},
+                    {"type": "deposit", "client": 2, "tx": 3, "amount": 2.5, "currency": "EUR"}
+                ]"#,
+            ),
+        )
+        .await;
+
+        let (status, body) = send(&engine, Method::GET, "/accounts", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!([
+                {"client": 1, "available": 10.0, "held": 0.0, "total": 10.0, "locked": false},
+                {"client": 2, "available": 5.0, "held": 0.0, "total": 5.0, "locked": false},
+                {"client": 2, "available": 2.5, "held": 0.0, "total": 2.5, "locked": false, "currency": "EUR"},
+            ])
+        );
+
+        let (status, body) = send(&engine, Method::GET, "/accounts/2", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["available"], 5.0);
+
+        let (status, body) = send(&engine, Method::GET, "/accounts/2/eur", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["available"], 2.5);
+
+        let (status, body) = send(&engine, Method::GET, "/accounts/3", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "E_ACCOUNT_NOT_FOUND");
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_get_metrics() {
+        let engine = Engine::default();
+        send(
+            &engine,
+            Method::POST,
+            "/transactions",
+            Some(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 5.0}"#),
+        )
+        .await;
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = router(engine).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; version=0.0.4"
+        );
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(body.contains("txn_transactions_total{type=\"deposit\",outcome=\"accepted\"} 1\n"));
+        assert!(body.contains("txn_accounts 1\n"));
+    }
+}
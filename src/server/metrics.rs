@@ -0,0 +1,396 @@
+//! Metrics of the shared engine in the Prometheus text exposition format, served on the HTTP API
+//! at `/metrics` or written periodically to a file for node-exporter's textfile collector.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::{
+    error::{Error, TransactionError},
+    model::{Currency, State, TxStatus, TxType},
+    server::Engine,
+};
+
+const TYPES: [TxType; 6] = [
+    TxType::Deposit,
+    TxType::Withdrawal,
+    TxType::Dispute,
+    TxType::Resolve,
+    TxType::Chargeback,
+    TxType::Transfer,
+];
+
+/// Upper bounds, in seconds, of the handler latency histogram buckets. Handlers only touch hash
+/// maps, so they run in microseconds.
+const BUCKETS: [f64; 10] = [
+    0.000_001,
+    0.000_002_5,
+    0.000_005,
+    0.000_01,
+    0.000_025,
+    0.000_05,
+    0.000_1,
+    0.000_25,
+    0.000_5,
+    0.001,
+];
+
+/// Outcomes and latencies of a single handler.
+#[derive(Default)]
+struct Handler {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    /// Observations per bucket, the last one counting those above every bound.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    nanos: AtomicU64,
+}
+
+/// Counters and histograms updated as transactions are applied.
+#[derive(Default)]
+pub struct Metrics {
+    handlers: [Handler; TYPES.len()],
+    rejections: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Gauges read from the State when metrics are rendered.
+pub struct Gauges {
+    pub accounts: usize,
+    pub locked: usize,
+    pub overdrawn: usize,
+    pub transactions: usize,
+    /// Sum of the disputed transactions per currency, as amounts of different currencies can't be
+    /// added up.
+    pub disputed: BTreeMap<Option<Currency>, f64>,
+}
+
+impl Gauges {
+    pub fn read(state: &State) -> Self {
+        // Transactions without a currency are always reported, even when none is disputed
+        let mut disputed = BTreeMap::from([(None, 0.)]);
+        for tx in state
+            .transactions
+            .values()
+            .filter(|tx| tx.status() == TxStatus::Disputed)
+        {
+            if let Some(amount) = tx.amount() {
+                *disputed.entry(tx.currency()).or_default() += f64::from(amount);
+            }
+        }
+
+        Self {
+            accounts: state.accounts.len(),
+            locked: state.accounts.values().filter(|a| a.locked).count(),
+            overdrawn: state.accounts.values().filter(|a| a.overdrawn()).count(),
+            transactions: state.transactions.len(),
+            disputed,
+        }
+    }
+}
+
+impl Metrics {
+    /// Records the outcome of applying a transaction, and how long its handler took.
+    pub fn record(&self, tx_type: TxType, error: Option<&TransactionError>, elapsed: Duration) {
+        let handler = &self.handlers[tx_type as usize];
+        match error {
+            None => handler.accepted.fetch_add(1, Ordering::Relaxed),
+            Some(e) => {
+                *self
+                    .rejections
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .entry(e.code())
+                    .or_default() += 1;
+                handler.rejected.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|le| seconds <= *le)
+            .unwrap_or(BUCKETS.len());
+        handler.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        handler
+            .nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "txn_transactions_total",
+            "counter",
+            "Transactions applied, by type and outcome.",
+        );
+        for (tx_type, handler) in TYPES.iter().zip(&self.handlers) {
+            for (outcome, count) in [
+                ("accepted", &handler.accepted),
+                ("rejected", &handler.rejected),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "txn_transactions_total{{type=\"{}\",outcome=\"{outcome}\"}} {}",
+                    label(*tx_type),
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "txn_rejections_total",
+            "counter",
+            "Rejected transactions, by error code.",
+        );
+        for (code, count) in self
+            .rejections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            let _ = writeln!(out, "txn_rejections_total{{code=\"{code}\"}} {count}");
+        }
+
+        for (name, help, value) in [
+            ("txn_accounts", "Client accounts.", gauges.accounts as f64),
+            (
+                "txn_locked_accounts",
+                "Locked Client accounts.",
+                gauges.locked as f64,
+            ),
+            (
+                "txn_overdrawn_accounts",
+                "Client accounts with negative available funds.",
+                gauges.overdrawn as f64,
+            ),
+            (
+                "txn_stored_transactions",
+                "Deposits and withdrawals stored for disputes.",
+                gauges.transactions as f64,
+            ),
+        ] {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        header(
+            &mut out,
+            "txn_disputed_amount",
+            "gauge",
+            "Sum of the transactions currently disputed, by currency.",
+        );
+        for (currency, amount) in &gauges.disputed {
+            let _ = match currency {
+                Some(currency) => writeln!(
+                    out,
+                    "txn_disputed_amount{{currency=\"{currency}\"}} {amount}"
+                ),
+                None => writeln!(out, "txn_disputed_amount {amount}"),
+            };
+        }
+
+        header(
+            &mut out,
+            "txn_handle_duration_seconds",
+            "histogram",
+            "Time spent applying a transaction to the State, by handler.",
+        );
+        for (tx_type, handler) in TYPES.iter().zip(&self.handlers) {
+            let label = label(*tx_type);
+            let mut count = 0;
+            for (le, bucket) in BUCKETS.iter().zip(&handler.buckets) {
+                count += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "txn_handle_duration_seconds_bucket{{handler=\"{label}\",le=\"{le}\"}} {count}"
+                );
+            }
+            count += handler.buckets[BUCKETS.len()].load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "txn_handle_duration_seconds_bucket{{handler=\"{label}\",le=\"+Inf\"}} {count}"
+            );
+            let _ = writeln!(
+                out,
+                "txn_handle_duration_seconds_sum{{handler=\"{label}\"}} {}",
+                handler.nanos.load(Ordering::Relaxed) as f64 / 1e9
+            );
+            let _ = writeln!(
+                out,
+                "txn_handle_duration_seconds_count{{handler=\"{label}\"}} {count}"
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn label(tx_type: TxType) -> String {
+    tx_type.as_ref().to_lowercase()
+}
+
+/// Writes the metrics to a file every `interval`, forever. The file is replaced atomically, as
+/// the textfile collector requires.
+pub async fn write_periodically(engine: Engine, path: PathBuf, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if let Err(e) = write(&engine, &path).await {
+            tracing::warn!(error = %e, "couldn't write metrics");
+        }
+    }
+}
+
+async fn write(engine: &Engine, path: &Path) -> Result<(), Error> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+
+    let write = async {
+        tokio::fs::write(&partial, engine.metrics()).await?;
+        tokio::fs::rename(&partial, path).await
+    };
+    write.await.map_err(|e| Error::WriteError {
+        filename: path.to_path_buf(),
+        source: e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use rstest::rstest;
+
+    use super::{Gauges, Metrics, write_periodically};
+    use crate::{
+        error::TransactionError,
+        model::{State, Transaction, TxType},
+        server::Engine,
+    };
+
+    #[rstest]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record(TxType::Deposit, None, Duration::from_nanos(500));
+        metrics.record(TxType::Deposit, None, Duration::from_micros(20));
+        metrics.record(
+            TxType::Withdrawal,
+            Some(&TransactionError::AccountNotFound { id: 1 }),
+            Duration::from_millis(2),
+        );
+        let gauges = Gauges {
+            accounts: 2,
+            locked: 1,
+            overdrawn: 1,
+            transactions: 3,
+            disputed: BTreeMap::from([(None, 12.5), ("EUR".parse().ok(), 4.)]),
+        };
+
+        let rendered = metrics.render(&gauges);
+        let lines = rendered.lines().collect::<Vec<_>>();
+
+        for expected in [
+            "txn_transactions_total{type=\"deposit\",outcome=\"accepted\"} 2",
+            "txn_transactions_total{type=\"withdrawal\",outcome=\"rejected\"} 1",
+            "txn_transactions_total{type=\"chargeback\",outcome=\"accepted\"} 0",
+            "txn_rejections_total{code=\"E_ACCOUNT_NOT_FOUND\"} 1",
+            "txn_accounts 2",
+            "txn_locked_accounts 1",
+            "txn_overdrawn_accounts 1",
+            "txn_stored_transactions 3",
+            "txn_disputed_amount 12.5",
+            "txn_disputed_amount{currency=\"EUR\"} 4",
+            "# HELP txn_disputed_amount Sum of the transactions currently disputed, by currency.",
+            "txn_handle_duration_seconds_bucket{handler=\"deposit\",le=\"0.000001\"} 1",
+            "txn_handle_duration_seconds_bucket{handler=\"deposit\",le=\"0.00001\"} 1",
+            "txn_handle_duration_seconds_bucket{handler=\"deposit\",le=\"0.000025\"} 2",
+            "txn_handle_duration_seconds_bucket{handler=\"deposit\",le=\"+Inf\"} 2",
+            "txn_handle_duration_seconds_sum{handler=\"deposit\"} 0.0000205",
+            "txn_handle_duration_seconds_count{handler=\"deposit\"} 2",
+            "txn_handle_duration_seconds_bucket{handler=\"withdrawal\",le=\"0.001\"} 0",
+            "txn_handle_duration_seconds_bucket{handler=\"withdrawal\",le=\"+Inf\"} 1",
+            "# TYPE txn_handle_duration_seconds histogram",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "Missing {expected:?} in:\n{rendered}"
+            );
+        }
+    }
+
+    #[rstest]
+    fn test_gauges() {
+        let mut state = State::default();
+        for (tx_type, client_id, tx_id, amount, currency) in [
+            (TxType::Deposit, 1, 1, Some(10.), None),
+            (TxType::Deposit, 1, 2, Some(2.5), None),
+            (TxType::Deposit, 2, 3, Some(5.), None),
+            (TxType::Deposit, 1, 4, Some(7.), Some("EUR")),
+            (TxType::Dispute, 1, 1, None, None),
+            (TxType::Dispute, 2, 3, None, None),
+            (TxType::Chargeback, 2, 3, None, None),
+            (TxType::Dispute, 1, 4, None, Some("EUR")),
+        ] {
+            state
+                .apply(Transaction {
+                    tx_type,
+                    client_id,
+                    tx_id,
+                    amount,
+                    currency: currency.and_then(|c| c.parse().ok()),
+                    destination: None,
+                })
+                .unwrap();
+        }
+
+        let gauges = Gauges::read(&state);
+
+        assert_eq!(
+            (
+                gauges.accounts,
+                gauges.locked,
+                gauges.transactions,
+                gauges.disputed
+            ),
+            (
+                3,
+                1,
+                4,
+                BTreeMap::from([(None, 10.), ("EUR".parse().ok(), 7.)])
+            )
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_periodically() {
+        let dir = std::env::temp_dir().join(format!("txn-metrics-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("txn.prom");
+        let engine = Engine::default();
+
+        let writer = tokio::spawn(write_periodically(
+            engine.clone(),
+            path.clone(),
+            Duration::from_millis(10),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        writer.abort();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("txn_accounts 0\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
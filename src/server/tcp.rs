@@ -0,0 +1,236 @@
+//! Line-based TCP protocol. Every line received is either a CSV transaction row or a query:
+//!
+//!  - A transaction row, e.g. `deposit,1,1,100.0`, is acknowledged with `ok,<tx>` or rejected with
+//!    `error,<tx>,<reason>`. Rows that can't be parsed are rejected with `error,,<reason>`.
+//!  - `balance,<client>` replies with an `account,<client>,<available>,<held>,<total>,<locked>`
+//!    row, or `error,,<reason>` if the Client has no account. `balance,<client>,<currency>`
+//!    replies with the Client's account in that currency, with the currency as a last column.
+//!  - `accounts` replies with an `account,...` row for every Client followed by `end`.
+//!
+//! Header rows and blank lines are ignored, so that CSV files can be streamed as they are.
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpListener;
+use tracing::Instrument;
+
+use crate::{
+    csv::{format_account, parse_row},
+    error::TransactionError,
+    model::{ClientId, Currency},
+    server::Engine,
+};
+
+/// Accepts connections forever, handling each one on its own task.
+pub async fn serve(listener: TcpListener, engine: Engine) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer)) => {
+                let engine = engine.clone();
+                let span = tracing::info_span!("connection", %peer);
+                tokio::spawn(
+                    async move {
+                        tracing::debug!("accepted");
+                        let (read, write) = socket.into_split();
+                        if let Err(e) = handle_connection(&engine, read, write).await
+                            && engine.verbose()
+                        {
+                            eprintln!("Connection from {peer} failed: {e}");
+                        }
+                        tracing::debug!("closed");
+                    }
+                    .instrument(span),
+                );
+            }
+            Err(e) => eprintln!("Couldn't accept connection: {e}"),
+        }
+    }
+}
+
+/// Replies to every line received on a connection until the client hangs up.
+pub async fn handle_connection(
+    engine: &Engine,
+    read: impl AsyncRead + Unpin,
+    write: impl AsyncWrite + Unpin,
+) -> std::io::Result<()> {
+    let mut lines = BufReader::new(read).lines();
+    let mut writer = BufWriter::new(write);
+
+    while let Some(line) = lines.next_line().await? {
+        respond(engine, line.trim(), &mut writer).await?;
+
+        // Replies to pipelined rows are batched until every row received so far is handled
+        if lines.get_mut().buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+
+    writer.flush().await
+}
+
+async fn respond(
+    engine: &Engine,
+    line: &str,
+    out: &mut (impl AsyncWrite + Unpin),
+) -> std::io::Result<()> {
+    let mut fields = line.split(',').map(str::trim);
+    let reply = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some(""), None, ..) | (Some("type"), ..) => return Ok(()),
+        (Some("accounts"), None, ..) => {
+            let mut reply = String::new();
+            for account in engine.accounts() {
+                reply.push_str(&format!("account,{}\n", format_account(&account)));
+            }
+            reply + "end"
+        }
+        (Some("balance"), Some(client_id), currency, None) => balance(engine, client_id, currency),
+        _ => match parse_row(line.as_bytes()).await {
+            Ok(tx) => {
+                let id = tx.tx_id;
+                match engine.apply(tx).await {
+                    Ok(()) => format!("ok,{id}"),
+                    Err(e) => format!("error,{id},{e}"),
+                }
+            }
+            Err(e) => format!("error,,{e}"),
+        },
+    };
+
+    out.write_all(reply.as_bytes()).await?;
+    out.write_all(b"\n").await
+}
+
+fn balance(engine: &Engine, client_id: &str, currency: Option<&str>) -> String {
+    let Ok(id) = client_id.parse::<ClientId>() else {
+        return format!("error,,Invalid Client Id '{client_id}'");
+    };
+    let currency = match currency
+        .filter(|c| !c.is_empty())
+        .map(str::parse::<Currency>)
+    {
+        None => None,
+        Some(Ok(currency)) => Some(currency),
+        Some(Err(e)) => return format!("error,,{e}"),
+    };
+
+    match engine.account(id, currency) {
+        Some(account) => format!("account,{}", format_account(&account)),
+        None => format!("error,,{}", TransactionError::AccountNotFound { id }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{handle_connection, serve};
+    use crate::server::Engine;
+
+    /// Sends the input on a single connection and returns the lines received in reply.
+    async fn exchange(engine: &Engine, input: &[u8]) -> Vec<String> {
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_read, server_write) = tokio::io::split(server);
+        let (client_read, mut client_write) = tokio::io::split(client);
+
+        let server = handle_connection(engine, server_read, server_write);
+        let client = async {
+            client_write.write_all(input).await.unwrap();
+            client_write.shutdown().await.unwrap();
+
+            let mut replies = Vec::new();
+            let mut lines = BufReader::new(client_read).lines();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                replies.push(line);
+            }
+            replies
+        };
+
+        let (res, replies) = tokio::join!(server, client);
+        res.expect("Connection failed");
+        replies
+    }
+
+    #[rstest]
+    #[case::transactions(indoc::indoc!{
+        b"\
+        type,client,tx,amount
+        deposit,1,1,100.0
+        withdrawal,1,2,250.0
+
+        dispute,1,1,
+        deposit,1
+        "
+    }.as_slice(), vec![
+        "ok,1",
+        "error,2,Balance insufficient: available '100', Withdrawal amount '250', Transaction Id '2'",
+        "ok,1",
+        "error,,Couldn't deserialize row in CSV: ByteRecord([\"deposit,1\"])",
+    ])]
+    #[case::queries(indoc::indoc!{
+        b"\
+        deposit,2,1,10.5
+        deposit,1,2,1.0
+        deposit,2,3,4.0,eur
+        balance,2
+        balance,2,EUR
+        balance,3
+        balance,x
+        balance,2,euro
+        accounts
+        "
+    }.as_slice(), vec![
+        "ok,1",
+        "ok,2",
+        "ok,3",
+        "account,2,10.5,0,10.5,false",
+        "account,2,4,0,4,false,EUR",
+        "error,,Account not found processing transaction: Client Id '3'",
+        "error,,Invalid Client Id 'x'",
+        "error,,Invalid currency 'euro', expected a three-letter code",
+        "account,1,1,0,1,false",
+        "account,2,10.5,0,10.5,false",
+        "account,2,4,0,4,false,EUR",
+        "end",
+    ])]
+    #[tokio::test]
+    async fn test_handle_connection(#[case] input: &[u8], #[case] expected: Vec<&str>) {
+        let engine = Engine::default();
+
+        let replies = exchange(&engine, input).await;
+
+        assert_eq!(replies, expected);
+    }
+
+    #[rstest]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_serve_concurrent_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let engine = Engine::default();
+        tokio::spawn(serve(listener, engine.clone()));
+
+        let connections = (0..8u32).map(|client_id| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let rows = (0..100u32)
+                .map(|n| format!("deposit,{client_id},{},1.0\n", client_id * 100 + n))
+                .collect::<String>();
+            stream.write_all(rows.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            let mut lines = BufReader::new(stream).lines();
+            let mut acknowledged = 0;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                assert!(line.starts_with("ok,"), "{line}");
+                acknowledged += 1;
+            }
+            acknowledged
+        });
+        let acknowledged = futures_util::future::join_all(connections).await;
+
+        assert_eq!(acknowledged, vec![100; 8]);
+        let accounts = engine.accounts();
+        assert_eq!(accounts.len(), 8);
+        assert!(accounts.iter().all(|account| account.total == 100.));
+    }
+}
@@ -0,0 +1,623 @@
+//! Parallel processing of a transaction stream, sharded by Client Id across worker tasks.
+//!
+//! Every handler but transfers only touches the account of a single Client, so each worker owns the
+//! accounts of the Clients routed to it in its own [`State`], and receives their transactions in
+//! input order. A transfer to a Client routed to another shard is applied on the source's shard,
+//! once the destination's shard has caught up and lent it the destination's account, which is then
+//! handed back before either shard receives another transaction.
+//! The router keeps a global index of the shards each transaction id was routed to, so that
+//! duplicate transaction ids and references to another Client's transaction are rejected exactly
+//! as a single [`State`] would: when a transaction id was routed to more than one shard, the
+//! router asks the shards in question which one stored it.
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+
+use futures_util::{Stream, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+use crate::{
+    error::{ParsingError, TransactionError},
+    model::{AccountId, ClientAccount, State, Transaction, TxId, TxType},
+    policy::Policy,
+};
+
+/// Number of transactions sent to a worker at once.
+const BATCH_SIZE: usize = 1024;
+/// Number of batches a worker can lag behind the router.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// A transaction rejected by the engine, along with the line it starts on in the source.
+#[derive(Debug)]
+pub struct Rejection {
+    pub line: u64,
+    pub tx: Transaction,
+    pub error: TransactionError,
+}
+
+enum Message {
+    Batch(Vec<(u64, Transaction)>),
+    /// Asks whether a transaction id is stored in the worker's State.
+    Stored(TxId, oneshot::Sender<bool>),
+    /// Removes an account from the worker's State, to lend it to the shard applying a transfer.
+    Lend(AccountId, oneshot::Sender<Option<ClientAccount>>),
+    /// Applies a transfer to an account lent by another shard, replying with the account.
+    Transfer(
+        u64,
+        Transaction,
+        Option<ClientAccount>,
+        oneshot::Sender<Option<ClientAccount>>,
+    ),
+    /// Takes back an account lent for a transfer.
+    Return(ClientAccount),
+}
+
+struct Shard {
+    sender: mpsc::Sender<Message>,
+    handle: JoinHandle<State>,
+    batch: Vec<(u64, Transaction)>,
+}
+
+impl Shard {
+    async fn flush(&mut self) {
+        if !self.batch.is_empty() {
+            let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+            self.send(Message::Batch(batch)).await;
+        }
+    }
+
+    async fn send(&self, message: Message) {
+        // A worker only hangs up when it panics, which is surfaced when joining it
+        let _ = self.sender.send(message).await;
+    }
+}
+
+/// Routes transactions to the shard owning their Client, or to the shard storing the transaction
+/// they reference.
+struct Router {
+    shards: Vec<Shard>,
+    /// The first shard a deposit, withdrawal or transfer id was routed to.
+    owners: HashMap<TxId, usize>,
+    /// Further shards a deposit, withdrawal or transfer id was routed to, for ids re-used across
+    /// Clients.
+    contested: HashMap<TxId, Vec<usize>>,
+    policy: Policy,
+}
+
+impl Router {
+    fn new(
+        shards: NonZeroUsize,
+        policy: &Policy,
+        errors: mpsc::UnboundedSender<Rejection>,
+    ) -> Self {
+        let shards = (0..shards.get())
+            .map(|index| {
+                let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+                let span = tracing::info_span!("shard", index);
+                Shard {
+                    sender,
+                    handle: tokio::spawn(
+                        worker(receiver, policy.clone(), errors.clone()).instrument(span),
+                    ),
+                    batch: Vec::with_capacity(BATCH_SIZE),
+                }
+            })
+            .collect();
+
+        Self {
+            shards,
+            owners: HashMap::new(),
+            contested: HashMap::new(),
+            policy: policy.clone(),
+        }
+    }
+
+    /// Shards a deposit, withdrawal or transfer with this transaction id was routed to.
+    fn candidates(&self, id: TxId) -> Vec<usize> {
+        self.owners
+            .get(&id)
+            .into_iter()
+            .chain(self.contested.get(&id).into_iter().flatten())
+            .copied()
+            .collect()
+    }
+
+    fn claim(&mut self, id: TxId, shard: usize) {
+        match self.owners.get(&id) {
+            None => {
+                self.owners.insert(id, shard);
+            }
+            Some(owner) if *owner == shard => {}
+            Some(_) => {
+                let contested = self.contested.entry(id).or_default();
+                if !contested.contains(&shard) {
+                    contested.push(shard);
+                }
+            }
+        }
+    }
+
+    /// Whether a shard stored the transaction id, once it has processed every transaction routed
+    /// to it so far.
+    async fn stored(&mut self, shard: usize, id: TxId) -> bool {
+        let shard = &mut self.shards[shard];
+        shard.flush().await;
+
+        let (sender, receiver) = oneshot::channel();
+        shard.send(Message::Stored(id, sender)).await;
+        receiver.await.unwrap_or_default()
+    }
+
+    /// Applies a transfer on the source's shard, borrowing the destination's account from the
+    /// `away` shard owning it and handing it back once the transfer is applied.
+    async fn transfer(&mut self, line: u64, tx: Transaction, home: usize, away: usize) {
+        let destination = (tx.destination.unwrap_or_default(), tx.currency);
+        tracing::trace!(line, tx = tx.tx_id, shard = home, away, "routed transfer");
+
+        let lender = &mut self.shards[away];
+        lender.flush().await;
+        let (sender, receiver) = oneshot::channel();
+        lender.send(Message::Lend(destination, sender)).await;
+        let lent = receiver.await.unwrap_or_default();
+
+        let borrower = &mut self.shards[home];
+        borrower.flush().await;
+        let (sender, receiver) = oneshot::channel();
+        borrower
+            .send(Message::Transfer(line, tx, lent, sender))
+            .await;
+        if let Some(account) = receiver.await.unwrap_or_default() {
+            self.shards[away].send(Message::Return(account)).await;
+        }
+    }
+
+    async fn route(&mut self, line: u64, tx: Transaction) -> Result<(), Rejection> {
+        let home = tx.client_id as usize % self.shards.len();
+        let id = tx.tx_id;
+
+        let shard = match tx.tx_type {
+            TxType::Deposit | TxType::Withdrawal | TxType::Transfer => {
+                for other in self.candidates(id) {
+                    if other != home && self.stored(other, id).await {
+                        tracing::debug!(line, tx = id, shard = other, "stored by another shard");
+                        return Err(Rejection {
+                            line,
+                            tx,
+                            error: TransactionError::DuplicateTransaction { id },
+                        });
+                    }
+                }
+                self.claim(id, home);
+
+                // Other types ignore the destination column
+                let away = tx
+                    .destination
+                    .filter(|_| tx.tx_type == TxType::Transfer)
+                    .map(|client_id| client_id as usize % self.shards.len());
+                if let Some(away) = away.filter(|away| *away != home) {
+                    self.transfer(line, tx, home, away).await;
+                    return Ok(());
+                }
+                home
+            }
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => {
+                // At most one of the candidates stored the referenced transaction, and any of them
+                // reports it as not found otherwise
+                match self.candidates(id).as_slice() {
+                    [] => home,
+                    [only] => *only,
+                    [rest @ .., last] => {
+                        let mut target = *last;
+                        for other in rest {
+                            if self.stored(*other, id).await {
+                                target = *other;
+                                break;
+                            }
+                        }
+                        target
+                    }
+                }
+            }
+        };
+
+        tracing::trace!(line, tx = id, shard, "routed");
+        let shard = &mut self.shards[shard];
+        shard.batch.push((line, tx));
+        if shard.batch.len() >= BATCH_SIZE {
+            shard.flush().await;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for every worker to finish and merges their States. Clients and stored transaction
+    /// ids are disjoint across shards.
+    async fn finish(mut self) -> State {
+        let mut state = State::new(self.policy.clone());
+        for mut shard in self.shards.drain(..) {
+            shard.flush().await;
+            drop(shard.sender);
+
+            let shard_state = match shard.handle.await {
+                Ok(shard_state) => shard_state,
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            };
+            state.accounts.extend(shard_state.accounts);
+            state.transactions.extend(shard_state.transactions);
+            state.velocity.extend(shard_state.velocity);
+            state.flagged.extend(shard_state.flagged);
+        }
+        state
+    }
+}
+
+async fn worker(
+    mut receiver: mpsc::Receiver<Message>,
+    policy: Policy,
+    errors: mpsc::UnboundedSender<Rejection>,
+) -> State {
+    let mut state = State::new(policy);
+    while let Some(message) = receiver.recv().await {
+        match message {
+            Message::Batch(batch) => {
+                for (line, tx) in batch {
+                    if let Err(error) = state.apply(tx.clone()) {
+                        let _ = errors.send(Rejection { line, tx, error });
+                    }
+                }
+            }
+            Message::Stored(id, reply) => {
+                let _ = reply.send(state.transactions.contains_key(&id));
+            }
+            Message::Lend(id, reply) => {
+                let _ = reply.send(state.accounts.remove(&id));
+            }
+            Message::Transfer(line, tx, lent, reply) => {
+                if let Some(account) = lent {
+                    state.accounts.insert(account.id(), account);
+                }
+                let destination = (tx.destination.unwrap_or_default(), tx.currency);
+                if let Err(error) = state.apply(tx.clone()) {
+                    let _ = errors.send(Rejection { line, tx, error });
+                }
+                let _ = reply.send(state.accounts.remove(&destination));
+            }
+            Message::Return(account) => {
+                state.accounts.insert(account.id(), account);
+            }
+        }
+    }
+    state
+}
+
+/// Processes a stream of Transactions and their source lines across `shards` worker tasks applying
+/// `policy`, preserving the order of each Client's transactions, and returns the merged State.
+/// Rejected transactions are passed to `on_error` in no particular order across Clients, whereas a
+/// CSV parsing error stops processing.
+pub async fn process(
+    stream: impl Stream<Item = Result<(u64, Transaction), ParsingError>>,
+    shards: NonZeroUsize,
+    policy: &Policy,
+    mut on_error: impl FnMut(Rejection),
+) -> Result<State, ParsingError> {
+    let (errors, mut reported) = mpsc::unbounded_channel();
+    let mut router = Router::new(shards, policy, errors);
+
+    futures_util::pin_mut!(stream);
+    while let Some(row) = stream.next().await {
+        let (line, tx) = match row {
+            Ok(row) => row,
+            Err(e) => {
+                // Rows still batched in the shards are applied, and their rejections reported,
+                // before the parsing error stops processing
+                router.finish().await;
+                while let Some(e) = reported.recv().await {
+                    on_error(e);
+                }
+                return Err(e);
+            }
+        };
+        if let Err(e) = router.route(line, tx).await {
+            on_error(e);
+        }
+        while let Ok(e) = reported.try_recv() {
+            on_error(e);
+        }
+    }
+
+    let state = router.finish().await;
+    while let Some(e) = reported.recv().await {
+        on_error(e);
+    }
+
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use futures_util::{StreamExt, TryStreamExt, stream};
+    use rstest::rstest;
+
+    use super::{Rejection, process};
+    use crate::{
+        csv::{format_account, parse_csv_lines},
+        error::ParsingError,
+        generate::{GenerateArgs, generate},
+        model::{State, Transaction, TxType},
+        policy::Policy,
+    };
+
+    /// Processes the CSV serially and across shards, returning the sorted output rows and error
+    /// messages, prefixed with their source line, of both.
+    async fn serial_and_sharded(
+        input: &[u8],
+        shards: usize,
+    ) -> ((Vec<String>, Vec<String>), (Vec<String>, Vec<String>)) {
+        let txs = parse_csv_lines(input)
+            .await
+            .try_collect::<Vec<_>>()
+            .await
+            .expect("Failed to parse");
+
+        let mut state = State::default();
+        let mut errors = txs
+            .iter()
+            .cloned()
+            .filter_map(|(line, tx)| state.apply(tx).err().map(|e| format!("{line}: {e}")))
+            .collect::<Vec<_>>();
+        let mut accounts = state
+            .accounts
+            .values()
+            .map(format_account)
+            .collect::<Vec<_>>();
+        errors.sort();
+        accounts.sort();
+
+        let mut sharded_errors = Vec::new();
+        let sharded = process(
+            stream::iter(txs.into_iter().map(Ok)),
+            NonZeroUsize::new(shards).unwrap(),
+            &Policy::default(),
+            |r: Rejection| sharded_errors.push(format!("{}: {}", r.line, r.error)),
+        )
+        .await
+        .expect("Failed to process");
+        let mut sharded_accounts = sharded
+            .accounts
+            .values()
+            .map(format_account)
+            .collect::<Vec<_>>();
+        sharded_errors.sort();
+        sharded_accounts.sort();
+
+        ((accounts, errors), (sharded_accounts, sharded_errors))
+    }
+
+    #[rstest]
+    #[case::cross_client_dispute(indoc::indoc!{
+        b"\
+        type,client,tx,amount
+        deposit,1,1,100.0
+        dispute,2,1,
+        resolve,2,1,
+        chargeback,2,1,
+        "
+    }.as_slice())]
+    #[case::duplicate_across_clients(indoc::indoc!{
+        b"\
+        type,client,tx,amount
+        deposit,1,1,100.0
+        deposit,2,1,50.0
+        withdrawal,3,1,10.0
+        dispute,2,1,
+        dispute,1,1,
+        "
+    }.as_slice())]
+    #[case::rejected_id_reused_across_clients(indoc::indoc!{
+        b"\
+        type,client,tx,amount
+        deposit,1,1,
+        deposit,2,1,50.0
+        deposit,3,1,20.0
+        dispute,1,1,
+        dispute,2,1,
+        chargeback,2,1,
+        deposit,2,2,10.0
+        "
+    }.as_slice())]
+    #[case::withdrawal_id_reused_by_deposit(indoc::indoc!{
+        b"\
+        type,client,tx,amount
+        deposit,1,1,100.0
+        withdrawal,1,2,50.0
+        deposit,2,2,10.0
+        dispute,2,2,
+        "
+    }.as_slice())]
+    #[case::transfers(indoc::indoc!{
+        b"\
+        type,client,tx,amount,currency,destination
+        deposit,1,1,100.0
+        deposit,2,2,10.0
+        deposit,3,3,5.0
+        transfer,1,4,30.0,,2
+        deposit,1,12,1.0,,2
+        transfer,2,5,50.0,,3
+        transfer,2,6,40.0,,3
+        transfer,3,7,10.0,,4
+        transfer,3,8,10.0,,3
+        deposit,3,4,1.0
+        dispute,3,3,
+        chargeback,3,3,
+        transfer,1,9,10.0,,3
+        transfer,3,10,1.0,,1
+        dispute,1,4,
+        withdrawal,3,11,40.0
+        "
+    }.as_slice())]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_process_matches_serial(
+        #[case] input: &[u8],
+        #[values(1, 2, 3, 4)] shards: usize,
+    ) {
+        let (serial, sharded) = serial_and_sharded(input, shards).await;
+
+        assert_eq!(sharded, serial);
+    }
+
+    #[rstest]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_process_currencies(#[values(1, 2)] shards: usize) {
+        let input = indoc::indoc! {
+            b"\
+            type,client,tx,amount,currency
+            deposit,1,1,100.0
+            deposit,1,2,50.0,EUR
+            withdrawal,1,3,75.0,eur
+            dispute,1,2,
+            dispute,1,2,,EUR
+            chargeback,1,2,,EUR
+            deposit,1,4,10.0,EUR
+            deposit,1,5,10.0
+            "
+        };
+
+        let (serial, sharded) = serial_and_sharded(input, shards).await;
+
+        assert_eq!(sharded, serial);
+        assert_eq!(
+            serial,
+            (
+                vec!["1,0,0,0,true,EUR".to_owned(), "1,110,0,110,false".to_owned()],
+                vec![
+                    "4: Balance insufficient: available '50', Withdrawal amount '75', Transaction Id '3'".to_owned(),
+                    "5: Currency mismatch processing transaction: expected 'EUR', got 'none'".to_owned(),
+                    "8: Locked Account: Client Id '1".to_owned(),
+                ]
+            )
+        );
+    }
+
+    #[rstest]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_process_generated_matches_serial(
+        #[values(0, 1, 2)] seed: u64,
+        #[values(1, 4, 7)] shards: usize,
+        #[values(
+            "deposit=30,withdrawal=20,dispute=30,resolve=10,chargeback=10",
+            "deposit=30,withdrawal=10,transfer=30,dispute=15,resolve=10,chargeback=5"
+        )]
+        mix: &str,
+    ) {
+        let args = GenerateArgs {
+            clients: 50,
+            rows: 20_000,
+            mix: mix.parse().unwrap(),
+            duplicate_rate: 0.1,
+            malformed_rate: 0.1,
+            seed,
+            output: None,
+            expected: None,
+        };
+        let mut input = Vec::new();
+        generate(&args, &mut input).unwrap();
+
+        let (serial, sharded) = serial_and_sharded(&input, shards).await;
+
+        assert_eq!(sharded, serial);
+    }
+
+    #[rstest]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_process_parsing_error(#[values(1, 2)] shards: usize) {
+        let input = indoc::indoc! {
+            b"\
+            type,client,tx,amount
+            deposit,1,1,100.0
+            deposit,not_a_number,2,100.0
+            "
+        };
+
+        let stream = parse_csv_lines(input.as_slice()).await;
+        let res = process(
+            stream,
+            NonZeroUsize::new(shards).unwrap(),
+            &Policy::default(),
+            |_| {},
+        )
+        .await;
+
+        assert!(matches!(res, Err(ParsingError::Deserialize { .. })));
+    }
+
+    #[rstest]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_process_parsing_error_reports_rejections(#[values(1, 2)] shards: usize) {
+        let input = indoc::indoc! {
+            b"\
+            type,client,tx,amount
+            deposit,1,1,100.0
+            withdrawal,1,2,250.0
+            withdrawal,2,3,1.0
+            deposit,not_a_number,4,100.0
+            "
+        };
+
+        let stream = parse_csv_lines(input.as_slice()).await;
+        let mut lines = Vec::new();
+        let res = process(
+            stream,
+            NonZeroUsize::new(shards).unwrap(),
+            &Policy::default(),
+            |r| lines.push(r.line),
+        )
+        .await;
+        lines.sort();
+
+        assert!(matches!(res, Err(ParsingError::Deserialize { .. })));
+        assert_eq!(lines, vec![3, 4]);
+    }
+
+    #[rstest]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_process_preserves_client_order() {
+        let stream = stream::iter((1..=5_000u32).map(|tx_id| {
+            let tx = Transaction {
+                tx_type: TxType::Deposit,
+                client_id: (tx_id % 3) as u16,
+                tx_id,
+                amount: Some(1.),
+                currency: None,
+                destination: None,
+            };
+            Ok((tx_id as u64 + 1, tx))
+        }))
+        .chain(stream::iter([Ok((
+            5_002,
+            Transaction {
+                tx_type: TxType::Withdrawal,
+                client_id: 1,
+                tx_id: 5_001,
+                amount: Some(1_667.),
+                currency: None,
+                destination: None,
+            },
+        ))]));
+
+        let state = process(
+            stream,
+            NonZeroUsize::new(2).unwrap(),
+            &Policy::default(),
+            |r| panic!("{}", r.error),
+        )
+        .await
+        .expect("Failed to process");
+
+        assert_eq!(state.accounts[&(1, None)].available, 0.);
+        assert_eq!(state.transactions.len(), 5_001);
+    }
+}
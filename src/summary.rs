@@ -0,0 +1,412 @@
+//! End-of-run summary statistics: rows processed per transaction type, rejections per error code,
+//! amounts moved, and how long processing took.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Serialize, Serializer};
+
+use crate::{
+    error::TransactionError,
+    model::{Amount, Currency, State, Transaction, TxId, TxType},
+};
+
+/// Rows of one transaction type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Counts {
+    pub rows: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+}
+
+/// Amounts moved in one currency, summed in `f64` to keep rounding errors from accumulating.
+///
+/// `deposited`, `withdrawn` and `transferred` are the accepted deposits, withdrawals and
+/// transfers, `held` the funds still held by disputes at the end of the run, `charged_back` the
+/// deposits reversed by accepted chargebacks, and `overdrawn` the negative available funds of the
+/// overdrawn accounts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Amounts {
+    pub deposited: f64,
+    pub withdrawn: f64,
+    pub transferred: f64,
+    pub held: f64,
+    pub charged_back: f64,
+    pub overdrawn: f64,
+}
+
+/// Statistics of a run, built from every row processed and every rejection, and completed with
+/// the final State.
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub rows: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub types: BTreeMap<TxType, Counts>,
+    /// Rejections per error code.
+    pub errors: BTreeMap<&'static str, u64>,
+    /// Amounts per currency, as amounts of different currencies can't be added up. Accounts and
+    /// transactions without a currency are under `None`.
+    #[serde(serialize_with = "serialize_amounts")]
+    pub amounts: BTreeMap<Option<Currency>, Amounts>,
+    pub accounts: usize,
+    pub locked_accounts: usize,
+    pub overdrawn_accounts: usize,
+    pub duration_secs: f64,
+    pub rows_per_sec: f64,
+    /// Chargeback rows per referenced transaction, less their rejections.
+    #[serde(skip)]
+    chargebacks: HashMap<TxId, i64>,
+}
+
+impl Summary {
+    /// Counts a row read from the input, assuming it is accepted until it is reported rejected.
+    pub fn row(&mut self, tx: &Transaction) {
+        self.rows += 1;
+        self.accepted += 1;
+        let counts = self.types.entry(tx.tx_type).or_default();
+        counts.rows += 1;
+        counts.accepted += 1;
+        self.tally(tx, 1);
+    }
+
+    /// Moves a row counted by [`Summary::row`] from accepted to rejected.
+    pub fn rejected(&mut self, tx: &Transaction, error: &TransactionError) {
+        self.accepted -= 1;
+        self.rejected += 1;
+        let counts = self.types.entry(tx.tx_type).or_default();
+        counts.accepted -= 1;
+        counts.rejected += 1;
+        *self.errors.entry(error.code()).or_default() += 1;
+        self.tally(tx, -1);
+    }
+
+    fn tally(&mut self, tx: &Transaction, sign: i64) {
+        // Rejected amounts such as NaN are subtracted again, so only finite ones are summed
+        let amount = tx.amount.filter(|amount| amount.is_finite()).unwrap_or(0.) as f64;
+        let amount = sign as f64 * amount;
+        let amounts = &mut self.amounts;
+        match tx.tx_type {
+            TxType::Deposit => amounts.entry(tx.currency).or_default().deposited += amount,
+            TxType::Withdrawal => amounts.entry(tx.currency).or_default().withdrawn += amount,
+            TxType::Transfer => amounts.entry(tx.currency).or_default().transferred += amount,
+            TxType::Chargeback => *self.chargebacks.entry(tx.tx_id).or_default() += sign,
+            TxType::Dispute | TxType::Resolve => {}
+        }
+    }
+
+    /// Completes the summary with the final State and the time processing took.
+    pub fn finish(&mut self, state: &State, duration: Duration) {
+        // Amounts are added to 0.0 rather than `sum()`'s -0.0, which would print as such without
+        // any amounts
+        for account in state.accounts.values() {
+            let amounts = self.amounts.entry(account.currency).or_default();
+            amounts.held += account.held as f64;
+            if account.overdrawn() {
+                amounts.overdrawn -= f64::from(account.available);
+            }
+        }
+        self.accounts = state.accounts.len();
+        self.locked_accounts = state.accounts.values().filter(|a| a.locked).count();
+        self.overdrawn_accounts = state.accounts.values().filter(|a| a.overdrawn()).count();
+        for (id, accepted) in &self.chargebacks {
+            let Some(tx) = state.transactions.get(id) else {
+                continue;
+            };
+            let amount: Amount = tx.amount().unwrap_or(0.);
+            self.amounts.entry(tx.currency()).or_default().charged_back +=
+                *accepted as f64 * amount as f64;
+        }
+        self.duration_secs = duration.as_secs_f64();
+        self.rows_per_sec = if self.duration_secs > 0. {
+            self.rows as f64 / self.duration_secs
+        } else {
+            0.
+        };
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Processed {} rows in {:.3}s ({:.0} rows/s): {} accepted, {} rejected",
+            self.rows, self.duration_secs, self.rows_per_sec, self.accepted, self.rejected
+        )?;
+        for (tx_type, counts) in &self.types {
+            writeln!(
+                f,
+                "  {:<10} {} rows, {} accepted, {} rejected",
+                tx_type.as_ref().to_lowercase(),
+                counts.rows,
+                counts.accepted,
+                counts.rejected
+            )?;
+        }
+        if !self.errors.is_empty() {
+            writeln!(f, "Rejections:")?;
+            for (code, count) in &self.errors {
+                writeln!(f, "  {code:<20} {count}")?;
+            }
+        }
+        for (currency, amounts) in &self.amounts {
+            match currency {
+                Some(currency) => write!(f, "In {currency}: deposited")?,
+                None => write!(f, "Deposited")?,
+            }
+            writeln!(
+                f,
+                " {:.4}, withdrawn {:.4}, transferred {:.4}, held {:.4}, charged back {:.4}, \
+                 overdrawn by {:.4}",
+                amounts.deposited,
+                amounts.withdrawn,
+                amounts.transferred,
+                amounts.held,
+                amounts.charged_back,
+                amounts.overdrawn
+            )?;
+        }
+        write!(
+            f,
+            "{} accounts, {} locked, {} overdrawn",
+            self.accounts, self.locked_accounts, self.overdrawn_accounts
+        )
+    }
+}
+
+/// Serializes the amounts as a list, each entry carrying its `currency`, as JSON object keys can't
+/// be `null`.
+fn serialize_amounts<S: Serializer>(
+    amounts: &BTreeMap<Option<Currency>, Amounts>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Entry<'a> {
+        currency: Option<Currency>,
+        #[serde(flatten)]
+        amounts: &'a Amounts,
+    }
+
+    serializer.collect_seq(amounts.iter().map(|(currency, amounts)| Entry {
+        currency: *currency,
+        amounts,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+
+    use futures_util::StreamExt;
+    use rstest::rstest;
+
+    use super::{Amounts, Counts, Summary};
+    use crate::{csv::parse_csv_lines, model::TxType, policy::Policy, shard::process};
+
+    /// Summarises processing the CSV across shards, over 2 seconds.
+    async fn summarise(input: &[u8], shards: usize) -> Summary {
+        let summary = RefCell::new(Summary::default());
+
+        let stream = parse_csv_lines(input).await.inspect(|row| {
+            if let Ok((_, tx)) = row {
+                summary.borrow_mut().row(tx);
+            }
+        });
+        let state = process(
+            stream,
+            NonZeroUsize::new(shards).unwrap(),
+            &Policy::default(),
+            |r| summary.borrow_mut().rejected(&r.tx, &r.error),
+        )
+        .await
+        .expect("Failed to process");
+        let mut summary = summary.into_inner();
+        summary.finish(&state, Duration::from_secs(2));
+        summary
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_summary(#[values(1, 3)] shards: usize) {
+        let input = indoc::indoc! {
+            b"\
+            type,client,tx,amount
+            deposit,1,1,100.0
+            deposit,2,2,50.0
+            deposit,3,3,20.0
+            withdrawal,1,4,30.0
+            withdrawal,2,5,80.0
+            deposit,1,6,NaN
+            dispute,1,1,
+            dispute,2,2,
+            chargeback,2,2,
+            chargeback,2,2,
+            dispute,3,9,
+            deposit,2,7,1.0
+            "
+        };
+
+        let summary = summarise(input, shards).await;
+
+        assert_eq!(
+            (summary.rows, summary.accepted, summary.rejected),
+            (12, 7, 5)
+        );
+        assert_eq!(
+            summary.types[&TxType::Deposit],
+            Counts {
+                rows: 5,
+                accepted: 3,
+                rejected: 2
+            }
+        );
+        assert_eq!(
+            summary.types[&TxType::Withdrawal],
+            Counts {
+                rows: 2,
+                accepted: 1,
+                rejected: 1
+            }
+        );
+        assert_eq!(
+            summary.types[&TxType::Chargeback],
+            Counts {
+                rows: 2,
+                accepted: 1,
+                rejected: 1
+            }
+        );
+        assert_eq!(
+            summary
+                .errors
+                .iter()
+                .map(|(k, v)| (*k, *v))
+                .collect::<Vec<_>>(),
+            vec![
+                ("E_ACCOUNT_LOCKED", 1),
+                ("E_INSUFFICIENT_FUNDS", 1),
+                ("E_INVALID_AMOUNT", 1),
+                ("E_INVALID_TX_STATE", 1),
+                ("E_TX_NOT_FOUND", 1),
+            ]
+        );
+        assert_eq!(
+            summary.amounts[&None],
+            Amounts {
+                deposited: 170.,
+                withdrawn: 30.,
+                transferred: 0.,
+                held: 100.,
+                charged_back: 50.,
+                overdrawn: 30.,
+            }
+        );
+        assert_eq!((summary.accounts, summary.locked_accounts), (3, 1));
+        assert_eq!(summary.overdrawn_accounts, 1);
+        assert_eq!(summary.rows_per_sec, 6.);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_summary_per_currency(#[values(1, 3)] shards: usize) {
+        let input = indoc::indoc! {
+            b"\
+            type,client,tx,amount,currency
+            deposit,1,1,20.0
+            deposit,1,2,5.0,EUR
+            withdrawal,2,3,1.0,EUR
+            dispute,1,2,,EUR
+            chargeback,1,2,,EUR
+            "
+        };
+
+        let summary = summarise(input, shards).await;
+
+        assert_eq!(
+            summary.amounts.keys().copied().collect::<Vec<_>>(),
+            vec![None, "EUR".parse().ok()]
+        );
+        assert_eq!(summary.amounts[&None].deposited, 20.);
+        assert_eq!(summary.amounts[&"EUR".parse().ok()].deposited, 5.);
+        assert_eq!(summary.amounts[&"EUR".parse().ok()].charged_back, 5.);
+        let display = summary.to_string();
+        assert!(
+            display.contains("Deposited 20.0000, withdrawn 0.0000"),
+            "{display}"
+        );
+        assert!(
+            display.contains("In EUR: deposited 5.0000, withdrawn 0.0000"),
+            "{display}"
+        );
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["amounts"][1]["currency"], "EUR");
+        assert_eq!(json["amounts"][1]["charged_back"], 5.);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_summary_nothing_charged_back() {
+        let input = indoc::indoc! {
+            b"\
+            type,client,tx,amount
+            deposit,1,1,100.0
+            dispute,1,1,
+            "
+        };
+
+        let summary = summarise(input, 1).await;
+
+        assert_eq!(summary.amounts[&None].charged_back, 0.);
+        assert!(summary.amounts[&None].charged_back.is_sign_positive());
+        assert!(summary.to_string().contains("charged back 0.0000"));
+        let json = serde_json::to_string(&summary).unwrap();
+        assert!(json.contains("\"charged_back\":0.0"), "{json}");
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_summary_nothing_overdrawn() {
+        let input = indoc::indoc! {
+            b"\
+            type,client,tx,amount
+            deposit,1,1,100.0
+            withdrawal,1,2,40.0
+            withdrawal,1,3,80.0
+            "
+        };
+
+        let summary = summarise(input, 1).await;
+
+        assert_eq!(
+            (summary.overdrawn_accounts, summary.amounts[&None].overdrawn),
+            (0, 0.)
+        );
+        assert!(summary.amounts[&None].overdrawn.is_sign_positive());
+        assert!(summary.to_string().contains("overdrawn by 0.0000"));
+        let json = serde_json::to_string(&summary).unwrap();
+        assert!(json.contains("\"overdrawn\":0.0"), "{json}");
+    }
+
+    #[rstest]
+    fn test_summary_json() {
+        let mut summary = Summary::default();
+        summary.row(&crate::model::Transaction {
+            tx_type: TxType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(1.5),
+            currency: None,
+            destination: None,
+        });
+
+        let json = serde_json::to_value(&summary).unwrap();
+
+        assert_eq!(json["types"]["deposit"]["accepted"], 1);
+        assert_eq!(json["amounts"][0]["currency"], serde_json::Value::Null);
+        assert_eq!(json["amounts"][0]["deposited"], 1.5);
+        assert!(json.get("chargebacks").is_none());
+    }
+}
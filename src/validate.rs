@@ -0,0 +1,327 @@
+//! Dry run of a transaction file, reporting every problem that would reject a row in a real run
+//! without printing balances, so that a file can be checked before it is accepted.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use csv_async::AsyncWriterBuilder;
+use futures_util::{Stream, StreamExt};
+use strum::AsRefStr;
+use tokio::io::AsyncWrite;
+
+use crate::{
+    csv::{HEADER, parse_csv_rows},
+    error::{Error, ParsingError, RunError, TransactionError},
+    model::{State, Transaction, TxId, TxType},
+    policy::Policy,
+};
+
+/// Check a transaction file for problems, without printing balances
+#[derive(Debug, clap::Args)]
+pub struct ValidateArgs {
+    /// CSV file to validate
+    pub filename: PathBuf,
+}
+
+/// Category of a problem found in a file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Kind {
+    /// The header or a row doesn't match the `type,client,tx,amount` columns, optionally followed
+    /// by `currency` and `destination`.
+    Schema,
+    /// A deposit or withdrawal re-uses the id of an earlier one in the file.
+    Duplicate,
+    /// A dispute, resolve or chargeback references an id that no earlier row has.
+    UnknownReference,
+    /// Any other transaction the engine would reject.
+    Rejection,
+}
+
+/// A problem found on a line of the file.
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub line: u64,
+    pub kind: Kind,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Findings of a validated file, ordered by line.
+#[derive(Debug, Default)]
+pub struct Validation {
+    pub rows: u64,
+    pub findings: Vec<Finding>,
+}
+
+impl Validation {
+    fn push(&mut self, line: u64, kind: Kind, code: &'static str, message: String) {
+        self.findings.push(Finding {
+            line,
+            kind,
+            code,
+            message,
+        });
+    }
+
+    fn count(&self, kind: Kind) -> usize {
+        self.findings.iter().filter(|f| f.kind == kind).count()
+    }
+
+    /// Classifies a row the engine rejected.
+    fn rejected(&mut self, line: u64, error: TransactionError, known: bool) {
+        let kind = match error {
+            TransactionError::NotFound { .. } if !known => Kind::UnknownReference,
+            _ => Kind::Rejection,
+        };
+        self.push(line, kind, error.code(), error.to_string());
+    }
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Validated {} rows: {} problems ({} schema, {} duplicate, {} unknown reference, {} rejection)",
+            self.rows,
+            self.findings.len(),
+            self.count(Kind::Schema),
+            self.count(Kind::Duplicate),
+            self.count(Kind::UnknownReference),
+            self.count(Kind::Rejection),
+        )
+    }
+}
+
+/// Code of a row that can't be parsed.
+fn schema_code(error: &ParsingError) -> &'static str {
+    match error {
+        ParsingError::Header { .. } => "E_HEADER",
+        _ => "E_MALFORMED_ROW",
+    }
+}
+
+/// Message of a row that can't be parsed, with the cause rather than the record it came from.
+fn schema_message(error: &ParsingError) -> String {
+    match error {
+        ParsingError::Deserialize { source, .. } | ParsingError::ReadRecord { source, .. } => {
+            source.to_string()
+        }
+        ParsingError::Header { record } => format!(
+            "Unexpected header `{}`, expected `{}`",
+            record
+                .iter()
+                .map(String::from_utf8_lossy)
+                .collect::<Vec<_>>()
+                .join(","),
+            HEADER.join(",")
+        ),
+        _ => error.to_string(),
+    }
+}
+
+/// Applies every row to a throwaway State under `policy` in order, collecting the problems found.
+/// Rows that can't be parsed are reported and skipped, and duplicate ids are reported even where
+/// the engine would accept the row, because the earlier one was rejected.
+pub async fn validate(
+    rows: impl Stream<Item = Result<(u64, Result<Transaction, ParsingError>), ParsingError>>,
+    policy: &Policy,
+) -> Result<Validation, ParsingError> {
+    let mut validation = Validation::default();
+    let mut state = State::new(policy.clone());
+    // Line of the first deposit, withdrawal or transfer with each id
+    let mut seen = HashMap::<TxId, u64>::new();
+
+    futures_util::pin_mut!(rows);
+    while let Some(row) = rows.next().await {
+        let (line, tx) = row?;
+        let tx = match tx {
+            Ok(tx) => tx,
+            Err(e) => {
+                validation.push(line, Kind::Schema, schema_code(&e), schema_message(&e));
+                continue;
+            }
+        };
+        validation.rows += 1;
+
+        let id = tx.tx_id;
+        match tx.tx_type {
+            TxType::Deposit | TxType::Withdrawal | TxType::Transfer => {
+                if let Some(first) = seen.get(&id) {
+                    validation.push(
+                        line,
+                        Kind::Duplicate,
+                        TransactionError::DuplicateTransaction { id }.code(),
+                        format!("Duplicate transaction: Transaction id '{id}', first seen on line {first}"),
+                    );
+                } else {
+                    seen.insert(id, line);
+                }
+                match state.apply(tx) {
+                    Ok(()) | Err(TransactionError::DuplicateTransaction { .. }) => {}
+                    Err(e) => validation.rejected(line, e, true),
+                }
+            }
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => {
+                if let Err(e) = state.apply(tx) {
+                    validation.rejected(line, e, seen.contains_key(&id));
+                }
+            }
+        }
+    }
+
+    Ok(validation)
+}
+
+/// Validates a file, writing its findings as CSV rows to `stdout` and a summary to `stderr`. Fails
+/// when there are findings, so that the exit status tells whether the file is valid.
+pub async fn run(args: &ValidateArgs, policy: &Policy) -> Result<(), Box<dyn std::error::Error>> {
+    let fp = tokio::fs::File::open(&args.filename)
+        .await
+        .map_err(|e| Error::IOError {
+            filename: args.filename.clone(),
+            source: e,
+        })?;
+
+    let validation = validate(parse_csv_rows(fp).await, policy).await?;
+    write_findings(tokio::io::stdout(), &validation.findings).await?;
+    eprintln!("{validation}");
+
+    match validation.findings.len() {
+        0 => Ok(()),
+        findings => {
+            let schema = validation.count(Kind::Schema);
+            Err(RunError::Invalid {
+                schema,
+                rejected: findings - schema,
+            }
+            .into())
+        }
+    }
+}
+
+/// Writes findings as CSV rows with a header: `line,kind,code,message`.
+pub async fn write_findings(
+    write: impl AsyncWrite + Unpin,
+    findings: &[Finding],
+) -> Result<(), csv_async::Error> {
+    let mut wtr = AsyncWriterBuilder::new().create_writer(write);
+    wtr.write_record(["line", "kind", "code", "message"])
+        .await?;
+    for finding in findings {
+        wtr.write_record([
+            finding.line.to_string().as_str(),
+            finding.kind.as_ref(),
+            finding.code,
+            &finding.message,
+        ])
+        .await?;
+    }
+    wtr.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use tokio::io::AsyncRead;
+
+    use super::{Kind, Validation, validate, write_findings};
+    use crate::{csv::parse_csv_rows, policy::Policy};
+
+    async fn validate_csv(read: impl AsyncRead + Unpin + Send) -> Validation {
+        validate(parse_csv_rows(read).await, &Policy::default())
+            .await
+            .expect("Failed to read")
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_validate() {
+        let input = indoc::indoc! {b"
+            type,client,tx,amount
+            deposit,1,1,10.0
+            deposit,1,x,10.0
+            withdrawal,1,2,50.0
+            deposit,1,2,5.0
+            deposit,1,1,5.0
+            dispute,1,9,
+            withdrawal,1,3,1.0
+            deposit,1
+            resolve,1,1,
+        "};
+
+        let validation = validate_csv(input.as_slice()).await;
+
+        assert_eq!(validation.rows, 7);
+        assert_eq!(
+            validation
+                .findings
+                .iter()
+                .map(|f| (f.line, f.kind, f.code))
+                .collect::<Vec<_>>(),
+            vec![
+                (3, Kind::Schema, "E_MALFORMED_ROW"),
+                (4, Kind::Rejection, "E_INSUFFICIENT_FUNDS"),
+                // The withdrawal was rejected, so the engine accepts the deposit re-using its id
+                (5, Kind::Duplicate, "E_DUPLICATE_TX"),
+                (6, Kind::Duplicate, "E_DUPLICATE_TX"),
+                (7, Kind::UnknownReference, "E_TX_NOT_FOUND"),
+                (9, Kind::Schema, "E_MALFORMED_ROW"),
+                (10, Kind::Rejection, "E_INVALID_TX_STATE"),
+            ]
+        );
+        assert_eq!(
+            validation.findings[3].message,
+            "Duplicate transaction: Transaction id '1', first seen on line 2"
+        );
+        assert_eq!(
+            validation.to_string(),
+            "Validated 7 rows: 7 problems (2 schema, 2 duplicate, 1 unknown reference, 2 rejection)"
+        );
+    }
+
+    #[rstest]
+    #[case::valid(b"type,client,tx,amount\ndeposit,1,1,1.0\n".as_slice(), vec![])]
+    #[case::header(b"kind,client,tx,amount\ndeposit,1,1,1.0\n".as_slice(), vec![(1, "E_HEADER")])]
+    #[case::header_case(b"Type, Client,TX,amount\n".as_slice(), vec![])]
+    #[tokio::test]
+    async fn test_validate_header(#[case] input: &[u8], #[case] expected: Vec<(u64, &str)>) {
+        let validation = validate_csv(input).await;
+
+        assert_eq!(
+            validation
+                .findings
+                .iter()
+                .map(|f| (f.line, f.code))
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_findings() {
+        let validation = validate_csv(
+            indoc::indoc! {b"
+                type,client,tx,amount
+                withdrawal,1,1,5.0
+            "}
+            .as_slice(),
+        )
+        .await;
+        let mut out = Vec::new();
+
+        write_findings(&mut out, &validation.findings)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "line,kind,code,message\n\
+             2,rejection,E_ACCOUNT_NOT_FOUND,Account not found processing transaction: Client Id '1'\n"
+        );
+    }
+}